@@ -0,0 +1,35 @@
+#[macro_use]
+extern crate bencher;
+
+use std::io::Write;
+
+use bencher::Bencher;
+use brouas::{fixtures, io::InMemory, paging::{pager::{BufPager, Pager, PageId, traits::Pager as TraitPager}, page::PageSectionType, storage::PagerStream}, utils::slice::IntoSection};
+
+fn bench_pager_random_write_to_page(bench: &mut Bencher) {
+    let nb_pages = 1000u64;
+    let pager: BufPager<PageId, u8, _> = Pager::new(PagerStream::new(InMemory::new()), 100);
+    let data = fixtures::random_data(3000);
+
+    for _ in 0..nb_pages {
+        pager.new_page(0x10).unwrap();
+        pager.flush().unwrap();
+    }
+
+    bench.iter(|| {
+        let pid: PageId = fixtures::random_u64(1, nb_pages);
+        
+        pager
+        .borrow_mut_page(&pid)
+        .unwrap()
+        .into_section(PageSectionType::Body)
+        .as_mut()
+        .write_all(&data)
+        .unwrap();
+        
+        pager.flush().unwrap();
+    })
+}
+
+benchmark_group!(pager_benches, bench_pager_random_write_to_page);
+benchmark_main!(pager_benches);
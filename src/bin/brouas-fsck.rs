@@ -0,0 +1,45 @@
+//! Check the consistency of a database file.
+//!
+//! Usage: brouas-fsck <path> [--repair]
+//!
+//! The overflow chains and the B+trees are walked, the links of the other page types are not known here:
+//! unreachable pages are not reported.
+
+use brouas::paging::{fsck::Fsck, pager::{BufPager, PageId}, storage::{FileStorage, LockMode, LockWait}};
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let repair = args.iter().any(|arg| arg == "--repair");
+
+    let path = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: brouas-fsck <path> [--repair]");
+            std::process::exit(2);
+        }
+    };
+
+    // Readers share the file, the repair needs it for itself.
+    let mode = if repair { LockMode::Exclusive } else { LockMode::Shared };
+    let store = FileStorage::open_locked(path, mode, LockWait::FailFast).map_err(Into::<std::io::Error>::into)?;
+    let pager: BufPager<PageId, u8, _> = BufPager::open(store, 16).map_err(Into::<std::io::Error>::into)?;
+    let fsck = Fsck::new().with_builtin_links().ignore_orphans();
+    let report = fsck.check(&pager).map_err(Into::<std::io::Error>::into)?;
+
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+
+    println!("{} pages checked, {} problems", report.pages, report.problems.len());
+
+    if repair && report.leaks() > 0 {
+        let repaired = fsck.repair(&pager, &report).map_err(Into::<std::io::Error>::into)?;
+        println!("{} leaked pages put back in the free pages list", repaired);
+    }
+
+    if !report.is_clean() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Dump and decode the pages of a database file.
+//!
+//! Usage: brouas-inspect <path> [--page <pid>] [--dump] [--summary]
+//!
+//! --page: only inspect the page
+//! --dump: hex dump the bodies of the pages
+//! --summary: only print the number of pages and the free space, by type
+
+use brouas::paging::{inspect::{hex_dump, free_space, type_name, PageBody, PageHeader, Summary}, pager::{traits::PageStorage, MIN_PAGE_SIZE}, storage::{FileStorage, LockMode, LockWait}, superblock::{Superblock, SUPERBLOCK_PAGE}};
+
+fn usage() -> ! {
+    eprintln!("usage: brouas-inspect <path> [--page <pid>] [--dump] [--summary]");
+    std::process::exit(2);
+}
+
+fn print_page(content: &[u8], dump: bool) {
+    let header = PageHeader::read(content);
+
+    println!(
+        "page {}: version {}, type {:#04x} ({}), parent {}, checksum {:08x}{}, lsn {}, body at {}, {} bytes free",
+        header.id, header.version, header.ptype, type_name(header.ptype), header.parent, header.checksum,
+        if header.valid { "" } else { " (mismatch)" }, header.lsn, header.body, free_space(content)
+    );
+
+    match PageBody::decode(content) {
+        PageBody::Free { next } => println!("  next free page: {:?}", next),
+        PageBody::Overflow { size, next } => println!("  overflow: {} bytes, next page: {:?}", size, next),
+        PageBody::Node { leaf, len, capacity, cells } => {
+            println!("  {} node: {} cells out of {}", if leaf { "leaf" } else { "branch" }, len, capacity);
+
+            for (index, cell) in cells.iter().enumerate() {
+                println!("  cell {}: key {}, {} bytes, {} in page, overflow: {:?}", index, cell.key, cell.size, cell.in_page_size, cell.overflow);
+            }
+        },
+        PageBody::Unknown => {}
+    }
+
+    if dump {
+        print!("{}", hex_dump(&content[header.body..], header.body));
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (mut path, mut page, mut dump, mut summary) = (None, None, false, false);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--page" => page = Some(args.next().and_then(|pid| pid.parse::<u64>().ok()).unwrap_or_else(|| usage())),
+            "--dump" => dump = true,
+            "--summary" => summary = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => usage()
+        }
+    }
+
+    let store = FileStorage::open_locked(path.unwrap_or_else(|| usage()), LockMode::Shared, LockWait::FailFast).map_err(Into::<std::io::Error>::into)?;
+
+    let mut content = vec![0u8; MIN_PAGE_SIZE];
+    store.fetch(SUPERBLOCK_PAGE, &mut content)?;
+    let page_size = Superblock::read_page_size(&content).map_err(Into::<std::io::Error>::into)?;
+
+    let mut content = vec![0u8; page_size as usize];
+    store.fetch(SUPERBLOCK_PAGE, &mut content)?;
+    let superblock = Superblock::read(&content).map_err(Into::<std::io::Error>::into)?;
+    let pages = store.len()? / page_size;
+
+    if let Some(pid) = page {
+        if pid == SUPERBLOCK_PAGE || pid >= pages {
+            eprintln!("no page {}, the file holds pages 1 to {}", pid, pages.saturating_sub(1));
+            std::process::exit(1);
+        }
+
+        store.fetch(pid, &mut content)?;
+        print_page(&content, dump);
+        return Ok(());
+    }
+
+    println!(
+        "superblock: version {}, page size {}, next page id {}, free pages list head {:?}, {} pages in the file",
+        superblock.version, superblock.page_size, superblock.next_page_id, superblock.freelist_head, pages
+    );
+
+    for (name, pid) in superblock.roots.iter() {
+        println!("  root {}: page {}", name, pid);
+    }
+
+    let mut totals = Summary::default();
+
+    for pid in 1..pages {
+        store.fetch(pid, &mut content)?;
+        totals.record(&content);
+
+        if !summary {
+            print_page(&content, dump);
+        }
+    }
+
+    println!("{} pages, {} corrupted", pages.saturating_sub(1), totals.corrupted);
+
+    for (ptype, count) in totals.pages.iter() {
+        println!("  {:#04x} ({}): {} pages, {} bytes free", ptype, type_name(*ptype), count, totals.free_space[ptype]);
+    }
+
+    Ok(())
+}
@@ -1,15 +1,50 @@
-use std::{alloc::Layout, ops::{Deref, DerefMut}};
+use std::{alloc::Layout, collections::{BTreeSet, HashMap}, ops::{Deref, DerefMut}, sync::{Arc, Mutex, OnceLock, atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering}}};
 
 use crate::utils::borrow::{RefBorrowMut, TryBorrow, TryBorrowMut, RefBorrow};
 
+use self::{budget::{BudgetShare, MemoryBudget}, eviction::{Clock, EvictionPolicy}};
+
+pub mod budget;
+pub mod eviction;
+
 #[derive(Debug)]
 pub enum Error {
     NotEnoughSpace,
-    MutablyBorrowed
+    /// The block is exclusively borrowed.
+    MutablyBorrowed,
+    /// The block is borrowed by readers, it cannot be exclusively borrowed.
+    ImmutablyBorrowed,
+    /// The dirty victim could not be written back.
+    WriteBackFailed(std::io::Error),
+    /// The block cannot be released while other cells share it.
+    StillShared,
+    /// The buffer is already registered to a memory budget.
+    AlreadyBudgeted
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Persists the content of a dirty block, before it is reclaimed.
+pub trait WriteBack {
+    /// key: the key under which the block is indexed
+    fn write_back(&self, key: u64, content: &mut [u8]) -> std::io::Result<()>;
+}
+
+/// Default number of shards of the block index.
+pub const DEFAULT_SHARDS: usize = 16;
+/// Hits logged by a shard of the index before they are reported to the eviction policy.
+const HIT_BATCH: usize = 64;
+/// Key of a block which is not indexed.
+pub const NO_KEY: u64 = u64::MAX;
+/// Smallest block handed out.
+pub const MIN_BLOCK_SIZE: usize = 64;
+/// Number of size classes between two powers of two.
+const CLASSES_PER_POWER: usize = 8;
+/// Borrow state of an exclusively borrowed block, otherwise it is the number of readers.
+const EXCLUSIVE: usize = usize::MAX;
+/// Raised in the pin count while the block is being reclaimed.
+const RECLAIMING: usize = 1 << (usize::BITS - 1);
+
 pub struct RawBufferCell<'buffer>
 {
     block: *mut BufferBlock,
@@ -22,6 +57,10 @@ impl<'buffer> From<*mut BufferBlock> for RawBufferCell<'buffer> {
     }
 }
 
+// The block state is only accessed through atomics.
+unsafe impl<'buffer> Send for RawBufferCell<'buffer> {}
+unsafe impl<'buffer> Sync for RawBufferCell<'buffer> {}
+
 impl<'buffer> Clone for RawBufferCell<'buffer> {
     fn clone(&self) -> Self {
         Self::new(self.block)
@@ -29,6 +68,7 @@ impl<'buffer> Clone for RawBufferCell<'buffer> {
 }
 
 impl<'buffer> RawBufferCell<'buffer> {
+    /// Share a block which is already pinned, by its allocation or by another cell.
     fn new(block: *mut BufferBlock) -> Self {
         let cell = Self {
             block,
@@ -39,6 +79,13 @@ impl<'buffer> RawBufferCell<'buffer> {
         cell
     }
 
+    /// Pin the block, unless it is being reclaimed.
+    fn try_pin(block: *mut BufferBlock) -> Option<Self> {
+        unsafe {
+            (*block).try_pin().then(|| Self { block, _pht: Default::default() })
+        }
+    }
+
     pub fn leak(&self) -> *mut BufferBlock {
         self.block
     }
@@ -50,31 +97,38 @@ impl<'buffer> RawBufferCell<'buffer> {
 
     pub fn inc_rc(&self) {
         unsafe {
-            (*self.block).rc += 1;
+            (*self.block).rc.fetch_add(1, Ordering::AcqRel);
         }
     }
 
     pub fn dec_rec(&self) {
         unsafe {
-            (*self.block).rc -= 1;
+            (*self.block).rc.fetch_sub(1, Ordering::AcqRel);
         }
     }
 
     pub fn rc(&self) -> usize {
         unsafe {
-            (*self.block).rc
+            (*self.block).rc()
+        }
+    }
+
+    /// Key under which the block is indexed, if any.
+    pub fn key(&self) -> Option<u64> {
+        unsafe {
+            (*self.block).key()
         }
     }
 
     pub fn raise_upserted(&mut self) {
         unsafe {
-            (*self.block).upserted = true;
+            (*self.block).upserted.store(true, Ordering::Release);
         }       
     }
     /// Remove the modification flag
     pub fn drop_upserted(&mut self) {
         unsafe {
-            (*self.block).upserted = false;
+            (*self.block).upserted.store(false, Ordering::Release);
         }
     }
 
@@ -90,22 +144,41 @@ impl<'buffer> RawBufferCell<'buffer> {
         }
     }
 
-    pub fn raise_mut_borrow(&self) {
+    /// Number of readers borrowing the block.
+    pub fn readers(&self) -> usize {
         unsafe {
-            (*self.block).raise_mut_borrow();
+            (*self.block).readers()
         }
     }
 
-    pub fn drop_mut_borrow(&self) {
+    pub fn try_borrow_shared(&self) -> Result<()> {
         unsafe {
-            (*self.block).drop_mut_borrow();
+            (*self.block).try_borrow_shared()
+        }
+    }
+
+    pub fn release_shared(&self) {
+        unsafe {
+            (*self.block).release_shared();
+        }
+    }
+
+    pub fn try_borrow_exclusive(&self) -> Result<()> {
+        unsafe {
+            (*self.block).try_borrow_exclusive()
+        }
+    }
+
+    pub fn release_exclusive(&self) {
+        unsafe {
+            (*self.block).release_exclusive();
         }
     }
 
     pub fn try_into<T>(self) -> Option<BufferCell<'buffer, T>> {
         unsafe {
             let size = std::mem::size_of::<T>();
-            let block_size = size.wrapping_div_euclid((*self.block).size);
+            let block_size = size.wrapping_div_euclid((*self.block).used);
             if size == block_size {
                 return Some(BufferCell::new(self))
             } else {
@@ -117,7 +190,7 @@ impl<'buffer> RawBufferCell<'buffer> {
     pub fn try_into_array<T>(self) -> Option<BufArray<'buffer, T>> {
         unsafe {
             let size = std::mem::size_of::<T>();
-            let block_size = (*self.block).size;
+            let block_size = (*self.block).used;
 
             let capacity = block_size.wrapping_div_euclid(size);
             let rem = block_size.wrapping_rem_euclid(size);
@@ -138,6 +211,8 @@ impl<'buffer> Drop for RawBufferCell<'buffer> {
 }
 
 pub struct BufferCell<'buffer, T: ?Sized> {
+    // Keeps the block shared as long as the cell is alive.
+    #[allow(dead_code)]
     raw: RawBufferCell<'buffer>,
     _pht: std::marker::PhantomData<T>
 }
@@ -166,13 +241,10 @@ impl<'buffer, T> TryBorrow<'buffer, [T]> for BufArray<'buffer, T> {
     type Error = Error;
 
     fn try_borrow(&self) -> std::result::Result<Self::Ref, Self::Error> {
-        if self.raw.is_mut_borrowed() {
-            return Err(Error::MutablyBorrowed);
-        } else {
-            return Ok(
-                RefBufArray::new(self.raw.clone(), self.len)
-            )
-        }
+        self.raw.try_borrow_shared()?;
+        return Ok(
+            RefBufArray::new(self.raw.clone(), self.len)
+        )
     }
 }
 
@@ -180,13 +252,10 @@ impl<'buffer, T> TryBorrowMut<'buffer, [T]> for BufArray<'buffer, T> {
     type RefMut = RefMutBufArray<'buffer, T>;
 
     fn try_borrow_mut(&mut self) -> std::result::Result<Self::RefMut, Self::Error> {
-        if self.raw.is_mut_borrowed() {
-            return Err(Error::MutablyBorrowed);
-        } else {
-            return Ok(
-                RefMutBufArray::new(self.raw.clone(), self.len)
-            )
-        }
+        self.raw.try_borrow_exclusive()?;
+        return Ok(
+            RefMutBufArray::new(self.raw.clone(), self.len)
+        )
     }
 }
 
@@ -199,6 +268,11 @@ impl<'buffer, T> BufArray<'buffer, T> {
         }
     }
     
+    /// Access the array without checking nor raising the borrow flags.
+    pub unsafe fn as_slice_unchecked(&self) -> &[T] {
+        std::slice::from_raw_parts(BufferBlock::leak_value_unchecked::<T>(self.raw.leak()), self.len)
+    }
+
     pub fn is_upserted(&self) -> bool {
         self.raw.is_upserted()
     }
@@ -206,10 +280,39 @@ impl<'buffer, T> BufArray<'buffer, T> {
     pub fn ack_upsertion(&mut self) {
         self.raw.drop_upserted()
     }
+
+    /// The cell to the underlying block.
+    pub fn raw(&self) -> &RawBufferCell<'buffer> {
+        &self.raw
+    }
+
+    /// Keep the block from being reclaimed, as long as the guard is held.
+    pub fn pin(&self) -> PinGuard<'buffer> {
+        PinGuard(self.raw.clone())
+    }
+}
+
+/// Keeps a block from being reclaimed, as long as it is held.
+pub struct PinGuard<'buffer>(RawBufferCell<'buffer>);
+
+impl<'buffer> PinGuard<'buffer> {
+    /// Number of cells and guards pinning the block.
+    pub fn pin_count(&self) -> usize {
+        self.0.rc()
+    }
+
+    /// Key under which the block is indexed, if any.
+    pub fn key(&self) -> Option<u64> {
+        self.0.key()
+    }
+
+    /// Access the pinned block as an array.
+    pub fn array<T>(&self) -> Option<BufArray<'buffer, T>> {
+        self.0.clone().try_into_array()
+    }
 }
 
 /// Immutable ref to an array stored in a buffer.
-#[derive(Clone)]
 pub struct RefBufArray<'buffer, T> {
     len: usize,
     raw: RawBufferCell<'buffer>,
@@ -217,6 +320,7 @@ pub struct RefBufArray<'buffer, T> {
 }
 
 impl<'buffer, T> RefBufArray<'buffer, T> {
+    /// The shared borrow must have been acquired.
     fn new(raw: RawBufferCell<'buffer>, len: usize) -> Self {
         Self {
             len,
@@ -226,6 +330,20 @@ impl<'buffer, T> RefBufArray<'buffer, T> {
     }
 }
 
+impl<'buffer, T> Clone for RefBufArray<'buffer, T> {
+    fn clone(&self) -> Self {
+        // Cannot fail, as the block is already borrowed by this reader.
+        self.raw.try_borrow_shared().unwrap();
+        Self::new(self.raw.clone(), self.len)
+    }
+}
+
+impl<'buffer, T> Drop for RefBufArray<'buffer, T> {
+    fn drop(&mut self) {
+        self.raw.release_shared();
+    }
+}
+
 impl<'buffer, T> AsRef<[T]> for RefBufArray<'buffer, T> {
 
     fn as_ref(&self) -> &[T] {
@@ -248,7 +366,7 @@ impl<'buffer, T> Deref for RefBufArray<'buffer, T> {
 impl<'buffer, T: 'static> RefBorrow<'buffer, [T]> for RefBufArray<'buffer, T> {
     fn borrow_ref(&self) -> Self::Ref {
         unsafe {
-            std::slice::from_raw_parts(BufferBlock::leak_value_unchecked::<T>(self.raw.leak()), self.len).into()
+            std::slice::from_raw_parts(BufferBlock::leak_value_unchecked::<T>(self.raw.leak()), self.len)
         }
     }
 
@@ -263,21 +381,19 @@ pub struct RefMutBufArray<'buffer, T> {
 }
 
 impl<'buffer, T> RefMutBufArray<'buffer, T> {
+    /// The exclusive borrow must have been acquired.
     fn new(raw: RawBufferCell<'buffer>, len: usize) -> Self {
-        let mut_ref = Self {
+        Self {
             len,
             raw,
             _pht: Default::default()
-        };
-
-        mut_ref.raw.raise_mut_borrow();
-        mut_ref
+        }
     }
 }
 
 impl<'buffer, T> Drop for RefMutBufArray<'buffer, T> {
     fn drop(&mut self) {
-        self.raw.drop_mut_borrow();
+        self.raw.release_exclusive();
     }
 }
 
@@ -324,7 +440,7 @@ impl<'buffer, T> RefBorrow<'buffer, [T]> for RefMutBufArray<'buffer, T> where T:
                 BufferBlock::leak_value_unchecked::<T>(
                     self.raw.leak()
                 ), self.len
-            ).into()
+            )
         }
     }
 }
@@ -334,7 +450,7 @@ impl<'buffer, T> RefBorrowMut<'buffer, [T]> for RefMutBufArray<'buffer, T> where
 
     fn borrow_mut_ref(&mut self) -> Self::RefMut {
         unsafe {
-            std::slice::from_raw_parts_mut(BufferBlock::leak_value_unchecked::<T>(self.raw.leak_mut()), self.len).into()
+            std::slice::from_raw_parts_mut(BufferBlock::leak_value_unchecked::<T>(self.raw.leak_mut()), self.len)
         }
     }
 }
@@ -342,222 +458,1060 @@ impl<'buffer, T> RefBorrowMut<'buffer, [T]> for RefMutBufArray<'buffer, T> where
 
 pub struct BufferBlock {
     pub size:           usize,
-    pub rc:             usize,
-    pub lru:            usize,
-    pub free:           bool,
-    pub upserted:       bool,
-    pub mut_borrowed:   bool,
-    pub next:           *mut BufferBlock
+    /// Bytes handed out to the array, the rest of the block is slack from the size class.
+    pub used:           usize,
+    pub index:          usize,
+    pub rc:             AtomicUsize,
+    pub key:            AtomicU64,
+    pub free:           AtomicBool,
+    pub upserted:       AtomicBool,
+    /// Raised on each hit, cleared by the eviction policy.
+    pub accessed:       AtomicBool,
+    pub borrow:         AtomicUsize,
+    pub next:           AtomicPtr<BufferBlock>,
+    /// Only accessed under the allocation lock.
+    pub prev:           *mut BufferBlock
 }
 
 impl BufferBlock {
     pub fn new(size: usize) -> Self {
         Self {
             size,
-            free: false,
-            rc: 0,
-            lru: 0,
-            upserted: false,
-            mut_borrowed: false,
-            next: std::ptr::null_mut()
+            used: size,
+            index: 0,
+            free: AtomicBool::new(false),
+            rc: AtomicUsize::new(0),
+            key: AtomicU64::new(NO_KEY),
+            upserted: AtomicBool::new(false),
+            accessed: AtomicBool::new(false),
+            borrow: AtomicUsize::new(0),
+            next: AtomicPtr::new(std::ptr::null_mut()),
+            prev: std::ptr::null_mut()
         }
     }
 
+    /// Memory footprint of a block, padded to keep the next block header aligned.
     pub fn size_of(size: usize) -> usize {
-        std::mem::size_of::<Self>() + size
+        (std::mem::size_of::<Self>() + size).next_multiple_of(std::mem::align_of::<Self>())
     }
 
     pub unsafe fn tail(raw: *mut Self) -> *mut Self {
-        (raw as *mut u8).offset((Self::size_of((*raw).size)) as isize) as *mut Self
+        (raw as *mut u8).add(Self::size_of((*raw).size)) as *mut Self
     }
 
     pub unsafe fn leak_value_unchecked<T>(raw: *mut Self) -> *mut T {
-        (*raw).lru += 1;
-        (raw as *mut u8).offset(std::mem::size_of::<Self>() as isize) as *mut T
+        (raw as *mut u8).add(std::mem::size_of::<Self>()) as *mut T
     }
 
     pub fn match_size(&self, size: usize) -> bool {
         self.size == size
     }
 
-    pub fn lru(&self) -> usize {
-        return self.lru
+    /// The block can hold size bytes.
+    pub fn fits(&self, size: usize) -> bool {
+        self.size >= size
+    }
+
+    pub fn rc(&self) -> usize {
+        return self.rc.load(Ordering::Acquire) & !RECLAIMING
+    }
+
+    pub fn key(&self) -> Option<u64> {
+        match self.key.load(Ordering::Acquire) {
+            NO_KEY => None,
+            key => Some(key)
+        }
     }
 
     pub fn is_upserted(&self) -> bool {
-        return self.upserted
+        return self.upserted.load(Ordering::Acquire)
     }
 
     pub fn is_mut_borrowed(&self) -> bool {
-        self.mut_borrowed
+        self.borrow.load(Ordering::Acquire) == EXCLUSIVE
     }
 
-    pub fn drop_mut_borrow(&mut self) {
-        self.mut_borrowed = false;
+    pub fn readers(&self) -> usize {
+        match self.borrow.load(Ordering::Acquire) {
+            EXCLUSIVE => 0,
+            readers => readers
+        }
     }
 
-    pub fn raise_mut_borrow(&mut self) {
-        self.mut_borrowed = true;
+    /// Add a reader, unless the block is exclusively borrowed.
+    pub fn try_borrow_shared(&self) -> Result<()> {
+        self.borrow
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |borrow| (borrow < EXCLUSIVE - 1).then(|| borrow + 1))
+        .map(|_| ())
+        .map_err(|_| Error::MutablyBorrowed)
+    }
+
+    pub fn release_shared(&self) {
+        self.borrow.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Borrow the block exclusively, unless it is already borrowed.
+    pub fn try_borrow_exclusive(&self) -> Result<()> {
+        match self.borrow.compare_exchange(0, EXCLUSIVE, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => Ok(()),
+            Err(EXCLUSIVE) => Err(Error::MutablyBorrowed),
+            Err(_) => Err(Error::ImmutablyBorrowed)
+        }
+    }
+
+    pub fn release_exclusive(&self) {
+        self.borrow.store(0, Ordering::Release);
     }
 
     pub fn is_free(&self) -> bool {
-        return self.free
+        return self.free.load(Ordering::Acquire)
     }
 
     pub fn is_unshared(&self) -> bool {
-        self.rc == 0
+        self.rc.load(Ordering::Acquire) == 0
+    }
+
+    /// Increment the pin count, unless the block is being reclaimed.
+    pub fn try_pin(&self) -> bool {
+        self.rc
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |rc| (rc & RECLAIMING == 0).then_some(rc + 1))
+        .is_ok()
+    }
+
+    /// Mark an unshared block as being reclaimed, no one can pin it through the index until it is released.
+    pub fn try_reclaim(&self) -> bool {
+        self.rc.compare_exchange(0, RECLAIMING, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    /// Mark a block pinned by a single cell as being reclaimed.
+    pub fn try_reclaim_last(&self) -> bool {
+        self.rc.compare_exchange(1, RECLAIMING | 1, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    pub fn release_reclaim(&self) {
+        self.rc.fetch_and(!RECLAIMING, Ordering::AcqRel);
+    }
+
+    /// Reset the state of a block handed out again.
+    fn reset(&mut self, used: usize) {
+        self.used = used;
+        self.free.store(false, Ordering::Release);
+        self.upserted.store(false, Ordering::Release);
+        self.accessed.store(false, Ordering::Release);
+        self.borrow.store(0, Ordering::Release);
+        self.key.store(NO_KEY, Ordering::Release);
     }
 }
 
-pub struct Buffer 
-{
-    pub layout: Layout,
-    // The base of the allocated area
-    pub base: *mut BufferBlock,
-    // Last element of the linked list of blocks
-    pub last: std::cell::RefCell<*mut BufferBlock>,
-    // The tail of the managed area, and the head of the heap.
-    pub tail: std::cell::RefCell<*mut BufferBlock>,
-    // The limit of the whole allocated area
-    pub end: *mut BufferBlock,
-    // Number of allocated blocks in the buffer
-    pub block_count: usize
+/// Blocks are carved in size classes: 8 classes per power of two, and at least MIN_BLOCK_SIZE bytes.
+pub fn size_class(size: usize) -> usize {
+    if size <= MIN_BLOCK_SIZE {
+        return MIN_BLOCK_SIZE;
+    }
+
+    let step = (size.next_power_of_two() / CLASSES_PER_POWER).max(MIN_BLOCK_SIZE);
+    return size.next_multiple_of(step);
 }
 
-struct BufferBlockIterator(*mut BufferBlock);
+/// Snapshot of the activity of the buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    /// Lookups finding the block in the index.
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks reclaimed to make room.
+    pub evictions: u64,
+    /// Dirty blocks written back before being reclaimed.
+    pub write_backs: u64,
+    pub pinned_blocks: usize,
+    /// Bytes handed out to the allocated blocks.
+    pub bytes_in_use: usize
+}
 
-impl Iterator for BufferBlockIterator {
-    type Item = *mut BufferBlock;
+/// Counters of the buffer activity, updated without holding any lock.
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.0.is_null() {
-            return None;
-        } else {
-            let block = self.0;
-            unsafe {
-                self.0 = (*self.0).next;
+/// Memory area the blocks are carved from, they are laid out back to back from its base.
+/// The area is mapped, so that its end can be released while its blocks stay in place.
+struct Region {
+    base: *mut BufferBlock,
+    /// The head of the unused part of the region.
+    tail: *mut BufferBlock,
+    end: *mut BufferBlock
+}
+
+impl Region {
+    fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        assert!(align <= page_size());
+
+        unsafe {
+            let base = libc::mmap(std::ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+
+            if base == libc::MAP_FAILED {
+                std::alloc::handle_alloc_error(layout);
             }
-            return Some(block);
+
+            let base = base as *mut BufferBlock;
+            let end = (base as *mut u8).add(size) as *mut BufferBlock;
+            Self { base, tail: base, end }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.end as usize - self.base as usize
+    }
+
+    /// Release the end of the region beyond size bytes, the blocks must have been released.
+    unsafe fn truncate(&mut self, size: usize) {
+        let end = (self.base as *mut u8).add(size);
+        debug_assert!(self.tail as *mut u8 <= end);
+
+        // Only whole pages are unmapped, the last one is kept until the region is dropped.
+        let (from, to) = ((end as usize).next_multiple_of(page_size()), (self.end as usize).next_multiple_of(page_size()));
+
+        if from < to {
+            libc::munmap(from as *mut libc::c_void, to - from);
+        }
+
+        self.end = end as *mut BufferBlock;
+    }
+
+    /// Bytes left beyond the tail.
+    fn remaining(&self) -> usize {
+        self.end as usize - self.tail as usize
+    }
+
+    /// Blocks carved from the region, in memory order.
+    unsafe fn blocks(&self) -> Vec<*mut BufferBlock> {
+        let mut blocks = Vec::new();
+        let mut block = self.base;
+
+        while block < self.tail {
+            blocks.push(block);
+            block = BufferBlock::tail(block);
         }
+
+        blocks
     }
 }
 
-pub struct BufCellIterator<'buffer>(BufferBlockIterator, std::marker::PhantomData<&'buffer ()>);
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size());
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Bookkeeping of the blocks, guarded by the allocation lock.
+#[derive(Default)]
+struct Heap {
+    /// Regions, the blocks are only carved from the last one.
+    regions: Vec<Region>,
+    /// First and last elements of the linked list of blocks, ordered as their regions.
+    head: *mut BufferBlock,
+    last: *mut BufferBlock,
+    /// Blocks by index, null once merged into another block.
+    blocks: Vec<*mut BufferBlock>,
+    /// Indices of the merged blocks, to be reused.
+    unused: Vec<usize>,
+    /// Free blocks, ordered by size.
+    free: BTreeSet<(usize, *mut BufferBlock)>
+}
+
+impl Heap {
+    /// Blocks starting a region are not adjacent to the previous ones.
+    fn starts_region(&self, block: *mut BufferBlock) -> bool {
+        self.regions.iter().any(|region| region.base == block)
+    }
+}
+
+/// Part of the index of the blocks, with the hits not reported to the eviction policy yet.
+#[derive(Default)]
+struct Shard {
+    blocks: HashMap<u64, *mut BufferBlock>,
+    hits: Vec<usize>
+}
+
+pub struct Buffer 
+{
+    // Footprint of a block holding an array of the size the buffer was created for
+    unit: usize,
+    // Alignment of the regions
+    align: usize,
+    // Number of allocated blocks in the buffer
+    pub block_count: AtomicUsize,
+    // Blocks and free blocks, the lock serializes the allocations
+    heap: Mutex<Heap>,
+    // Selects the blocks to reclaim
+    policy: Mutex<Box<dyn EvictionPolicy>>,
+    // Index of the blocks by key, sharded to limit the contention
+    shards: Box<[Mutex<Shard>]>,
+    counters: Counters,
+    // Memory granted by the budget the buffer is registered to
+    share: OnceLock<Arc<BudgetShare>>
+}
+
+// Blocks are shared through pin counts and borrow flags which are atomics,
+// the list of blocks is only modified, and walked by the iterators, under the allocation lock.
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+/// Iterate over the blocks holding arrays, the last visited block is pinned to keep its region alive.
+pub struct BufCellIterator<'buffer> {
+    buffer: &'buffer Buffer,
+    cursor: Option<RawBufferCell<'buffer>>,
+    done: bool
+}
 
 impl<'buffer> Iterator for BufCellIterator<'buffer> {
     type Item = RawBufferCell<'buffer>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            None => None,
-            Some(block) => Some(RawBufferCell::from(block))
+        if self.done {
+            return None;
+        }
+
+        let heap = self.buffer.heap.lock().unwrap();
+
+        unsafe {
+            let mut block = match &self.cursor {
+                None => heap.head,
+                Some(cursor) => (*cursor.leak()).next.load(Ordering::Acquire)
+            };
+
+            // Free blocks hold nothing, and blocks being reclaimed are about to be handed to a new owner.
+            loop {
+                if block.is_null() {
+                    self.done = true;
+                    self.cursor = None;
+                    return None;
+                }
+
+                if !(*block).is_free() {
+                    if let Some(cell) = RawBufferCell::try_pin(block) {
+                        self.cursor = Some(cell.clone());
+                        return Some(cell);
+                    }
+                }
+
+                block = (*block).next.load(Ordering::Acquire);
+            }
         }
     }
 }
 
-
 impl Buffer {
     /// Create a buffer intented to be used with equal_sized memory blocks.
     pub fn new_by_type<T>(capacity: usize) -> Self {
-        let align   = (std::mem::size_of::<T>() + std::mem::size_of::<BufferBlock>()).next_power_of_two();
-        let size    = capacity.wrapping_mul(align);
-        let layout = Layout::from_size_align(size, align).unwrap();
+        Self::new_by_array::<T>(1, capacity)
+    }
+
+    /// Create a buffer able to hold capacity arrays of array_size elements.
+    /// Blocks are laid out back to back, only the block header alignment is enforced.
+    pub fn new_by_array<T>(array_size: usize, capacity: usize) -> Self {
+        Self::with_shards::<T>(array_size, capacity, DEFAULT_SHARDS)
+    }
+
+    /// Create a buffer able to hold capacity arrays of array_size elements, indexed over shards.
+    pub fn with_shards<T>(array_size: usize, capacity: usize, shards: usize) -> Self {
+        let block_data_size = size_class(std::mem::size_of::<T>().wrapping_mul(array_size));
+        let align   = std::mem::align_of::<BufferBlock>().max(std::mem::align_of::<T>());
+        let unit    = BufferBlock::size_of(block_data_size);
+        let size    = capacity.wrapping_mul(unit);
+        let mut heap = Heap::default();
+
+        if size > 0 {
+            heap.regions.push(Region::new(size, align));
+        }
+
+        Self { 
+            unit,
+            align,
+            block_count: AtomicUsize::new(0),
+            heap: Mutex::new(heap),
+            policy: Mutex::new(Box::new(Clock::new())),
+            shards: (0..shards.max(1)).map(|_| Mutex::new(Shard::default())).collect(),
+            counters: Default::default(),
+            share: OnceLock::new()
+        }       
+    }
+
+    /// Number of arrays, of the size the buffer was created for, the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.heap.lock().unwrap().regions.iter().map(Region::size).sum::<usize>() / self.unit
+    }
+
+    /// Number of arrays, of the size the buffer was created for, the buffer can still hold without reclaiming any block.
+    pub fn free_capacity(&self) -> usize {
+        let heap = self.heap.lock().unwrap();
+        let size: usize = heap.regions.iter().map(Region::size).sum();
+
+        let used: usize = heap.blocks
+        .iter()
+        .filter_map(|block| unsafe { block.as_ref() })
+        .filter(|block| !block.is_free())
+        .map(|block| BufferBlock::size_of(block.size))
+        .sum();
+
+        size.saturating_sub(used) / self.unit
+    }
+
+    /// Register the buffer to the memory budget, it then grows on demand while the ceiling allows it.
+    /// Fails if its current capacity does not fit under the ceiling.
+    pub fn join_budget(&self, budget: &Arc<MemoryBudget>) -> Result<()> {
+        let heap = self.heap.lock().unwrap();
+
+        if self.share.get().is_some() {
+            return Err(Error::AlreadyBudgeted);
+        }
+
+        let size = heap.regions.iter().map(Region::size).sum();
+        let share = budget.register(size, self.unit).ok_or(Error::NotEnoughSpace)?;
+        self.share.set(share).map_err(|_| Error::AlreadyBudgeted)
+    }
+
+    /// Give back the memory beyond the target set by the budget, dirty blocks are not evicted.
+    pub fn trim(&self) -> Result<()> {
+        self.give_back(&mut self.heap.lock().unwrap(), None)
+    }
+
+    /// Give back the memory beyond the target set by the budget, dirty blocks may be written back to be evicted.
+    pub fn trim_with_write_back(&self, write_back: &dyn WriteBack) -> Result<()> {
+        self.give_back(&mut self.heap.lock().unwrap(), Some(write_back))
+    }
+
+    /// Release the most recent regions whose blocks can all be evicted, until the buffer is within its target.
+    fn give_back(&self, heap: &mut Heap, write_back: Option<&dyn WriteBack>) -> Result<()> {
+        let share = match self.share.get() {
+            Some(share) if share.is_shrinking() => share,
+            _ => return Ok(())
+        };
+
+        let mut index = heap.regions.len();
+
+        unsafe {
+            while share.excess() > 0 && index > 0 {
+                index -= 1;
+                
+                if self.release_region(heap, index, write_back)? {
+                    self.sync_share(heap);
+                }
+            }
+        }
+
+        if share.excess() == 0 {
+            share.end_shrinking();
+        }
+
+        Ok(())
+    }
+
+    /// Report the memory held by the regions to the budget.
+    fn sync_share(&self, heap: &Heap) {
+        if let Some(share) = self.share.get() {
+            share.set_reserved(heap.regions.iter().map(Region::size).sum());
+        }
+    }
+
+    /// Ask the budget for a region able to hold size bytes.
+    unsafe fn grow(&self, heap: &mut Heap, size: usize) -> bool {
+        let share = match self.share.get() {
+            Some(share) => share,
+            None => return false
+        };
+
+        // Grow by a quarter of the buffer, at least.
+        let min = BufferBlock::size_of(size).next_multiple_of(self.unit);
+        let held: usize = heap.regions.iter().map(Region::size).sum();
+        let max = min.max(held / 4 / self.unit * self.unit);
+        let granted = share.request(min, max);
+
+        if granted == 0 {
+            return false;
+        }
+
+        self.push_region(heap, granted);
+        self.sync_share(heap);
+        return true;
+    }
+
+    /// Resize the buffer to hold capacity arrays, of the size it was created for.
+    /// Growing adds a region, shrinking releases the memory beyond the capacity: the most recent regions, and the end of the last one kept,
+    /// whose blocks must all be evictable.
+    /// Outstanding arrays stay valid, as pinned blocks are never evicted.
+    /// Fails if not enough regions could be released, dirty blocks are not evicted, or if the budget does not allow it to grow.
+    pub fn resize(&self, capacity: usize) -> Result<()> {
+        self.resize_regions(capacity, None)
+    }
+
+    /// Resize the buffer, dirty blocks may be written back to be evicted.
+    pub fn resize_with_write_back(&self, capacity: usize, write_back: &dyn WriteBack) -> Result<()> {
+        self.resize_regions(capacity, Some(write_back))
+    }
+
+    fn resize_regions(&self, capacity: usize, write_back: Option<&dyn WriteBack>) -> Result<()> {
+        let mut heap = self.heap.lock().unwrap();
+        let target = capacity.wrapping_mul(self.unit);
+        let mut size: usize = heap.regions.iter().map(Region::size).sum();
+        let mut index = heap.regions.len();
+
+        // The target of the share is left to the budget, only the bytes held by the regions are reported.
+        unsafe {
+            while size > target && index > 0 {
+                index -= 1;
+                let region_size = heap.regions[index].size();
+
+                // The blocks of the region within the target are kept.
+                if size - target < region_size {
+                    if index + 1 == heap.regions.len() && self.release_tail(&mut heap, index, region_size - (size - target), write_back)? {
+                        size = target;
+                        self.sync_share(&heap);
+                    }
+
+                    break;
+                }
+
+                if self.release_region(&mut heap, index, write_back)? {
+                    size -= region_size;
+                    self.sync_share(&heap);
+                }
+            }
+
+            if size > target {
+                return Err(Error::StillShared);
+            }
+
+            if size < target {
+                if let Some(share) = self.share.get() {
+                    if share.request(target - size, target - size) == 0 {
+                        return Err(Error::NotEnoughSpace);
+                    }
+                }
+
+                self.push_region(&mut heap, target - size);
+                self.sync_share(&heap);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a region of size bytes, the unused part of the last region is handed to the free blocks.
+    unsafe fn push_region(&self, heap: &mut Heap, size: usize) {
+        if let Some(region) = heap.regions.last_mut() {
+            let remaining = region.remaining();
+
+            if remaining >= BufferBlock::size_of(MIN_BLOCK_SIZE) {
+                let block = region.tail;
+                region.tail = region.end;
+                std::ptr::write(block, BufferBlock::new(remaining - std::mem::size_of::<BufferBlock>()));
+                self.register(heap, block);
+                self.link_last(heap, block);
+                self.free_block(heap, block);
+            }
+        }
+
+        heap.regions.push(Region::new(size, self.align));
+    }
+
+    /// Evict all the blocks of the region, and release its memory.
+    /// Returns false if one of its blocks is pinned, or dirty and cannot be written back.
+    unsafe fn release_region(&self, heap: &mut Heap, index: usize, write_back: Option<&dyn WriteBack>) -> Result<bool> {
+        let blocks = heap.regions[index].blocks();
+
+        if !self.release_blocks(heap, &blocks, write_back)? {
+            return Ok(false);
+        }
+
+        heap.regions.remove(index);
+        Ok(true)
+    }
+
+    /// Evict the blocks of the region reaching beyond size bytes, and release the memory beyond.
+    /// Returns false if one of them is pinned, or dirty and cannot be written back.
+    unsafe fn release_tail(&self, heap: &mut Heap, index: usize, size: usize, write_back: Option<&dyn WriteBack>) -> Result<bool> {
+        let end = (heap.regions[index].base as *mut u8).add(size);
+        let blocks: Vec<_> = heap.regions[index].blocks().into_iter().filter(|block| BufferBlock::tail(*block) as *mut u8 > end).collect();
+
+        if !self.release_blocks(heap, &blocks, write_back)? {
+            return Ok(false);
+        }
+
+        let region = &mut heap.regions[index];
+
+        if let Some(&first) = blocks.first() {
+            region.tail = first;
+        }
+
+        region.truncate(size);
+        Ok(true)
+    }
+
+    /// Evict the blocks, contiguous in memory, and drop them from the list of blocks.
+    /// Returns false if one of them is pinned, or dirty and cannot be written back.
+    unsafe fn release_blocks(&self, heap: &mut Heap, blocks: &[*mut BufferBlock], write_back: Option<&dyn WriteBack>) -> Result<bool> {
+        let mut claimed = Vec::new();
+
+        for &block in blocks {
+            if (*block).is_free() {
+                continue;
+            }
+
+            let evictable = !(*block).is_upserted() || (write_back.is_some() && (*block).key().is_some());
+
+            if evictable && (*block).try_reclaim() {
+                claimed.push(block);
+            } else {
+                claimed.iter().for_each(|block| (**block).release_reclaim());
+                return Ok(false);
+            }
+        }
+
+        for &block in &claimed {
+            if let Err(err) = self.write_back_block(block, write_back) {
+                claimed.iter().for_each(|block| (**block).release_reclaim());
+                return Err(err);
+            }
+        }
+
+        // The claimed blocks cannot be pinned again, as they are no longer indexed.
+        for &block in blocks {
+            if (*block).is_free() {
+                heap.free.remove(&((*block).size, block));
+            } else {
+                self.unindex(block);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+
+            self.unregister(heap, block);
+        }
+
+        if let (Some(&first), Some(&last)) = (blocks.first(), blocks.last()) {
+            let prev = (*first).prev;
+            let next = (*last).next.load(Ordering::Acquire);
+
+            if prev.is_null() { heap.head = next; } else { (*prev).next.store(next, Ordering::Release); }
+            if next.is_null() { heap.last = prev; } else { (*next).prev = prev; }
+        }
+
+        Ok(true)
+    }
+
+    /// Replace the policy selecting the blocks to reclaim.
+    pub fn set_eviction_policy(&self, mut policy: Box<dyn EvictionPolicy>) {
+        let heap = self.heap.lock().unwrap();
         
+        heap.blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, block)| !block.is_null())
+        .for_each(|(index, _)| policy.insert(index));
+
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    fn shard(&self, key: u64) -> &Mutex<Shard> {
+        &self.shards[(key % self.shards.len() as u64) as usize]
+    }
+
+    /// Get the block indexed by the key.
+    pub fn get(&self, key: u64) -> Option<RawBufferCell<'_>> {
+        let cell = self.lookup(key);
+        
+        match cell {
+            Some(_) => self.counters.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.counters.misses.fetch_add(1, Ordering::Relaxed)
+        };
+
+        if let (None, Some(share)) = (&cell, self.share.get()) {
+            share.record_miss();
+        }
+
+        return cell;
+    }
+
+    fn lookup(&self, key: u64) -> Option<RawBufferCell<'_>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let block = *shard.blocks.get(&key)?;
+        let cell = RawBufferCell::try_pin(block)?;
+        
+        if cell.key() != Some(key) {
+            return None;
+        }
+
         unsafe {
-            let base = std::alloc::alloc_zeroed(layout) as *mut BufferBlock;
-            let tail = std::cell::RefCell::new(base);
-            let end = (base as *mut u8).offset(size as isize) as *mut BufferBlock;    
-            Self { layout, base, last: std::cell::RefCell::new(std::ptr::null_mut()), tail, end, block_count: 0 }       
+            (*block).accessed.store(true, Ordering::Relaxed);
+            shard.hits.push((*block).index);
         }
+
+        // The hits are reported in batches, to take the policy lock once per batch.
+        if shard.hits.len() >= HIT_BATCH {
+            let hits = std::mem::take(&mut shard.hits);
+            std::mem::drop(shard);
+            let mut policy = self.policy.lock().unwrap();
+            hits.into_iter().for_each(|block| policy.touch(block));
+        }
+
+        return Some(cell);
     }
 
-    pub fn new_by_array<T>(array_size: usize, capacity: usize) -> Self {
-        let block_data_size = std::mem::size_of::<T>().wrapping_mul(array_size);
-        let align   = (block_data_size + std::mem::size_of::<BufferBlock>()).next_power_of_two();
-        let size    = capacity.wrapping_mul(align);
-        let layout = Layout::from_size_align(size, align).unwrap();
+    /// Index the block by the key.
+    /// If another block was indexed by the key in the meantime, it is kept and returned.
+    pub fn insert(&self, key: u64, cell: &RawBufferCell<'_>) -> Option<RawBufferCell<'_>> {
+        let mut shard = self.shard(key).lock().unwrap();
+
+        if let Some(existing) = shard.blocks.get(&key).and_then(|block| RawBufferCell::try_pin(*block)) {
+            if existing.key() == Some(key) && existing.leak() != cell.leak() {
+                return Some(existing);
+            }
+        }
+
+        unsafe {
+            (*cell.leak()).key.store(key, Ordering::Release);
+        }
+
+        shard.blocks.insert(key, cell.leak());
+        std::mem::drop(shard);
+
+        unsafe {
+            self.policy.lock().unwrap().indexed((*cell.leak()).index, key);
+        }
+
+        return None;
+    }
+
+    /// Remove the block indexed by the key from the index.
+    pub fn remove(&self, key: u64) {
+        let mut shard = self.shard(key).lock().unwrap();
         
+        if let Some(block) = shard.blocks.remove(&key) {
+            unsafe {
+                let _ = (*block).key.compare_exchange(key, NO_KEY, Ordering::AcqRel, Ordering::Acquire);
+            }
+        }
+    }
+
+    /// Remove a reclaimed block from the index.
+    unsafe fn unindex(&self, block: *mut BufferBlock) {
+        if let Some(key) = (*block).key() {
+            let mut shard = self.shard(key).lock().unwrap();
+            
+            if shard.blocks.get(&key) == Some(&block) {
+                shard.blocks.remove(&key);
+            }
+
+            (*block).key.store(NO_KEY, Ordering::Release);
+        }
+    }
+
+    pub fn iter(&self) -> BufCellIterator<'_> {
+        BufCellIterator { buffer: self, cursor: None, done: false }
+    }
+
+    /// Release the array, its block is returned to the free blocks, and coalesced with its free neighbours.
+    /// Fails if the block is still shared by other cells.
+    pub fn release<T>(&self, array: BufArray<'_, T>) -> Result<()> {
+        let mut heap = self.heap.lock().unwrap();
+        let block = array.raw().leak();
+
         unsafe {
-            let base = std::alloc::alloc_zeroed(layout) as *mut BufferBlock;
-            let tail = std::cell::RefCell::new(base);
-            let end = (base as *mut u8).offset(size as isize) as *mut BufferBlock;    
-            Self { layout, base, last: std::cell::RefCell::new(std::ptr::null_mut()), tail, end, block_count: 0 }       
-        }        
+            if !(*block).try_reclaim_last() {
+                return Err(Error::StillShared);
+            }
+
+            // The block cannot be pinned again, as it is not indexed, and the allocation lock is held.
+            self.unindex(block);
+            std::mem::drop(array);
+            (*block).release_reclaim();
+            self.free_block(&mut heap, block);
+        }
+
+        Ok(())
     }
 
-    fn iter_blocks(&self) -> BufferBlockIterator {
-        if *self.tail.borrow() == self.base {
-            return BufferBlockIterator(std::ptr::null_mut());
-        } else {
-            return BufferBlockIterator(self.base);
+    /// Pin the block indexed by the key.
+    pub fn pin(&self, key: u64) -> Option<PinGuard<'_>> {
+        self.get(key).map(PinGuard)
+    }
+
+    /// Snapshot of the activity of the buffer.
+    pub fn stats(&self) -> BufferStats {
+        let heap = self.heap.lock().unwrap();
+        
+        let (pinned_blocks, bytes_in_use) = heap.blocks
+        .iter()
+        .filter_map(|block| unsafe { block.as_ref() })
+        .filter(|block| !block.is_free())
+        .fold((0, 0), |(pinned, bytes), block| (pinned + (block.rc() > 0) as usize, bytes + block.used));
+
+        BufferStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            write_backs: self.counters.write_backs.load(Ordering::Relaxed),
+            pinned_blocks,
+            bytes_in_use
         }
     }
 
-    pub fn iter(&self) -> BufCellIterator {
-        BufCellIterator(self.iter_blocks(), Default::default())
+    /// Number of free blocks.
+    pub fn count_free_blocks(&self) -> usize {
+        self.heap.lock().unwrap().free.len()
     }
 
-    /// Find a candidate block (unshared and which lru is low) to reallocate
-    fn find_candidate_block(&self, size: usize) -> Option<*mut BufferBlock>
+    /// Find a candidate block (unshared, and selected by the eviction policy) to reallocate
+    /// Dirty blocks are candidates only if they can be written back.
+    fn find_candidate_block(&self, heap: &Heap, size: usize, write_back: bool) -> Option<*mut BufferBlock>
     {
-        self.iter_blocks()
-        .filter(|ptr| {
+        let mut policy = self.policy.lock().unwrap();
+
+        // The policy selects the victim knowing all the hits so far.
+        for shard in self.shards.iter() {
+            std::mem::take(&mut shard.lock().unwrap().hits).into_iter().for_each(|block| policy.touch(block));
+        }
+
+        policy.victim(&mut |index| {
             unsafe {
-                let block_ref = ptr.as_ref().unwrap();
-                block_ref.is_unshared() 
-                && block_ref.match_size(size) 
-                && !block_ref.is_upserted()
+                match heap.blocks.get(index).and_then(|block| block.as_ref()) {
+                    None => false,
+                    Some(block_ref) => {
+                        !block_ref.is_free()
+                        && block_ref.is_unshared() 
+                        && block_ref.fits(size) 
+                        && (!block_ref.is_upserted() || (write_back && block_ref.key().is_some()))
+                    }
+                }
             }
-        }).min_by_key(|ptr| {
+        }, &mut |index| {
             unsafe {
-                let block_ref = ptr.as_ref().unwrap();
-                block_ref.lru()
+                heap.blocks.get(index)
+                .and_then(|block| block.as_ref())
+                .is_some_and(|block_ref| block_ref.accessed.swap(false, Ordering::Relaxed))
             }
         })
+        .map(|index| heap.blocks[index])
     }
 
-    /// Find a free block
-    fn find_free_block(&self, size: usize) -> Option<*mut BufferBlock>
+    /// Reclaim a candidate block, it may be pinned concurrently, so retry until one is claimed.
+    unsafe fn reclaim_candidate_block(&self, heap: &Heap, size: usize, write_back: Option<&dyn WriteBack>) -> Result<Option<*mut BufferBlock>>
     {
-        self
-        .iter_blocks()
-        .find(|block_ptr| {
-            unsafe {
-                let block_ref = block_ptr.as_ref().unwrap();
-                block_ref.is_free() && block_ref.match_size(size)
+        loop {
+            let block = match self.find_candidate_block(heap, size, write_back.is_some()) {
+                Some(block) => block,
+                None => return Ok(None)
+            };
+
+            if (*block).try_reclaim() {
+                if let Err(err) = self.write_back_block(block, write_back) {
+                    (*block).release_reclaim();
+                    return Err(err);
+                }
+
+                let key = (*block).key();
+                self.unindex(block);
+                (*block).release_reclaim();
+
+                let mut policy = self.policy.lock().unwrap();
+
+                if let Some(key) = key {
+                    policy.evicted((*block).index, key);
+                }
+
+                policy.insert((*block).index);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(block));
             }
-        })
+        }
+    }
+
+    /// Write back the block if it is dirty, the block must be claimed.
+    unsafe fn write_back_block(&self, block: *mut BufferBlock, write_back: Option<&dyn WriteBack>) -> Result<()>
+    {
+        if let (true, Some(write_back), Some(key)) = ((*block).is_upserted(), write_back, (*block).key()) {
+            let content = std::slice::from_raw_parts_mut(BufferBlock::leak_value_unchecked::<u8>(block), (*block).used);
+            write_back.write_back(key, content).map_err(Error::WriteBackFailed)?;
+            (*block).upserted.store(false, Ordering::Release);
+            self.counters.write_backs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
     }
 
-    unsafe fn push_block(&self, size: usize) -> Result<*mut BufferBlock> 
+    /// Take the smallest free block able to hold size bytes.
+    unsafe fn take_free_block(&self, heap: &mut Heap, size: usize) -> Option<*mut BufferBlock>
     {
-        let new_tail = (*self.tail.borrow() as *mut u8).offset(BufferBlock::size_of(size) as isize) as *mut BufferBlock;  
+        let entry = *heap.free.range((size, std::ptr::null_mut())..).next()?;
+        heap.free.remove(&entry);
         
-        if new_tail >= self.end {
-            return Err(Error::NotEnoughSpace);
+        let block = entry.1;
+        (*block).free.store(false, Ordering::Release);
+        self.policy.lock().unwrap().insert((*block).index);
+        Some(block)
+    }
+
+    /// Give an index to the block, and register it to the eviction policy.
+    unsafe fn register(&self, heap: &mut Heap, block: *mut BufferBlock)
+    {
+        let index = match heap.unused.pop() {
+            Some(index) => {
+                heap.blocks[index] = block;
+                index
+            },
+            None => {
+                heap.blocks.push(block);
+                heap.blocks.len() - 1
+            }
+        };
+
+        (*block).index = index;
+        self.policy.lock().unwrap().insert(index);
+        self.block_count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    unsafe fn unregister(&self, heap: &mut Heap, block: *mut BufferBlock)
+    {
+        heap.blocks[(*block).index] = std::ptr::null_mut();
+        heap.unused.push((*block).index);
+        self.block_count.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Append the block to the list.
+    unsafe fn link_last(&self, heap: &mut Heap, block: *mut BufferBlock)
+    {
+        let last = heap.last;
+
+        if last.is_null() {
+            heap.head = block;
+        } else {
+            (*block).prev = last;
+            (*last).next.store(block, Ordering::Release);
         }
 
-        let new_block = *self.tail.borrow();
-        *new_block = BufferBlock::new(size);      
+        heap.last = block;
+    }
 
-        let last = *self.last.borrow();
+    /// Insert the block in the list, after the previous one.
+    unsafe fn link_after(&self, heap: &mut Heap, prev: *mut BufferBlock, block: *mut BufferBlock)
+    {
+        let next = (*prev).next.load(Ordering::Acquire);
+        (*block).prev = prev;
+        (*block).next.store(next, Ordering::Release);
 
-        if !last.is_null() {
-            (*last).next = new_block;    
+        if next.is_null() {
+            heap.last = block;
+        } else {
+            (*next).prev = block;
         }
 
-        *self.last.borrow_mut() = new_block;
-        *self.tail.borrow_mut() = new_tail;
+        (*prev).next.store(block, Ordering::Release);
+    }
+
+    /// Split the end of the block beyond size bytes into a free block, if it is worth it.
+    unsafe fn split_block(&self, heap: &mut Heap, block: *mut BufferBlock, size: usize)
+    {
+        let footprint = BufferBlock::size_of(size);
+        let remainder = BufferBlock::size_of((*block).size) - footprint;
+
+        if remainder < BufferBlock::size_of(MIN_BLOCK_SIZE) {
+            return;
+        }
+
+        (*block).size = footprint - std::mem::size_of::<BufferBlock>();
+        
+        let rest = (block as *mut u8).add(footprint) as *mut BufferBlock;
+        std::ptr::write(rest, BufferBlock::new(remainder - std::mem::size_of::<BufferBlock>()));
+        
+        self.link_after(heap, block, rest);
+        self.register(heap, rest);
+        self.free_block(heap, rest);
+    }
+
+    /// Absorb the next block, which is adjacent in memory.
+    unsafe fn merge_block(&self, heap: &mut Heap, block: *mut BufferBlock, next: *mut BufferBlock)
+    {
+        let after = (*next).next.load(Ordering::Acquire);
+        
+        (*block).size = BufferBlock::size_of((*block).size) + BufferBlock::size_of((*next).size) - std::mem::size_of::<BufferBlock>();
+        (*block).next.store(after, Ordering::Release);
+
+        if after.is_null() {
+            heap.last = block;
+        } else {
+            (*after).prev = block;
+        }
+
+        self.unregister(heap, next);
+    }
+
+    /// Mark the block as free, and coalesce it with its free neighbours.
+    unsafe fn free_block(&self, heap: &mut Heap, block: *mut BufferBlock)
+    {
+        (*block).free.store(true, Ordering::Release);
+        (*block).upserted.store(false, Ordering::Release);
+        (*block).key.store(NO_KEY, Ordering::Release);
+
+        let next = (*block).next.load(Ordering::Acquire);
+
+        if !next.is_null() && (*next).is_free() && !heap.starts_region(next) {
+            heap.free.remove(&((*next).size, next));
+            self.merge_block(heap, block, next);
+        }
+
+        let prev = (*block).prev;
+
+        let block = if !prev.is_null() && (*prev).is_free() && !heap.starts_region(block) {
+            heap.free.remove(&((*prev).size, prev));
+            self.merge_block(heap, prev, block);
+            prev
+        } else {
+            block
+        };
+
+        heap.free.insert(((*block).size, block));
+    }
+
+    unsafe fn push_block(&self, heap: &mut Heap, size: usize) -> Result<*mut BufferBlock> 
+    {
+        let footprint = BufferBlock::size_of(size);
+
+        let new_block = match heap.regions.last_mut() {
+            Some(region) if region.remaining() >= footprint => {
+                let block = region.tail;
+                region.tail = (block as *mut u8).add(footprint) as *mut BufferBlock;
+                block
+            },
+            _ => return Err(Error::NotEnoughSpace)
+        };
+
+        std::ptr::write(new_block, BufferBlock::new(size));      
+        self.register(heap, new_block);
+        self.link_last(heap, new_block);
 
         Ok(new_block)
     }
 
-    unsafe fn push_block_or_free_candidate(&self, size: usize) -> Result<*mut BufferBlock>
+    unsafe fn push_block_or_free_candidate(&self, heap: &mut Heap, size: usize, write_back: Option<&dyn WriteBack>) -> Result<*mut BufferBlock>
     {       
-        match self.push_block(size) {
+        match self.push_block(heap, size) {
             Err(Error::NotEnoughSpace) => {
+                // Grow within the budget, rather than evicting.
+                if self.grow(heap, size) {
+                    return self.push_block(heap, size);
+                }
                 
-                if let Some(block) = self.find_candidate_block(size) 
+                if let Some(block) = self.reclaim_candidate_block(heap, size, write_back)? 
                 {
                     return Ok(block);
                 }
@@ -568,36 +1522,52 @@ impl Buffer {
         }
     }
 
+    /// Allocate a block, it is not pinned once the allocation lock is released.
     pub fn alloc_raw(&self, size: usize) -> Result<*mut BufferBlock>
     {
+        let mut heap = self.heap.lock().unwrap();
+        self.alloc_block(&mut heap, size, None)
+    }
+
+    fn alloc_block(&self, heap: &mut Heap, used: usize, write_back: Option<&dyn WriteBack>) -> Result<*mut BufferBlock>
+    {
+        let size = size_class(used);
+        self.give_back(heap, write_back)?;
+
         unsafe {
-            let block = if let Some(block) = self.find_free_block(size) {
+            let block = if let Some(block) = self.take_free_block(heap, size) {
                 block
             } else {
-                self.push_block_or_free_candidate(size)?
+                self.push_block_or_free_candidate(heap, size, write_back)?
             };
+
+            self.split_block(heap, block, size);
+            (*block).reset(used);
             return Ok(block)
         }
     }
 
     pub fn alloc_array_uninit<'a, T>(&'a self, len: usize) -> Result<BufArray<'a, T>> {
-        let block = self.alloc_raw(std::mem::size_of::<T>().wrapping_mul(len))?; 
-        Ok(BufArray::new(block, len))
+        self.alloc_array(len, None)
     }
-}
 
-impl Drop for Buffer {
-    fn drop(&mut self) {
-        unsafe {
-            std::alloc::dealloc(self.base as *mut u8, self.layout);
-        }
+    /// Allocate an array, dirty blocks may be written back to be reclaimed.
+    pub fn alloc_array_uninit_with_write_back<'a, T>(&'a self, len: usize, write_back: &dyn WriteBack) -> Result<BufArray<'a, T>> {
+        self.alloc_array(len, Some(write_back))
+    }
+
+    fn alloc_array<'a, T>(&'a self, len: usize, write_back: Option<&dyn WriteBack>) -> Result<BufArray<'a, T>> {
+        // The block is pinned before another allocation can reclaim it.
+        let mut heap = self.heap.lock().unwrap();
+        let block = self.alloc_block(&mut heap, std::mem::size_of::<T>().wrapping_mul(len), write_back)?; 
+        Ok(BufArray::new(block, len))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{fixtures, utils::borrow::{TryBorrowMut, TryBorrow}};
-    use super::{Buffer};
+    use super::{Buffer, Error, eviction::Lru};
 
     #[test]
     fn test_buffer() -> super::Result<()> {
@@ -612,5 +1582,225 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_buffer_borrows_and_pins() -> super::Result<()> {
+        let buffer = Buffer::new_by_array::<u8>(1024, 1);
+        let mut arr = buffer.alloc_array_uninit::<u8>(1024)?;
+        
+        {
+            let reader = arr.try_borrow()?;
+            let other = reader.clone();
+            assert_eq!(arr.raw().readers(), 2);
+            assert!(matches!(arr.try_borrow_mut(), Err(Error::ImmutablyBorrowed)));
+            drop((reader, other));
+        }
+
+        {
+            let _writer = arr.try_borrow_mut()?;
+            assert!(matches!(arr.try_borrow(), Err(Error::MutablyBorrowed)));
+            assert!(matches!(arr.try_borrow_mut(), Err(Error::MutablyBorrowed)));
+        }
+
+        assert_eq!(arr.raw().readers(), 0);
+        arr.ack_upsertion();
+
+        // The guard keeps the block from being reclaimed.
+        let guard = arr.pin();
+        drop(arr);
+        assert_eq!(guard.pin_count(), 1);
+        assert_eq!(buffer.stats().pinned_blocks, 1);
+        assert!(matches!(buffer.alloc_array_uninit::<u8>(1024), Err(Error::NotEnoughSpace)));
+        
+        drop(guard);
+        assert!(buffer.alloc_array_uninit::<u8>(1024).is_ok());
+
+        let stats = buffer.stats();
+        assert_eq!((stats.evictions, stats.pinned_blocks, stats.bytes_in_use), (1, 0, 1024));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_release() -> super::Result<()> {
+        let buffer = Buffer::new_by_array::<u8>(1024, 3);
+        let first = buffer.alloc_array_uninit::<u8>(1024)?;
+        let second = buffer.alloc_array_uninit::<u8>(1024)?;
+        let third = buffer.alloc_array_uninit::<u8>(1024)?;
+
+        let shared = third.clone();
+        assert!(matches!(buffer.release(third), Err(super::Error::StillShared)));
+
+        // Adjacent free blocks are coalesced.
+        buffer.release(first)?;
+        buffer.release(second)?;
+        assert_eq!(buffer.count_free_blocks(), 1);
+
+        // Smaller arrays are split from the free block.
+        let small = buffer.alloc_array_uninit::<u8>(100)?;
+        assert_eq!(small.try_borrow()?.len(), 100);
+        assert_eq!(buffer.count_free_blocks(), 1);
+        buffer.release(small)?;
+
+        let large = buffer.alloc_array_uninit::<u8>(2000)?;
+        assert_eq!(large.try_borrow()?.len(), 2000);
+        assert_eq!(buffer.count_free_blocks(), 0);
+        assert_eq!(buffer.iter().count(), 2);
+
+        buffer.release(large)?;
+        buffer.release(shared)?;
+        assert_eq!(buffer.count_free_blocks(), 1);
+        assert_eq!(buffer.iter().count(), 0);
+
+        // The iterator does not pin a block being reclaimed.
+        let block = buffer.alloc_array_uninit::<u8>(1024)?.raw().leak();
+        assert!(unsafe { (*block).try_reclaim() });
+        assert_eq!(buffer.iter().count(), 0);
+        unsafe { (*block).release_reclaim() };
+        assert_eq!(buffer.iter().count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_resize() -> super::Result<()> {
+        let buffer = Buffer::new_by_array::<u8>(1024, 2);
+        let mut first = buffer.alloc_array_uninit::<u8>(1024)?;
+        first.try_borrow_mut()?.fill(1);
+        first.ack_upsertion();
+        buffer.alloc_array_uninit::<u8>(1024)?;
+
+        buffer.resize(4)?;
+        assert_eq!(buffer.capacity(), 4);
+        let added = [buffer.alloc_array_uninit::<u8>(1024)?, buffer.alloc_array_uninit::<u8>(1024)?];
+        
+        // The blocks of the added region are pinned.
+        assert!(matches!(buffer.resize(2), Err(Error::StillShared)));
+        drop(added);
+        buffer.resize(2)?;
+        assert_eq!(buffer.capacity(), 2);
+        
+        // Shrinking within the first region only releases its end, the pinned array stays.
+        buffer.resize(1)?;
+        assert_eq!(buffer.capacity(), 1);
+        assert!(first.try_borrow()?.iter().all(|byte| *byte == 1));
+        assert!(matches!(buffer.resize(0), Err(Error::StillShared)));
+        assert!(matches!(buffer.alloc_array_uninit::<u8>(1024), Err(Error::NotEnoughSpace)));
+        drop(first);
+        
+        assert_eq!(buffer.iter().count(), 1);
+        assert!(buffer.alloc_array_uninit::<u8>(1024).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_shrink_by_one_block() -> super::Result<()> {
+        let buffer = Buffer::new_by_array::<u8>(1024, 4);
+
+        for key in 0..4u8 {
+            let mut arr = buffer.alloc_array_uninit::<u8>(1024)?;
+            arr.try_borrow_mut()?.fill(key);
+            arr.ack_upsertion();
+            buffer.insert(u64::from(key), arr.raw());
+        }
+
+        // Only the last block is evicted, the others stay indexed with their content.
+        buffer.resize(3)?;
+        assert_eq!((buffer.capacity(), buffer.stats().evictions), (3, 1));
+        assert!(buffer.get(3).is_none());
+
+        for key in 0..3u8 {
+            let arr = buffer.get(u64::from(key)).and_then(|cell| cell.try_into_array::<u8>()).unwrap();
+            assert!(arr.try_borrow()?.iter().all(|byte| *byte == key));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_lru() -> super::Result<()> {
+        let buffer = Buffer::new_by_array::<u8>(1024, 3);
+        buffer.set_eviction_policy(Box::new(Lru::new()));
+
+        let alloc = |key: u64| -> super::Result<()> {
+            let mut arr = buffer.alloc_array_uninit::<u8>(1024)?;
+            arr.ack_upsertion();
+            buffer.insert(key, arr.raw());
+            Ok(())
+        };
+
+        (10..13).try_for_each(alloc)?;
+        buffer.get(11);
+        alloc(13)?;
+        buffer.get(12);
+
+        // The hits reach the policy: 11 was hit before 13 was allocated, it is the least recently used.
+        alloc(14)?;
+        assert!(buffer.get(11).is_none());
+        assert!([12, 13, 14].iter().all(|key| buffer.get(*key).is_some()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_shared_between_threads() -> super::Result<()> {
+        fn assert_sync<T: Send + Sync>(_: &T) {}
+
+        let buffer = Buffer::new_by_array::<u8>(1024, 32);
+        assert_sync(&buffer);
+
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4u8).map(|thread| {
+                let buffer = &buffer;
+
+                scope.spawn(move || -> super::Result<()> {
+                    let mut arrays = Vec::new();
+
+                    for i in 0..8u64 {
+                        let mut arr = buffer.alloc_array_uninit::<u8>(1024)?;
+                        arr.try_borrow_mut()?.fill(thread);
+                        arr.ack_upsertion();
+                        assert!(buffer.insert(u64::from(thread) * 100 + i, arr.raw()).is_none());
+                        arrays.push(arr);
+                    }
+
+                    for i in 0..8u64 {
+                        let arr = buffer.get(u64::from(thread) * 100 + i).and_then(|cell| cell.try_into_array::<u8>()).unwrap();
+                        assert!(arr.try_borrow()?.iter().all(|byte| *byte == thread));
+                    }
+
+                    Ok(())
+                })
+            }).collect();
+
+            threads.into_iter().try_for_each(|thread| thread.join().unwrap())
+        })?;
+
+        assert_eq!(buffer.iter().count(), 32);
+
+        // The buffer is full, the reclaimed block is removed from the index.
+        let _arr = buffer.alloc_array_uninit::<u8>(1024)?;
+        let indexed = (0..4u64).flat_map(|thread| (0..8u64).map(move |i| thread * 100 + i)).filter(|key| buffer.get(*key).is_some()).count();
+        assert_eq!(indexed, 31);
+
+        // Blocks are split and coalesced while another thread walks over them.
+        std::thread::scope(|scope| {
+            scope.spawn(|| (0..100).for_each(|_| assert!(buffer.iter().count() <= 64)));
+
+            scope.spawn(|| -> super::Result<()> {
+                for size in [256, 512, 1024].into_iter().cycle().take(100) {
+                    let mut arr = buffer.alloc_array_uninit::<u8>(size)?;
+                    arr.ack_upsertion();
+                    // The walking thread may pin the block in the meantime, it is then left to be reclaimed.
+                    let _ = buffer.release(arr);
+                }
+
+                Ok(())
+            }).join().unwrap()
+        })?;
+
+        Ok(())
+    }
 }
 
@@ -0,0 +1,196 @@
+//! Process-wide ceiling on the memory held by buffers, shared between them by demand.
+
+use std::sync::{Arc, Mutex, Weak, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+
+/// Ceiling on the memory held by the buffers registered to it.
+///
+/// Buffers grow while the ceiling allows it. Once it is reached, the budget sets the target of each buffer
+/// by the lookups it missed, buffers set above their target give back memory at their next allocation, or when trimmed.
+pub struct MemoryBudget {
+    limit: usize,
+    shares: Mutex<Vec<Weak<BudgetShare>>>
+}
+
+/// Memory held by a buffer, under a budget.
+pub struct BudgetShare {
+    budget: Arc<MemoryBudget>,
+    /// Bytes held by the regions of the buffer.
+    reserved: AtomicUsize,
+    /// Bytes the buffer may hold, unbounded until the ceiling is first reached.
+    target: AtomicUsize,
+    /// Lookups missed since the last rebalance.
+    demand: AtomicU64,
+    /// Raised when a rebalance sets the target below the reserved bytes, until the buffer is back within its target.
+    shrinking: AtomicBool,
+    /// Granularity of the capacity of the buffer, the target is never below a unit.
+    unit: usize
+}
+
+impl MemoryBudget {
+    /// limit: bytes the buffers may hold altogether
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self { limit, shares: Default::default() })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes held by the registered buffers.
+    pub fn used(&self) -> usize {
+        Self::live(&mut self.shares.lock().unwrap()).iter().map(|share| share.reserved()).sum()
+    }
+
+    /// Shares of the buffers still alive.
+    fn live(shares: &mut Vec<Weak<BudgetShare>>) -> Vec<Arc<BudgetShare>> {
+        shares.retain(|share| share.strong_count() > 0);
+        shares.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Register a buffer holding reserved bytes, unless it does not fit under the ceiling.
+    pub(crate) fn register(self: &Arc<Self>, reserved: usize, unit: usize) -> Option<Arc<BudgetShare>> {
+        let mut shares = self.shares.lock().unwrap();
+        let used: usize = Self::live(&mut shares).iter().map(|share| share.reserved()).sum();
+
+        if used + reserved > self.limit {
+            return None;
+        }
+
+        let share = Arc::new(BudgetShare {
+            budget: self.clone(),
+            reserved: AtomicUsize::new(reserved),
+            target: AtomicUsize::new(usize::MAX),
+            demand: AtomicU64::new(0),
+            shrinking: AtomicBool::new(false),
+            unit
+        });
+
+        shares.push(Arc::downgrade(&share));
+        Some(share)
+    }
+
+    /// Grant between min and max more bytes to the share, within the ceiling and its target.
+    /// Returns the granted bytes, if the ceiling is reached, nothing is granted and the targets are rebalanced.
+    fn request(&self, share: &BudgetShare, min: usize, max: usize) -> usize {
+        let mut shares = self.shares.lock().unwrap();
+        let live = Self::live(&mut shares);
+        let used: usize = live.iter().map(|share| share.reserved()).sum();
+        let available = self.limit.saturating_sub(used);
+
+        if available < min {
+            self.rebalance(&live);
+            return 0;
+        }
+
+        let headroom = share.target().saturating_sub(share.reserved());
+        let granted = max.min(available).min(headroom);
+
+        if granted < min {
+            return 0;
+        }
+
+        share.reserved.fetch_add(granted, Ordering::AcqRel);
+        return granted;
+    }
+
+    /// Share the ceiling between the buffers, by the lookups they missed since the last rebalance.
+    fn rebalance(&self, shares: &[Arc<BudgetShare>]) {
+        let demands: Vec<u64> = shares.iter().map(|share| share.demand.swap(0, Ordering::AcqRel) + 1).collect();
+        let total: u64 = demands.iter().sum();
+
+        for (share, demand) in shares.iter().zip(demands) {
+            let fair = (self.limit as u128 * demand as u128 / total as u128) as usize;
+            let target = (fair - fair % share.unit).max(share.unit);
+            share.target.store(target, Ordering::Release);
+
+            if target < share.reserved() {
+                share.shrinking.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+impl BudgetShare {
+    /// Bytes held by the buffer.
+    pub fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::Acquire)
+    }
+
+    /// Bytes the buffer may hold.
+    pub fn target(&self) -> usize {
+        self.target.load(Ordering::Acquire)
+    }
+
+    /// Bytes the buffer should give back.
+    pub fn excess(&self) -> usize {
+        self.reserved().saturating_sub(self.target())
+    }
+
+    pub(crate) fn set_reserved(&self, reserved: usize) {
+        self.reserved.store(reserved, Ordering::Release);
+    }
+
+    /// The buffer was set above its target, and should give back memory.
+    pub(crate) fn is_shrinking(&self) -> bool {
+        self.shrinking.load(Ordering::Acquire)
+    }
+
+    /// The buffer gave back what it could, it is asked again at the next rebalance setting it above its target.
+    pub(crate) fn end_shrinking(&self) {
+        self.shrinking.store(false, Ordering::Release);
+    }
+
+    pub(crate) fn record_miss(&self) {
+        self.demand.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Ask the budget for between min and max more bytes.
+    pub(crate) fn request(&self, min: usize, max: usize) -> usize {
+        self.budget.request(self, min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer::{size_class, Buffer, BufferBlock, Error};
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_memory_budget() -> crate::buffer::Result<()> {
+        let unit = BufferBlock::size_of(size_class(1024));
+        let budget = MemoryBudget::new(4 * unit);
+
+        let (hot, cold) = (Buffer::new_by_array::<u8>(1024, 2), Buffer::new_by_array::<u8>(1024, 2));
+        hot.join_budget(&budget)?;
+        cold.join_budget(&budget)?;
+        assert!(matches!(hot.join_budget(&budget), Err(Error::AlreadyBudgeted)));
+        assert!(Buffer::new_by_array::<u8>(1024, 1).join_budget(&budget).is_err());
+
+        // The hot buffer misses, and holds all of its arrays, the ceiling is reached.
+        (0..6).for_each(|key| assert!(hot.get(key).is_none()));
+        let mut arrays = vec![hot.alloc_array_uninit::<u8>(1024)?, hot.alloc_array_uninit::<u8>(1024)?];
+        assert!(matches!(hot.alloc_array_uninit::<u8>(1024), Err(Error::NotEnoughSpace)));
+
+        // The cold buffer gives back its memory, the hot one grows into it.
+        cold.trim()?;
+        assert_eq!(cold.capacity(), 0);
+        arrays.push(hot.alloc_array_uninit::<u8>(1024)?);
+        assert_eq!(hot.capacity(), 3);
+        assert_eq!(budget.used(), 3 * unit);
+
+        // The cold buffer grows back up to its target.
+        let array = cold.alloc_array_uninit::<u8>(1024)?;
+        assert!(matches!(cold.alloc_array_uninit::<u8>(1024), Err(Error::NotEnoughSpace)));
+        assert_eq!(budget.used(), 4 * unit);
+
+        drop(array);
+        drop(cold);
+        assert_eq!(budget.used(), 3 * unit);
+
+        // Resizing does not override the target set by the budget.
+        assert!(matches!(hot.resize(4), Err(Error::NotEnoughSpace)));
+        assert_eq!(hot.capacity(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! Policies selecting the block to reclaim when the buffer is full.
+//! Blocks are identified by their index in the buffer.
+//!
+//! Hits raise an access bit on the block, and are logged by the shard of the index they went through:
+//! the buffer reports them to the policy in batches, and before selecting a victim.
+//! The policies may read and clear the bits of the blocks they consider when selecting a victim.
+
+use std::collections::{HashMap, VecDeque};
+
+const NIL: usize = usize::MAX;
+
+pub trait EvictionPolicy: Send {
+    /// The block was allocated, or reallocated after being reclaimed.
+    fn insert(&mut self, block: usize);
+    /// The block was accessed.
+    fn touch(&mut self, block: usize);
+    /// Select a block to reclaim, among the evictable ones.
+    /// accessed: clears the access bit of the block, and returns whether it was raised.
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool, accessed: &mut dyn FnMut(usize) -> bool) -> Option<usize>;
+    /// The block, indexed by the key, was reclaimed.
+    fn evicted(&mut self, _block: usize, _key: u64) {}
+    /// The block was indexed by the key.
+    fn indexed(&mut self, _block: usize, _key: u64) {}
+}
+
+/// Doubly linked list over block indices, from the least to the most recently queued.
+#[derive(Default)]
+struct IndexList {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    queued: Vec<bool>,
+    head: usize,
+    tail: usize,
+    len: usize
+}
+
+impl IndexList {
+    fn new() -> Self {
+        Self { head: NIL, tail: NIL, ..Default::default() }
+    }
+
+    fn contains(&self, block: usize) -> bool {
+        self.queued.get(block).copied().unwrap_or(false)
+    }
+
+    fn push_back(&mut self, block: usize) {
+        if block >= self.queued.len() {
+            self.prev.resize(block + 1, NIL);
+            self.next.resize(block + 1, NIL);
+            self.queued.resize(block + 1, false);
+        }
+
+        self.remove(block);
+
+        self.prev[block] = self.tail;
+        self.next[block] = NIL;
+
+        if self.tail != NIL {
+            self.next[self.tail] = block;
+        } else {
+            self.head = block;
+        }
+
+        self.tail = block;
+        self.queued[block] = true;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, block: usize) {
+        if !self.contains(block) {
+            return;
+        }
+
+        let (prev, next) = (self.prev[block], self.next[block]);
+
+        if prev != NIL { self.next[prev] = next; } else { self.head = next; }
+        if next != NIL { self.prev[next] = prev; } else { self.tail = prev; }
+
+        self.queued[block] = false;
+        self.len -= 1;
+    }
+
+    /// First block from the least recently queued one which is evictable, and not spared.
+    /// The blocks passed over are queued again, so that the next searches do not go over them:
+    /// each block is examined once per pass over the list, which keeps the search O(1) amortised.
+    /// Two passes at most: a block is spared once.
+    fn find(&mut self, evictable: &mut dyn FnMut(usize) -> bool, spared: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        for _ in 0..2 * self.len {
+            let block = self.head;
+
+            if evictable(block) && !spared(block) {
+                return Some(block);
+            }
+
+            self.push_back(block);
+        }
+
+        return None;
+    }
+}
+
+/// Second chance: a hand sweeps over the blocks, and spares the ones accessed since its last pass.
+#[derive(Default)]
+pub struct Clock {
+    referenced: Vec<bool>,
+    hand: usize
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn insert(&mut self, block: usize) {
+        if block >= self.referenced.len() {
+            self.referenced.resize(block + 1, false);
+        }
+
+        self.referenced[block] = true;
+    }
+
+    fn touch(&mut self, block: usize) {
+        if let Some(referenced) = self.referenced.get_mut(block) {
+            *referenced = true;
+        }
+    }
+
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool, accessed: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let len = self.referenced.len();
+
+        // Two passes: the first one may only clear the reference bits.
+        for _ in 0..2 * len {
+            let block = self.hand;
+            self.hand = (self.hand + 1) % len;
+
+            if !evictable(block) {
+                continue;
+            }
+
+            let accessed = accessed(block);
+
+            if self.referenced[block] || accessed {
+                self.referenced[block] = false;
+            } else {
+                return Some(block);
+            }
+        }
+
+        return None;
+    }
+}
+
+/// Least recently used, the hits move the blocks to the most recent end.
+/// The access bits are not considered, the hits are all reported before a victim is selected.
+pub struct Lru(IndexList);
+
+impl Lru {
+    pub fn new() -> Self {
+        Self(IndexList::new())
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn insert(&mut self, block: usize) {
+        self.0.push_back(block);
+    }
+
+    /// The hits are reported after the fact, the block may have been released since.
+    fn touch(&mut self, block: usize) {
+        if self.0.contains(block) {
+            self.0.push_back(block);
+        }
+    }
+
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool, _accessed: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        self.0.find(evictable, &mut |_| false)
+    }
+}
+
+/// Scan resistant 2Q: blocks enter a FIFO (A1in), and are remembered by key once evicted from it (A1out).
+/// A key faulted in again while remembered goes to the blocks reused over time (Am), managed as an LRU.
+pub struct TwoQueue {
+    /// A1in: blocks faulted in once, first in first out.
+    probation: IndexList,
+    /// Am: blocks faulted in again shortly after being evicted, least recently used first.
+    protected: IndexList,
+    /// A1out: keys recently evicted from A1in, with their rank to discard the outdated entries of the queue.
+    ghosts: VecDeque<(u64, u64)>,
+    ghost_ranks: HashMap<u64, u64>,
+    rank: u64,
+    /// Share of the blocks kept in A1in, in percent.
+    probation_ratio: usize,
+    /// Number of keys remembered in A1out, in percent of the blocks.
+    ghost_ratio: usize
+}
+
+impl TwoQueue {
+    pub fn new() -> Self {
+        Self::with_probation_ratio(25)
+    }
+
+    pub fn with_probation_ratio(probation_ratio: usize) -> Self {
+        Self {
+            probation: IndexList::new(),
+            protected: IndexList::new(),
+            ghosts: VecDeque::new(),
+            ghost_ranks: HashMap::new(),
+            rank: 0,
+            probation_ratio,
+            ghost_ratio: 50
+        }
+    }
+
+    fn remember(&mut self, key: u64) {
+        self.rank += 1;
+        self.ghosts.push_back((key, self.rank));
+        self.ghost_ranks.insert(key, self.rank);
+
+        let capacity = ((self.probation.len + self.protected.len) * self.ghost_ratio / 100).max(1);
+
+        while self.ghost_ranks.len() > capacity {
+            if let Some((key, rank)) = self.ghosts.pop_front() {
+                if self.ghost_ranks.get(&key) == Some(&rank) {
+                    self.ghost_ranks.remove(&key);
+                }
+            }
+        }
+
+        // The keys faulted in again, or remembered again since, leave outdated entries: drop them once they outnumber the remembered keys.
+        if self.ghosts.len() > 2 * capacity {
+            let ranks = &self.ghost_ranks;
+            self.ghosts.retain(|(key, rank)| ranks.get(key) == Some(rank));
+        }
+    }
+}
+
+impl EvictionPolicy for TwoQueue {
+    fn insert(&mut self, block: usize) {
+        self.protected.remove(block);
+        self.probation.push_back(block);
+    }
+
+    /// Accesses to the blocks of A1in are ignored, as they are correlated to their first access.
+    fn touch(&mut self, block: usize) {
+        if self.protected.contains(block) {
+            self.protected.push_back(block);
+        }
+    }
+
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool, accessed: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let total = self.probation.len + self.protected.len;
+        let threshold = (total * self.probation_ratio / 100).max(1);
+
+        // A1in is a FIFO, the access bits of its blocks are not considered.
+        if self.probation.len >= threshold {
+            self.probation.find(evictable, &mut |_| false).or_else(|| self.protected.find(evictable, accessed))
+        } else {
+            self.protected.find(evictable, accessed).or_else(|| self.probation.find(evictable, &mut |_| false))
+        }
+    }
+
+    fn evicted(&mut self, block: usize, key: u64) {
+        if self.probation.contains(block) {
+            self.remember(key);
+        }
+    }
+
+    fn indexed(&mut self, block: usize, key: u64) {
+        if self.ghost_ranks.remove(&key).is_some() {
+            self.probation.remove(block);
+            self.protected.push_back(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, EvictionPolicy, Lru, TwoQueue};
+
+    fn fill(policy: &mut dyn EvictionPolicy, blocks: usize) {
+        (0..blocks).for_each(|block| policy.insert(block));
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let mut clock = Clock::new();
+        fill(&mut clock, 3);
+        assert_eq!(clock.victim(&mut |_| true, &mut |_| false), Some(0));
+        clock.touch(1);
+        assert_eq!(clock.victim(&mut |_| true, &mut |_| false), Some(2));
+        // The access bit spares the block as well.
+        assert_eq!(clock.victim(&mut |_| true, &mut |block| block == 0), Some(1));
+
+        let mut lru = Lru::new();
+        fill(&mut lru, 3);
+        lru.touch(0);
+        assert_eq!(lru.victim(&mut |_| true, &mut |_| false), Some(1));
+        lru.insert(1);
+        assert_eq!(lru.victim(&mut |block| block != 2, &mut |_| false), Some(0));
+        lru.insert(0);
+        // The access bits are ignored, the hits reach the policy as touches.
+        assert_eq!(lru.victim(&mut |_| true, &mut |block| block == 1), Some(1));
+
+        // A scan over blocks 2..8 does not evict the block faulted in again after its eviction.
+        let mut two_queue = TwoQueue::new();
+        fill(&mut two_queue, 8);
+        assert_eq!(two_queue.victim(&mut |_| true, &mut |_| false), Some(0));
+        two_queue.evicted(0, 100);
+        two_queue.insert(0);
+        two_queue.indexed(0, 100);
+
+        for block in 1..8 {
+            assert_eq!(two_queue.victim(&mut |_| true, &mut |_| false), Some(block));
+            two_queue.insert(block);
+        }
+
+        assert_eq!(two_queue.victim(&mut |block| block == 0, &mut |_| false), Some(0));
+
+        // The keys faulted in again right after their eviction do not pile up in A1out.
+        for key in 200..1200 {
+            two_queue.insert(1);
+            two_queue.evicted(1, key);
+            two_queue.indexed(1, key);
+        }
+
+        assert!(two_queue.ghosts.len() <= 2 * 8);
+    }
+}
@@ -22,3 +22,8 @@ pub fn random_u64(min: u64, max: u64) -> u64 {
     rng.gen_range(min..max)
 }
 
+
+/// Path to a unique file in the temporary directory.
+pub fn temp_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("brouas-{}", hex::encode(&*random_data(8))))
+}
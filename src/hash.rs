@@ -74,4 +74,90 @@ impl self::traits::Hashable for Sha256 {
     fn hash<H: traits::Hasher>(&self, hasher: &mut H) {
         hasher.update(&self.data);
     }
-}
\ No newline at end of file
+}
+
+/// Lookup table of the CRC-32 (IEEE 802.3) polynomial.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// Fast, non-cryptographic, hasher used to detect corrupted data.
+pub struct Crc32Hasher {
+    crc: u32
+}
+
+impl Crc32Hasher {
+    pub fn new() -> Self {
+        Self {
+            crc: 0xFFFF_FFFF
+        }
+    }
+}
+
+impl self::traits::Hasher for Crc32Hasher
+{
+    type Hash = Crc32;
+
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        for byte in data.as_ref() {
+            self.crc = CRC32_TABLE[((self.crc ^ *byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finalize(self) -> Self::Hash {
+        Crc32(!self.crc)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Crc32(u32);
+
+impl From<Crc32> for u32 {
+    fn from(value: Crc32) -> Self {
+        value.0
+    }
+}
+
+impl std::fmt::Display for Crc32 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl self::traits::Hash for Crc32 {
+    type Hasher = Crc32Hasher;
+
+    fn new_hasher() -> Self::Hasher {
+        Crc32Hasher::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traits::Hasher, Crc32Hasher};
+
+    #[test]
+    fn test_crc32() {
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(b"123456789");
+        assert_eq!(u32::from(hasher.finalize()), 0xCBF4_3926);
+    }
+}
@@ -250,11 +250,11 @@ impl<'a> OutStream for DataRef<'a>
     type Output = Self;
 
     fn write_to_stream<W: std::io::Write + ?Sized>(output: &Self::Output, writer: &mut W) -> std::io::Result<usize> {
-        writer.write(&output.0)
+        writer.write(output.0)
     }
 
     fn write_all_to_stream<W: Write + ?Sized>(output: &Self::Output, writer: &mut W) -> std::io::Result<()> {
-        writer.write_all(&output.0)       
+        writer.write_all(output.0)       
     }
 }
 
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return, clippy::from_over_into, clippy::new_without_default, clippy::missing_safety_doc)]
+
 //pub mod bptree;
 pub mod hash;
 pub mod buffer;
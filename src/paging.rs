@@ -1,4 +1,18 @@
 pub mod page;
+pub mod layout;
 pub mod pager;
+pub mod storage;
+pub mod superblock;
+pub mod wal;
+pub mod transaction;
+pub mod mvcc;
+pub mod mmap;
+pub mod stats;
+pub mod allocator;
+pub mod vacuum;
+pub mod links;
+pub mod fsck;
+pub mod inspect;
+pub mod faults;
 pub mod error;
 pub mod result;
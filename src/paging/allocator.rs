@@ -1,142 +1,317 @@
-use crate::{pager::traits::Pager, io::traits::{InStream, OutStream}};
+use std::{cell::RefCell, collections::BTreeMap};
 
-use super::{page::{id::PageId, offset::PageOffset, result::PageResult, page_type::PageType}};
+use super::{page::PAGE_HEADER_SIZE, result::Result};
 
-#[derive(Default)]
-pub struct FreeHeader 
-{
-    next: Option<PageId>
+use self::traits::{PageAccess, PageAllocator};
+
+pub mod traits {
+    use crate::paging::result::Result;
+
+    /// Access to the raw content of the pages, and to the state of the pager the allocators rely on.
+    pub trait PageAccess {
+        /// Read the whole content of the page.
+        fn read_page(&self, pid: u64, read: &mut dyn FnMut(&[u8])) -> Result<()>;
+        /// Modify the whole content of the page.
+        fn write_page(&self, pid: u64, write: &mut dyn FnMut(&mut [u8])) -> Result<()>;
+        /// Head of the free pages list, persisted in the superblock.
+        fn read_freelist_head(&self) -> Option<u64>;
+        fn write_freelist_head(&self, head: Option<u64>);
+        /// Id of the last created page.
+        fn last_page_id(&self) -> u64;
+    }
+
+    /// Selects the dropped pages to reuse.
+    pub trait PageAllocator {
+        /// Take a free page out of the free pages, if any.
+        fn alloc(&self, pages: &dyn PageAccess) -> Result<Option<u64>>;
+        /// Take a run of count contiguous free pages, and returns the first one, if any.
+        fn alloc_run(&self, pages: &dyn PageAccess, count: usize) -> Result<Option<u64>> {
+            if count == 1 {
+                self.alloc(pages)
+            } else {
+                Ok(None)
+            }
+        }
+        /// Keep track of the dropped page, its content can be overwritten.
+        fn free(&self, pages: &dyn PageAccess, pid: u64) -> Result<()>;
+        /// The pages were restored by a rollback, forget any state derived from them.
+        fn invalidate(&self) {}
+        /// Free pages, from the free pages list.
+        fn free_pages(&self, pages: &dyn PageAccess) -> Result<Vec<u64>> {
+            let mut free = std::collections::BTreeSet::new();
+            let mut current = pages.read_freelist_head();
+
+            // A page linked twice would loop forever.
+            while let Some(pid) = current.filter(|pid| !free.contains(pid)) {
+                free.insert(pid);
+                pages.read_page(pid, &mut |content| current = super::read_next(content))?;
+            }
+
+            Ok(free.into_iter().collect())
+        }
+        /// Replace the free pages, the lowest ones are handed out first.
+        fn rebuild(&self, pages: &dyn PageAccess, free: &[u64]) -> Result<()> {
+            self.invalidate();
+            pages.write_freelist_head(None);
+
+            let mut free = free.to_vec();
+            free.sort_unstable_by(|a, b| b.cmp(a));
+            free.into_iter().try_for_each(|pid| self.free(pages, pid))
+        }
+    }
+}
+
+/// No next page, the superblock is never free.
+const NO_NEXT: u64 = 0;
+
+/// The next free page is stored at the beginning of the body of a free page.
+pub(crate) fn read_next(content: &[u8]) -> Option<u64> {
+    match u64::from_le_bytes(content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].try_into().unwrap()) {
+        NO_NEXT => None,
+        next => Some(next)
+    }
 }
 
-impl InStream for FreeHeader {
-    fn read_from_stream<R: std::io::Read>(&mut self, read: &mut R) -> std::io::Result<()> {
-        self.next.read_from_stream(read)
+fn write_next(content: &mut [u8], next: Option<u64>) {
+    content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].copy_from_slice(&next.unwrap_or(NO_NEXT).to_le_bytes());
+}
+
+/// Free pages are stacked in a list, its head is kept in the superblock.
+pub struct FreeList;
+
+impl FreeList {
+    pub fn new() -> Self {
+        Self
     }
 }
 
-impl OutStream for FreeHeader {
-    fn write_to_stream<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<usize> {
-        self.next.write_to_stream(writer)
+impl PageAllocator for FreeList {
+    fn alloc(&self, pages: &dyn PageAccess) -> Result<Option<u64>> {
+        let head = match pages.read_freelist_head() {
+            Some(head) => head,
+            None => return Ok(None)
+        };
+
+        let mut next = None;
+        pages.read_page(head, &mut |content| next = read_next(content))?;
+        pages.write_freelist_head(next);
+        Ok(Some(head))
     }
 
-    fn write_all_to_stream<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        self.next.write_all_to_stream(writer)
+    fn free(&self, pages: &dyn PageAccess, pid: u64) -> Result<()> {
+        let head = pages.read_freelist_head();
+        pages.write_page(pid, &mut |content| write_next(content, head))?;
+        pages.write_freelist_head(Some(pid));
+        Ok(())
     }
 }
 
-pub struct FreePage {
-    page_id: PageId,
-    base: PageOffset,
-    header: FreeHeader
+/// Free pages are tracked in a bitmap, to hand out runs of contiguous pages.
+///
+/// The bitmap is loaded from the free pages list when first used. The list is kept in sync, the pages handed out
+/// are unlinked from it, so that the free pages stay known if the database is reopened with another allocator.
+pub struct BitmapAllocator {
+    free: RefCell<Option<FreePages>>
 }
 
-impl FreePage 
-{    
-    pub fn new<P: Pager>(pager: &mut P, page_id: PageId) -> PageResult<Self> 
-    {
-        unsafe {
-            pager.change_page_type(&page_id, PageType::Free)?;
+impl BitmapAllocator {
+    pub fn new() -> Self {
+        Self { free: RefCell::new(None) }
+    }
+
+    /// Run the function over the free pages, loading them if needed.
+    fn with_free_pages<R>(&self, pages: &dyn PageAccess, f: impl FnOnce(&mut FreePages) -> Result<R>) -> Result<R> {
+        let mut free = self.free.borrow_mut();
+
+        if free.is_none() {
+            *free = Some(FreePages::load(pages)?);
         }
 
-        let base = 0u32.into();
-        let header = FreeHeader::default();
+        f(free.as_mut().unwrap())
+    }
+}
 
-        Ok(Self {
-            page_id: page_id,
-            base:    base,
-            header:  header
-        })
+impl PageAllocator for BitmapAllocator {
+    fn alloc(&self, pages: &dyn PageAccess) -> Result<Option<u64>> {
+        self.alloc_run(pages, 1)
     }
 
-    pub fn get<P: Pager>(page_id: &PageId, pager: &mut P) -> PageResult<Self> {
-        pager.open_page(page_id)?;
-        pager.assert_page_type(page_id, &PageType::Free)?;
-        
-        let base = 0u64.into();
-        let header = pager.read_and_instantiate_from_page::<FreeHeader, _>(page_id, base)?;
-        
-        Ok(Self {
-            page_id: *page_id,
-            base:     base,
-            header:   header
+    fn alloc_run(&self, pages: &dyn PageAccess, count: usize) -> Result<Option<u64>> {
+        self.with_free_pages(pages, |free| {
+            let first = match free.bitmap.find_run(count) {
+                Some(first) => first,
+                None => return Ok(None)
+            };
+
+            (first..first + count as u64).try_for_each(|pid| free.unlink(pages, pid))?;
+            Ok(Some(first))
         })
     }
 
-    pub fn flush<P: Pager>(&mut self, pager: &mut P) -> PageResult<()> {
-        pager.write_all_to_page(&self.page_id, &self.header, self.base)
+    fn free(&self, pages: &dyn PageAccess, pid: u64) -> Result<()> {
+        self.with_free_pages(pages, |free| free.push(pages, pid))
     }
- 
-    pub fn set_next(&mut self, next: Option<PageId>) {
-        self.header.next = next;
+
+    fn invalidate(&self) {
+        self.free.replace(None);
     }
 
-    pub fn get_next(&self) -> Option<PageId> {
-        self.header.next
+    fn free_pages(&self, pages: &dyn PageAccess) -> Result<Vec<u64>> {
+        self.with_free_pages(pages, |free| Ok(free.links.keys().copied().collect()))
     }
 }
 
-pub struct Allocator;
+/// Free pages, as a bitmap, and as the links of the persisted free pages list.
+#[derive(Default)]
+struct FreePages {
+    bitmap: Bitmap,
+    /// Previous and next pages in the list, by free page.
+    links: BTreeMap<u64, (Option<u64>, Option<u64>)>
+}
+
+impl FreePages {
+    /// Walk the free pages list.
+    fn load(pages: &dyn PageAccess) -> Result<Self> {
+        let mut free = Self::default();
+        let (mut prev, mut current) = (None, pages.read_freelist_head());
+
+        // A page linked twice would loop forever.
+        while let Some(pid) = current.filter(|pid| !free.links.contains_key(pid)) {
+            let mut next = None;
+            pages.read_page(pid, &mut |content| next = read_next(content))?;
+            free.bitmap.set(pid, true);
+            free.links.insert(pid, (prev, next));
+            (prev, current) = (Some(pid), next);
+        }
+
+        Ok(free)
+    }
+
+    /// Stack the page on top of the list.
+    fn push(&mut self, pages: &dyn PageAccess, pid: u64) -> Result<()> {
+        let head = pages.read_freelist_head();
+        pages.write_page(pid, &mut |content| write_next(content, head))?;
+        pages.write_freelist_head(Some(pid));
+
+        if let Some(head) = head.and_then(|head| self.links.get_mut(&head)) {
+            head.0 = Some(pid);
+        }
 
-impl Allocator
-{   
-    /// Allocate a new page, if there are any free pages available.
-    /// This methods opens the page, if any.
-    pub fn alloc<P: Pager>(pager: &mut P) -> PageResult<Option<PageId>> 
-    {
-        Self::pop(pager)
+        self.links.insert(pid, (None, head));
+        self.bitmap.set(pid, true);
+        Ok(())
     }
 
-    /// Free the page, and returns the new head of the free stack list.
-    /// This methods does not open the page, nor does it close it or flush it.
-    pub fn free<P: Pager>(pager: &mut P, page_id: &PageId) -> PageResult<()> 
-    {
-        // Create a free page from an existing one.
-        let mut pg = FreePage::new(pager, *page_id)?;
-        
-        // Save the new state
-        pg.flush(pager)?;
+    /// Take the page out of the list, its previous page is linked to its next one.
+    fn unlink(&mut self, pages: &dyn PageAccess, pid: u64) -> Result<()> {
+        let (prev, next) = self.links.remove(&pid).unwrap_or_default();
+
+        match prev {
+            None => pages.write_freelist_head(next),
+            Some(prev) => {
+                pages.write_page(prev, &mut |content| write_next(content, next))?;
+                self.links.entry(prev).and_modify(|links| links.1 = next);
+            }
+        }
 
-        // Push it on top of the stack
-        Self::push(pager, page_id)?;
+        if let Some(next) = next {
+            self.links.entry(next).and_modify(|links| links.0 = prev);
+        }
 
+        self.bitmap.set(pid, false);
         Ok(())
     }
-    
-    /// Pop a free page, and opens it.
-    fn pop<P: Pager>(pager: &mut P) -> PageResult<Option<PageId>> 
-    {
-        if let Some(page_id) = pager.get_freelist_head() {
-            pager.open_page(&page_id)?;
-            let head = FreePage::get(&page_id, pager)?;
-            pager.set_freelist_head(head.get_next());
-            Ok(Some(page_id))
+}
+
+/// One bit per page, raised for the free ones.
+#[derive(Default)]
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn get(&self, pid: u64) -> bool {
+        self.0.get((pid / 64) as usize).map(|word| word & (1 << (pid % 64)) != 0).unwrap_or(false)
+    }
+
+    fn set(&mut self, pid: u64, free: bool) {
+        let word = (pid / 64) as usize;
+
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+
+        if free {
+            self.0[word] |= 1 << (pid % 64);
         } else {
-            Ok(None)
+            self.0[word] &= !(1 << (pid % 64));
         }
     }
 
-    fn push<P: Pager>(pager: &mut P, page_id: &PageId) -> PageResult<()> 
-    {
-        match Self::get_free_head(pager)? {
-            Some(mut pg) => {
-                pg.set_next(Some(*page_id));
-                pg.flush(pager)?;
+    /// First run of count free pages.
+    fn find_run(&self, count: usize) -> Option<u64> {
+        let mut run = 0;
+
+        for pid in 0..(self.0.len() * 64) as u64 {
+            if self.get(pid) {
+                run += 1;
+
+                if run == count {
+                    return Some(pid + 1 - count as u64);
+                }
+            } else {
+                run = 0;
             }
-            None => {}
         }
-        
-        pager.set_freelist_head(Some(*page_id));
-        
-        Ok(())
-    }
 
-    fn get_free_head<P: Pager>(pager: &mut P) -> PageResult<Option<FreePage>> 
-    {
-        pager
-        .get_freelist_head()
-        .map(|head| FreePage::get(&head, pager))
-        .transpose()
+        return None;
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{io::InMemory, paging::{pager::{traits::Pager, BufPager, PageId}, storage::PagerStream, error::Error, result::Result}};
+    use super::BitmapAllocator;
+
+    #[test]
+    fn test_free_list() -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 10);
+        let pids = (0..3).map(|_| pager.new_page(0x10)).collect::<Result<Vec<_>>>()?;
+        pager.drop_page(&pids[0])?;
+        pager.drop_page(&pids[1])?;
+        pager.flush()?;
+
+        // Dropping a free page would link it to itself.
+        [0, pids[0], pids[2] + 1].iter().for_each(|pid| assert!(matches!(pager.drop_page(pid), Err(Error::NotDroppable { .. }))));
+
+        // The free pages list is persisted.
+        let pager: BufPager<PageId, u8, _> = BufPager::open(pager.into_storage(), 10)?;
+        assert_eq!(pager.new_page(0x10)?, pids[1]);
+        assert_eq!(pager.new_page(0x10)?, pids[0]);
+        assert_eq!(pager.new_page(0x10)?, pids[2] + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitmap_allocator() -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 10);
+        assert!(matches!(pager.new_pages(0x10, 0), Err(Error::EmptyRun)));
+        let first = pager.new_pages(0x10, 6)?;
+        assert_eq!(first, 1);
+
+        [1, 3, 4, 5].iter().try_for_each(|pid| pager.drop_page(pid))?;
+        pager.flush()?;
+
+        // The free pages are loaded from the free pages list.
+        let pager: BufPager<PageId, u8, _> = BufPager::open(pager.into_storage(), 10)?.with_allocator(BitmapAllocator::new());
+        assert_eq!(pager.new_pages(0x10, 3)?, 3);
+        assert_eq!(pager.new_pages(0x10, 2)?, 7);
+        pager.flush()?;
 
+        // The list is kept in sync, the remaining free page is still known to the free pages list allocator.
+        let pager: BufPager<PageId, u8, _> = BufPager::open(pager.into_storage(), 10)?;
+        assert_eq!(pager.new_page(0x10)?, 1);
+        assert_eq!(pager.new_page(0x10)?, 9);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,39 @@
-
 #[derive(Debug)]
 pub enum Error {
     BufferError(crate::buffer::Error),
-    IoError(std::io::Error)
+    IoError(std::io::Error),
+    /// The storage does not start with a superblock.
+    InvalidMagic,
+    /// The database was written with another version of the format.
+    IncompatibleVersion { expected: u16, got: u16 },
+    /// The superblock content cannot be decoded.
+    CorruptedSuperblock,
+    /// The superblock has no room left for named roots.
+    SuperblockFull,
+    /// Page sizes must be a power of two between MIN_PAGE_SIZE and MAX_PAGE_SIZE.
+    InvalidPageSize(u64),
+    /// The page content does not match its checksum.
+    ChecksumMismatch { pid: u64, expected: u32, actual: u32 },
+    /// A transaction is already pending on the pager.
+    TransactionInProgress,
+    /// No transaction is pending on the pager.
+    NoTransaction,
+    /// Snapshots are opened on the pager.
+    SnapshotPinned,
+    /// The committed content of the page was written over before the snapshot was opened.
+    SnapshotUnavailable { pid: u64 },
+    /// The page modified within the transaction cannot be written back before the commit, as the storage has no log.
+    UncommittedWriteBack { pid: u64 },
+    /// The page was stored by another writer since it was read, at the found version.
+    Conflict { pid: u64, expected: u64, found: u64 },
+    /// The superblock was stored by another writer since it was read.
+    SuperblockConflict,
+    /// The page is the superblock, is already free, or does not exist.
+    NotDroppable { pid: u64 },
+    /// Runs of pages hold at least one page.
+    EmptyRun,
+    /// The database file is locked by another handle, held by the process if known.
+    Locked { pid: Option<u32> }
 }
 
 impl Into<std::io::Error> for Error {
@@ -10,13 +41,33 @@ impl Into<std::io::Error> for Error {
         match self {
             Error::BufferError(err) => std::io::Error::new(std::io::ErrorKind::OutOfMemory, format!("memory buffer error: {:?}", err)),
             Error::IoError(err) => err,
+            Error::InvalidMagic => std::io::Error::new(std::io::ErrorKind::InvalidData, "not a brouas database"),
+            Error::IncompatibleVersion { expected, got } => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("incompatible format version: expected {}, got {}", expected, got)),
+            Error::CorruptedSuperblock => std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupted superblock"),
+            Error::SuperblockFull => std::io::Error::new(std::io::ErrorKind::OutOfMemory, "no room left in the superblock"),
+            Error::ChecksumMismatch { pid, expected, actual } => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("corrupted page {}: expected checksum {:08x}, got {:08x}", pid, expected, actual)),
+            Error::TransactionInProgress => std::io::Error::new(std::io::ErrorKind::WouldBlock, "a transaction is already in progress"),
+            Error::NoTransaction => std::io::Error::new(std::io::ErrorKind::InvalidInput, "no transaction in progress"),
+            Error::SnapshotPinned => std::io::Error::new(std::io::ErrorKind::WouldBlock, "snapshots are still opened"),
+            Error::SnapshotUnavailable { pid } => std::io::Error::other(format!("the committed content of page {} was written over before the snapshot", pid)),
+            Error::UncommittedWriteBack { pid } => std::io::Error::new(std::io::ErrorKind::OutOfMemory, format!("page {} modified within the transaction does not fit in the buffer", pid)),
+            Error::Conflict { pid, expected, found } => std::io::Error::other(format!("page {} was modified by another writer: expected version {}, found {}", pid, expected, found)),
+            Error::NotDroppable { pid } => std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("page {} cannot be dropped", pid)),
+            Error::EmptyRun => std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot allocate an empty run of pages"),
+            Error::Locked { pid: Some(pid) } => std::io::Error::new(std::io::ErrorKind::WouldBlock, format!("the database is locked by the process {}", pid)),
+            Error::Locked { pid: None } => std::io::Error::new(std::io::ErrorKind::WouldBlock, "the database is locked"),
+            Error::SuperblockConflict => std::io::Error::other("the superblock was modified by another writer"),
+            Error::InvalidPageSize(size) => std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid page size: {}", size)),
         }
     }
 }
 
 impl From<crate::buffer::Error> for Error {
     fn from(err: crate::buffer::Error) -> Self {
-        Self::BufferError(err)
+        match err {
+            crate::buffer::Error::WriteBackFailed(err) => Self::IoError(err),
+            err => Self::BufferError(err)
+        }
     }
 }
 
@@ -24,4 +75,4 @@ impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Self::IoError(err)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,585 @@
+//! Storages failing on demand, to test the failure paths and the recovery from crashes.
+
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, rc::Rc};
+
+use super::{pager::traits::PageStorage, wal::traits::LogStorage, error::Error, result::Result};
+
+/// Fault injected into a write (store, append), a sync or a truncation.
+/// Faults altering the written bytes fail syncs and truncations, their offsets are bound to the written bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Nothing is written, and the write fails.
+    Fail,
+    /// Only the first bytes are written, and the write fails.
+    ShortWrite(usize),
+    /// The byte at the offset is flipped, and the write succeeds.
+    Corrupt(usize),
+    /// Only the bytes before the offset reach the device, then the power is lost.
+    Tear(usize),
+    /// The power is lost before the write, the writes not synced are discarded.
+    PowerLoss
+}
+
+/// Faults to inject, by operation, shared by the storages of a database.
+/// Once the power is lost, every operation fails until the storages are released.
+#[derive(Default)]
+pub struct FaultPlan {
+    ops: Cell<usize>,
+    faults: RefCell<BTreeMap<usize, Fault>>,
+    triggered: Cell<bool>,
+    powered_off: Cell<bool>
+}
+
+impl FaultPlan {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Self::default())
+    }
+
+    /// Inject the fault into the operation (0 for the first one).
+    pub fn inject(&self, op: usize, fault: Fault) {
+        self.faults.borrow_mut().insert(op, fault);
+    }
+
+    /// Number of operations so far.
+    pub fn ops(&self) -> usize {
+        self.ops.get()
+    }
+
+    /// A fault was injected.
+    pub fn triggered(&self) -> bool {
+        self.triggered.get()
+    }
+
+    pub fn is_powered_off(&self) -> bool {
+        self.powered_off.get()
+    }
+
+    pub fn power_off(&self) {
+        self.powered_off.set(true);
+    }
+
+    /// Count the operation, and returns the fault to inject into it.
+    fn next(&self) -> std::io::Result<Option<Fault>> {
+        self.check_power()?;
+
+        let op = self.ops.get();
+        self.ops.set(op + 1);
+
+        let fault = self.faults.borrow_mut().remove(&op);
+
+        match fault {
+            Some(Fault::Fail) => {
+                self.triggered.set(true);
+                Err(injected("write failure"))
+            },
+            Some(Fault::PowerLoss) => Err(self.lose_power()),
+            Some(_) => {
+                self.triggered.set(true);
+                Ok(fault)
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Count the operation, any fault fails it.
+    fn next_sync(&self) -> std::io::Result<()> {
+        match self.next()? {
+            Some(_) => Err(injected("sync failure")),
+            None => Ok(())
+        }
+    }
+
+    fn check_power(&self) -> std::io::Result<()> {
+        if self.powered_off.get() {
+            return Err(injected("power lost"));
+        }
+
+        Ok(())
+    }
+
+    fn lose_power(&self) -> std::io::Error {
+        self.triggered.set(true);
+        self.powered_off.set(true);
+        injected("power lost")
+    }
+}
+
+fn injected(fault: &str) -> std::io::Error {
+    std::io::Error::other(format!("injected fault: {}", fault))
+}
+
+/// Flip the byte at the offset, wrapped around the written bytes, if any.
+fn flip(written: &mut [u8], offset: usize) {
+    if let Some(byte) = offset.checked_rem(written.len()).and_then(|offset| written.get_mut(offset)) {
+        *byte ^= 0xFF;
+    }
+}
+
+/// Page storage keeping the stored pages aside until synced, and failing as planned.
+pub struct FaultyStorage<S> {
+    inner: S,
+    plan: Rc<FaultPlan>,
+    unsynced: RefCell<BTreeMap<u64, Vec<u8>>>
+}
+
+impl<S> FaultyStorage<S> where S: PageStorage, S::Error: Into<Error> {
+    pub fn new(inner: S, plan: Rc<FaultPlan>) -> Self {
+        Self { inner, plan, unsynced: Default::default() }
+    }
+
+    /// Release the storage, the unsynced pages are lost if the power was.
+    pub fn into_inner(self) -> Result<S> {
+        if !self.plan.is_powered_off() {
+            self.apply()?;
+        }
+
+        Ok(self.inner)
+    }
+
+    /// Current content of the page, zeroes if it was never stored.
+    fn current(&self, pid: u64, len: usize) -> Vec<u8> {
+        if let Some(page) = self.unsynced.borrow().get(&pid) {
+            return page.clone();
+        }
+
+        let mut page = vec![0u8; len];
+
+        if self.inner.fetch(pid, &mut page).is_err() {
+            page.fill(0);
+        }
+
+        page
+    }
+
+    fn apply(&self) -> Result<()> {
+        for (pid, page) in std::mem::take(&mut *self.unsynced.borrow_mut()) {
+            self.inner.store(pid, page).map_err(Into::<Error>::into)?;
+        }
+
+        self.inner.sync().map_err(Into::<Error>::into)
+    }
+}
+
+impl<S> PageStorage for FaultyStorage<S> where S: PageStorage, S::Error: Into<Error> {
+    type Error = Error;
+
+    fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error> {
+        let (pid, page) = (id.into(), page.as_ref());
+
+        match self.plan.next()? {
+            None => {
+                self.unsynced.borrow_mut().insert(pid, page.to_vec());
+            },
+            Some(Fault::ShortWrite(len)) => {
+                let mut written = self.current(pid, page.len());
+                written[..len.min(page.len())].copy_from_slice(&page[..len.min(page.len())]);
+                self.unsynced.borrow_mut().insert(pid, written);
+                return Err(injected("short write").into());
+            },
+            Some(Fault::Corrupt(offset)) => {
+                let mut written = page.to_vec();
+                flip(&mut written, offset);
+                self.unsynced.borrow_mut().insert(pid, written);
+            },
+            Some(Fault::Tear(offset)) => {
+                let mut written = self.current(pid, page.len());
+                written[..offset.min(page.len())].copy_from_slice(&page[..offset.min(page.len())]);
+                self.inner.store(pid, written).map_err(Into::<Error>::into)?;
+                return Err(self.plan.lose_power().into());
+            },
+            Some(Fault::Fail) | Some(Fault::PowerLoss) => unreachable!()
+        }
+
+        Ok(())
+    }
+
+    fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error> {
+        self.plan.check_power()?;
+        let (pid, data) = (id.into(), data.as_mut());
+
+        match self.unsynced.borrow().get(&pid) {
+            Some(page) => data.copy_from_slice(&page[..data.len()]),
+            None => self.inner.fetch(pid, &mut &mut *data).map_err(Into::<Error>::into)?
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.plan.next_sync()?;
+        self.apply()
+    }
+
+    fn is_logged(&self) -> bool {
+        self.inner.is_logged()
+    }
+
+    fn lock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.lock_commit().map_err(Into::<Error>::into)
+    }
+
+    fn unlock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.unlock_commit().map_err(Into::<Error>::into)
+    }
+
+    fn truncate(&self, len: u64) -> std::result::Result<(), Self::Error> {
+        self.plan.next_sync()?;
+        self.unsynced.borrow_mut().retain(|pid, page| (pid + 1) * page.len() as u64 <= len);
+        self.inner.truncate(len).map_err(Into::<Error>::into)
+    }
+}
+
+/// The workloads borrow the storage, to hand it back whatever happened.
+impl<S> PageStorage for &FaultyStorage<S> where S: PageStorage, S::Error: Into<Error> {
+    type Error = Error;
+
+    fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error> {
+        (*self).store(id, page)
+    }
+
+    fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error> {
+        (*self).fetch(id, data)
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        (*self).sync()
+    }
+
+    fn is_logged(&self) -> bool {
+        (*self).is_logged()
+    }
+
+    fn lock_commit(&self) -> std::result::Result<(), Self::Error> {
+        (*self).lock_commit()
+    }
+
+    fn unlock_commit(&self) -> std::result::Result<(), Self::Error> {
+        (*self).unlock_commit()
+    }
+
+    fn truncate(&self, len: u64) -> std::result::Result<(), Self::Error> {
+        (*self).truncate(len)
+    }
+}
+
+/// Log keeping the appended records aside until synced, and failing as planned.
+pub struct FaultyLog<L> {
+    inner: L,
+    plan: Rc<FaultPlan>,
+    unsynced: RefCell<Vec<u8>>
+}
+
+impl<L> FaultyLog<L> where L: LogStorage {
+    pub fn new(inner: L, plan: Rc<FaultPlan>) -> Self {
+        Self { inner, plan, unsynced: Default::default() }
+    }
+
+    /// Release the log, the unsynced records are lost if the power was.
+    pub fn into_inner(self) -> std::io::Result<L> {
+        if !self.plan.is_powered_off() {
+            self.apply()?;
+        }
+
+        Ok(self.inner)
+    }
+
+    fn apply(&self) -> std::io::Result<()> {
+        let unsynced = std::mem::take(&mut *self.unsynced.borrow_mut());
+
+        if !unsynced.is_empty() {
+            self.inner.append(&unsynced)?;
+        }
+
+        self.inner.sync()
+    }
+}
+
+impl<L> LogStorage for FaultyLog<L> where L: LogStorage {
+    fn append(&self, record: &[u8]) -> std::io::Result<()> {
+        match self.plan.next()? {
+            None => self.unsynced.borrow_mut().extend_from_slice(record),
+            Some(Fault::ShortWrite(len)) => {
+                self.unsynced.borrow_mut().extend_from_slice(&record[..len.min(record.len())]);
+                return Err(injected("short write"));
+            },
+            Some(Fault::Corrupt(offset)) => {
+                let mut written = record.to_vec();
+                flip(&mut written, offset);
+                self.unsynced.borrow_mut().extend_from_slice(&written);
+            },
+            // The log is written sequentially, the records appended before reached the device.
+            Some(Fault::Tear(offset)) => {
+                self.unsynced.borrow_mut().extend_from_slice(&record[..offset.min(record.len())]);
+                self.apply()?;
+                return Err(self.plan.lose_power());
+            },
+            Some(Fault::Fail) | Some(Fault::PowerLoss) => unreachable!()
+        }
+
+        Ok(())
+    }
+
+    fn read_all(&self) -> std::io::Result<Vec<u8>> {
+        self.plan.check_power()?;
+        let mut log = self.inner.read_all()?;
+        log.extend_from_slice(&self.unsynced.borrow());
+        Ok(log)
+    }
+
+    fn truncate(&self) -> std::io::Result<()> {
+        self.plan.next_sync()?;
+        self.unsynced.borrow_mut().clear();
+        self.inner.truncate()
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.plan.next_sync()?;
+        self.apply()
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.inner.size()? + self.unsynced.borrow().len() as u64)
+    }
+}
+
+impl<L> LogStorage for &FaultyLog<L> where L: LogStorage {
+    fn append(&self, record: &[u8]) -> std::io::Result<()> {
+        (*self).append(record)
+    }
+
+    fn read_all(&self) -> std::io::Result<Vec<u8>> {
+        (*self).read_all()
+    }
+
+    fn truncate(&self) -> std::io::Result<()> {
+        (*self).truncate()
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        (*self).sync()
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        (*self).size()
+    }
+}
+
+/// Run the workload once per operation it performs, with the fault injected into that operation,
+/// and check the storages after each run, once released.
+/// The check is told whether the workload completed, the runs stop once the workload completes without reaching the fault.
+/// Returns the number of runs.
+pub fn run_fault_points<S, L>(
+    fresh: impl Fn() -> (S, L),
+    fault: Fault,
+    workload: impl Fn(&FaultyStorage<S>, &FaultyLog<L>) -> Result<()>,
+    check: impl Fn(usize, S, L, bool) -> Result<()>
+) -> Result<usize>
+where S: PageStorage, S::Error: Into<Error>, L: LogStorage
+{
+    for op in 0.. {
+        let plan = FaultPlan::new();
+        plan.inject(op, fault);
+
+        let (store, log) = fresh();
+        let (store, log) = (FaultyStorage::new(store, plan.clone()), FaultyLog::new(log, plan.clone()));
+        let completed = workload(&store, &log).is_ok();
+        check(op, store.into_inner()?, log.into_inner()?, completed)?;
+
+        if !plan.triggered() {
+            return Ok(op + 1);
+        }
+    }
+
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use crate::{io::InMemory, paging::{allocator::traits::PageAccess, fsck::Fsck, layout::{cell_offset, read_u64, write_u64, NODE_TYPE, NODE_LEN, NODE_CAPACITY, BRANCH_NODE, LEAF_NODE, BRANCH_CHILD, BRANCH_KEY, LEAF_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, OV_SIZE, OV_NEXT}, page::{PageSectionType, BPTREE_BRANCH, BPTREE_LEAF, traits::WritePage}, pager::{traits::{Pager, PageStorage}, BufPager, PageId, OVERFLOW_PAGE}, storage::PagerStream, wal::{traits::LogStorage, MemoryLog, WalStorage}, error::Error, result::Result}, utils::slice::IntoSection};
+    use super::{run_fault_points, Fault, FaultPlan, FaultyLog, FaultyStorage};
+
+    type Store = PagerStream<InMemory>;
+
+    fn fill_pages<S>(pager: &BufPager<PageId, u8, S>, pids: &[PageId], value: u8) -> Result<()>
+    where S: crate::paging::pager::traits::PageStorage, S::Error: Into<crate::paging::error::Error>
+    {
+        pids.iter().try_for_each(|pid| {
+            pager.borrow_mut_page(pid)?.into_section(PageSectionType::Body).as_mut().fill(value);
+            Ok(())
+        })
+    }
+
+    /// Three pages written with 1, then 2 within a transaction, then 3 in a rolled back transaction.
+    fn workload(store: &FaultyStorage<Store>, log: &FaultyLog<MemoryLog>) -> Result<()> {
+        let mut wal = WalStorage::open(store, log)?;
+        wal.set_checkpoint_threshold(0);
+
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(wal, 4096, 10)?;
+        let pids = (0..3).map(|_| pager.new_page(0x10)).collect::<Result<Vec<_>>>()?;
+        fill_pages(&pager, &pids, 1)?;
+        pager.flush()?;
+
+        let transaction = pager.begin()?;
+        fill_pages(&pager, &pids, 2)?;
+        transaction.commit()?;
+
+        let transaction = pager.begin()?;
+        fill_pages(&pager, &pids, 3)?;
+        transaction.rollback()
+    }
+
+    fn check(op: usize, store: Store, log: MemoryLog, completed: bool) -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = match BufPager::open(WalStorage::open(store, log)?, 10) {
+            Ok(pager) => pager,
+            // Crashed before the first flush.
+            Err(_) if !completed => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        let values = (1..=pager.last_page_id())
+            .map(|pid| Ok(pager.borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0]))
+            .collect::<Result<Vec<_>>>()?;
+
+        assert!(values.is_empty() || values == [1, 1, 1] || values == [2, 2, 2], "operation {}: {:?}", op, values);
+        assert!(Fsck::new().ignore_orphans().check(&pager)?.is_clean(), "operation {}", op);
+
+        if completed {
+            assert_eq!(values, [2, 2, 2]);
+        }
+
+        Ok(())
+    }
+
+    /// Corrupted pages are detected by their checksums, corrupted log records are dropped with the tail of the log:
+    /// the last transactions may be lost, but the pages are consistent.
+    fn check_corrupt(op: usize, store: Store, log: MemoryLog, _completed: bool) -> Result<()> {
+        match check(op, store, log, false) {
+            Err(Error::ChecksumMismatch { .. }) => Ok(()),
+            result => result
+        }
+    }
+
+    /// Write the node, and the fields of its cells, from the first one.
+    fn write_node<S>(pager: &BufPager<PageId, u8, S>, pid: PageId, parent: PageId, node_type: u8, cells: &[&[(Range<usize>, u64)]]) -> Result<()>
+    where S: PageStorage, S::Error: Into<Error>
+    {
+        let mut page = pager.borrow_mut_page(&pid)?;
+        page.set_parent(parent);
+        let mut section = page.into_section(PageSectionType::Body);
+        let body = section.as_mut();
+        (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]) = (node_type, cells.len() as u8, 4);
+
+        for (index, fields) in cells.iter().enumerate() {
+            let offset = cell_offset(4096, 4, index);
+            fields.iter().for_each(|(field, value)| write_u64(body, offset + field.start..offset + field.end, *value));
+        }
+
+        Ok(())
+    }
+
+    /// Pages laid out as a B+tree of two leaves, the second one holding an element overflowing in three pages,
+    /// then a third leaf within a transaction. The nodes are written by hand from the on-disk layout:
+    /// the B+tree operations (insertion, split) are not covered, only the pager, the log and the built-in links.
+    fn node_layout_workload(store: &FaultyStorage<Store>, log: &FaultyLog<MemoryLog>) -> Result<()> {
+        let mut wal = WalStorage::open(store, log)?;
+        wal.set_checkpoint_threshold(0);
+
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(wal, 4096, 10)?;
+        let (branch, first, second) = (pager.new_page(BPTREE_BRANCH)?, pager.new_page(BPTREE_LEAF)?, pager.new_page(BPTREE_LEAF)?);
+        let chain = pager.new_pages(OVERFLOW_PAGE, 3)?;
+
+        write_node(&pager, first, branch, LEAF_NODE, &[&[(LEAF_KEY, 1), (LEAF_SIZE, 10), (LEAF_IN_PAGE_SIZE, 10)]])?;
+        write_node(&pager, second, branch, LEAF_NODE, &[&[(LEAF_KEY, 5), (LEAF_SIZE, 1000), (LEAF_IN_PAGE_SIZE, 100), (LEAF_OVERFLOW, chain)]])?;
+
+        for pid in chain..chain + 3 {
+            let mut page = pager.borrow_mut_page(&pid)?;
+            page.set_parent(if pid == chain { second } else { pid - 1 });
+            let mut section = page.into_section(PageSectionType::Body);
+            section.as_mut()[OV_SIZE].copy_from_slice(&300u16.to_le_bytes());
+            write_u64(section.as_mut(), OV_NEXT, if pid < chain + 2 { pid + 1 } else { 0 });
+        }
+
+        write_node(&pager, branch, 0, BRANCH_NODE, &[&[(BRANCH_CHILD, first), (BRANCH_KEY, 1)], &[(BRANCH_CHILD, second), (BRANCH_KEY, 5)]])?;
+        pager.set_root("tree", branch);
+        pager.flush()?;
+
+        let transaction = pager.begin()?;
+        let third = pager.new_page(BPTREE_LEAF)?;
+        write_node(&pager, third, branch, LEAF_NODE, &[&[(LEAF_KEY, 9), (LEAF_SIZE, 10), (LEAF_IN_PAGE_SIZE, 10)]])?;
+        write_node(&pager, branch, 0, BRANCH_NODE, &[&[(BRANCH_CHILD, first), (BRANCH_KEY, 1)], &[(BRANCH_CHILD, second), (BRANCH_KEY, 5)], &[(BRANCH_CHILD, third), (BRANCH_KEY, 9)]])?;
+        transaction.commit()
+    }
+
+    /// The nodes are walked through the built-in links, each page of the file is reachable from them.
+    fn check_node_layout(op: usize, store: Store, log: MemoryLog, completed: bool) -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = match BufPager::open(WalStorage::open(store, log)?, 10) {
+            Ok(pager) => pager,
+            Err(_) if !completed => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        let branch = match pager.get_root("tree") {
+            Some(branch) => branch,
+            None if !completed => return Ok(()),
+            None => panic!("operation {}: no tree", op)
+        };
+
+        let report = Fsck::new().with_builtin_links().check(&pager)?;
+        assert!(report.is_clean(), "operation {}: {:?}", op, report.problems);
+
+        let body = pager.borrow_page(&branch)?.into_section(PageSectionType::Body).as_ref().to_vec();
+        let leaves: Vec<u64> = (0..body[NODE_LEN] as usize).map(|index| read_u64(&body[cell_offset(4096, 4, index)..], BRANCH_CHILD)).collect();
+        assert!(leaves == [2, 3] || leaves == [2, 3, 7], "operation {}: {:?}", op, leaves);
+
+        if completed {
+            assert_eq!(leaves, [2, 3, 7]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crash_points() -> Result<()> {
+        let fresh = || (PagerStream::new(InMemory::new()), MemoryLog::new());
+
+        let runs = run_fault_points(fresh, Fault::PowerLoss, workload, check)?;
+        assert!(runs > 10);
+
+        // Torn pages and log records are detected by their checksums.
+        assert_eq!(run_fault_points(fresh, Fault::Tear(100), workload, check)?, runs);
+
+        // The failed writes are reported, and leave the storage recoverable.
+        assert_eq!(run_fault_points(fresh, Fault::Fail, workload, check)?, runs);
+        assert_eq!(run_fault_points(fresh, Fault::ShortWrite(100), workload, check)?, runs);
+
+        // The corrupted writes succeed.
+        assert_eq!(run_fault_points(fresh, Fault::Corrupt(100), workload, check_corrupt)?, runs);
+
+        // Node layout only, the B+tree code is not exercised.
+        let runs = run_fault_points(fresh, Fault::PowerLoss, node_layout_workload, check_node_layout)?;
+        assert!(runs > 10);
+
+        for fault in [Fault::Tear(100), Fault::Fail, Fault::ShortWrite(100)] {
+            assert_eq!(run_fault_points(fresh, fault, node_layout_workload, check_node_layout)?, runs);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_writes() -> Result<()> {
+        let plan = FaultPlan::new();
+        let (store, log) = (FaultyStorage::new(PagerStream::new(InMemory::new()), plan.clone()), FaultyLog::new(MemoryLog::new(), plan.clone()));
+        plan.inject(0, Fault::Corrupt(100));
+        plan.inject(1, Fault::Corrupt(100));
+
+        store.store(1u64, [])?;
+        log.append(&[])?;
+        assert!(plan.triggered());
+        assert_eq!(log.size()?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{page::{Page, PAGE_TYPE_OFFSET, traits::ReadPage}, pager::{BufPager, FREE_PAGE, traits::PageStorage}, superblock::SUPERBLOCK_PAGE, allocator::{read_next, traits::PageAccess}, vacuum::traits::PageLinks, links::builtin_links, error::Error, result::Result};
+
+/// Inconsistency found in the database, references from the superblock (named roots, free pages list) come from SUPERBLOCK_PAGE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The page content does not match its checksum, it is not walked.
+    ChecksumMismatch { pid: u64, expected: u32, actual: u32 },
+    /// The page references a page beyond the last one.
+    OutOfRange { pid: u64, referrer: u64 },
+    /// The page is referenced twice.
+    DoubleReferenced { pid: u64, first: u64, second: u64 },
+    /// The page references one of the pages leading to it.
+    Cycle { pid: u64, referrer: u64 },
+    /// A page of the free pages list is not free, a free page is referenced by a live page, or a page is referenced by a page not expecting its type.
+    TypeMismatch { pid: u64, found: u8 },
+    /// The parent id of the page is not the page referencing it.
+    ParentMismatch { pid: u64, parent: u64, expected: u64 },
+    /// The content of the page violates an invariant of its type.
+    Invalid { pid: u64, reason: String },
+    /// The live page is not reachable from the named roots.
+    Orphaned(u64),
+    /// The free page is not in the free pages list.
+    Leaked(u64)
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ChecksumMismatch { pid, expected, actual } => write!(f, "page {}: expected checksum {:08x}, got {:08x}", pid, expected, actual),
+            Self::OutOfRange { pid, referrer } => write!(f, "page {}: references page {} beyond the last page", referrer, pid),
+            Self::DoubleReferenced { pid, first, second } => write!(f, "page {}: referenced by pages {} and {}", pid, first, second),
+            Self::Cycle { pid, referrer } => write!(f, "page {}: referenced back by page {}", pid, referrer),
+            Self::TypeMismatch { pid, found } => write!(f, "page {}: unexpected type {:#04x}", pid, found),
+            Self::ParentMismatch { pid, parent, expected } => write!(f, "page {}: parent is {}, expected {}", pid, parent, expected),
+            Self::Invalid { pid, reason } => write!(f, "page {}: {}", pid, reason),
+            Self::Orphaned(pid) => write!(f, "page {}: unreachable", pid),
+            Self::Leaked(pid) => write!(f, "page {}: free, but not in the free pages list", pid)
+        }
+    }
+}
+
+/// Outcome of a check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Pages checked, the superblock excluded.
+    pub pages: u64,
+    pub problems: Vec<Problem>,
+    /// Free pages of the free pages list, and leaked ones, to rebuild the list from.
+    free: Vec<u64>
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Free pages missing from the free pages list.
+    pub fn leaks(&self) -> usize {
+        self.problems.iter().filter(|problem| matches!(problem, Problem::Leaked(_))).count()
+    }
+}
+
+/// Checks the consistency of a database: the free pages list, the pages reachable from the named roots, and the checksums.
+///
+/// Pages are walked through the links registered for their type (see Vacuum), pages of other types are checked, but not walked.
+pub struct Fsck {
+    links: BTreeMap<u8, Box<dyn PageLinks>>,
+    orphans: bool
+}
+
+/// State of the walk over the references.
+struct Walk {
+    last: u64,
+    /// Type of the pages, none if it could not be read.
+    types: BTreeMap<u64, Option<u8>>,
+    /// Page referencing each page reached so far.
+    referrers: BTreeMap<u64, u64>,
+    problems: Vec<Problem>
+}
+
+impl Walk {
+    /// Record the reference, returns whether the page should be walked.
+    /// path: pages leading to the referrer, included
+    fn reference(&mut self, referrer: u64, pid: u64, path: &BTreeSet<u64>) -> bool {
+        if pid == SUPERBLOCK_PAGE || pid > self.last {
+            self.problems.push(Problem::OutOfRange { pid, referrer });
+            return false;
+        }
+
+        if path.contains(&pid) {
+            self.problems.push(Problem::Cycle { pid, referrer });
+            return false;
+        }
+
+        if let Some(&first) = self.referrers.get(&pid) {
+            self.problems.push(Problem::DoubleReferenced { pid, first, second: referrer });
+            return false;
+        }
+
+        self.referrers.insert(pid, referrer);
+        self.types[&pid].is_some()
+    }
+}
+
+impl Fsck {
+    pub fn new() -> Self {
+        Self { links: Default::default(), orphans: true }
+    }
+
+    /// Register the links held by the pages of the type.
+    pub fn with_links(mut self, ptype: u8, links: impl PageLinks + 'static) -> Self {
+        self.links.insert(ptype, Box::new(links));
+        self
+    }
+
+    /// Register the links held by the overflow pages and the B+tree nodes.
+    pub fn with_builtin_links(mut self) -> Self {
+        self.links.extend(builtin_links());
+        self
+    }
+
+    /// Do not report unreachable live pages, when the links of some types are unknown.
+    pub fn ignore_orphans(mut self) -> Self {
+        self.orphans = false;
+        self
+    }
+
+    pub fn check<'buffer, Id, Type, Storage>(&self, pager: &BufPager<'buffer, Id, Type, Storage>) -> Result<FsckReport>
+    where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+    {
+        let last = pager.last_page_id();
+        let mut walk = Walk { last, types: Default::default(), referrers: Default::default(), problems: Default::default() };
+
+        for pid in 1..=last {
+            let mut ptype = FREE_PAGE;
+
+            match pager.read_page(pid, &mut |content| ptype = content[PAGE_TYPE_OFFSET]) {
+                Ok(()) => walk.types.insert(pid, Some(ptype)),
+                Err(Error::ChecksumMismatch { pid, expected, actual }) => {
+                    walk.problems.push(Problem::ChecksumMismatch { pid, expected, actual });
+                    walk.types.insert(pid, None)
+                },
+                Err(err) => return Err(err)
+            };
+        }
+
+        let mut free = self.check_free_list(pager, &mut walk)?;
+
+        for root in pager.get_roots().into_values() {
+            if walk.reference(SUPERBLOCK_PAGE, root.into(), &BTreeSet::new()) {
+                self.check_tree(pager, &mut walk, root.into())?;
+            }
+        }
+
+        for (&pid, &ptype) in walk.types.iter() {
+            match (ptype, walk.referrers.get(&pid)) {
+                (Some(FREE_PAGE), None) => {
+                    walk.problems.push(Problem::Leaked(pid));
+                    free.push(pid);
+                },
+                (Some(_), None) if self.orphans => walk.problems.push(Problem::Orphaned(pid)),
+                _ => {}
+            }
+        }
+
+        Ok(FsckReport { pages: last, problems: walk.problems, free })
+    }
+
+    /// Rebuild the free pages list, to repair the leaks found by the check, returns the number of repaired leaks.
+    pub fn repair<'buffer, Id, Type, Storage>(&self, pager: &BufPager<'buffer, Id, Type, Storage>, report: &FsckReport) -> Result<usize>
+    where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: From<u8> + Into<u8>
+    {
+        pager.rebuild_free_pages(&report.free)?;
+        super::pager::traits::Pager::flush(pager)?;
+        Ok(report.leaks())
+    }
+
+    /// Walk the free pages list, returns the free pages in it.
+    fn check_free_list(&self, pages: &dyn PageAccess, walk: &mut Walk) -> Result<Vec<u64>> {
+        let (mut list, mut path) = (Vec::new(), BTreeSet::new());
+        let mut next = pages.read_freelist_head();
+        let mut referrer = SUPERBLOCK_PAGE;
+
+        while let Some(pid) = next {
+            if !walk.reference(referrer, pid, &path) {
+                break;
+            }
+
+            if let Some(ptype) = walk.types[&pid].filter(|ptype| *ptype != FREE_PAGE) {
+                walk.problems.push(Problem::TypeMismatch { pid, found: ptype });
+                break;
+            }
+
+            pages.read_page(pid, &mut |content| next = read_next(content))?;
+            list.push(pid);
+            path.insert(pid);
+            referrer = pid;
+        }
+
+        Ok(list)
+    }
+
+    /// Walk the pages reachable from the root, depth first.
+    /// The pages on the path from the root are kept aside, with the children left to walk, to find the cycles.
+    fn check_tree(&self, pages: &dyn PageAccess, walk: &mut Walk, root: u64) -> Result<()> {
+        let mut stack: Vec<(u64, std::vec::IntoIter<u64>)> = Vec::new();
+        let mut path = BTreeSet::new();
+
+        if let Some(children) = self.check_page(pages, walk, root, SUPERBLOCK_PAGE)? {
+            stack.push((root, children.into_iter()));
+            path.insert(root);
+        }
+
+        while let Some((pid, children)) = stack.last_mut() {
+            let pid = *pid;
+
+            match children.next() {
+                None => {
+                    stack.pop();
+                    path.remove(&pid);
+                },
+                Some(child) if walk.reference(pid, child, &path) => {
+                    if let Some(children) = self.check_page(pages, walk, child, pid)? {
+                        stack.push((child, children.into_iter()));
+                        path.insert(child);
+                    }
+                },
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the page, returns the pages it references, if its links are known.
+    fn check_page(&self, pages: &dyn PageAccess, walk: &mut Walk, pid: u64, referrer: u64) -> Result<Option<Vec<u64>>> {
+        let ptype = walk.types[&pid].unwrap_or(FREE_PAGE);
+
+        if ptype == FREE_PAGE {
+            walk.problems.push(Problem::TypeMismatch { pid, found: ptype });
+            return Ok(None);
+        }
+
+        let mut content = Vec::new();
+        pages.read_page(pid, &mut |page| content = page.to_vec())?;
+
+        let parent = Page::<u64, u8, _>::from(&content[..]).get_parent();
+
+        if parent != referrer {
+            walk.problems.push(Problem::ParentMismatch { pid, parent, expected: referrer });
+        }
+
+        let links = match self.links.get(&ptype) {
+            Some(links) => links,
+            None => return Ok(None)
+        };
+
+        if let Err(reason) = links.check(&content) {
+            walk.problems.push(Problem::Invalid { pid, reason });
+        }
+
+        let children: BTreeSet<u64> = links.links(&content).into_iter().collect();
+
+        // The free pages are reported when walked.
+        for &child in children.iter() {
+            if let Some(Some(found)) = walk.types.get(&child).filter(|found| **found != Some(FREE_PAGE)) {
+                if !links.accepts(&content, *found) {
+                    walk.problems.push(Problem::TypeMismatch { pid: child, found: *found });
+                }
+            }
+        }
+
+        Ok(Some(children.into_iter().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fixtures, io::InMemory, paging::{layout::{cell_offset, write_u64, NODE_TYPE, NODE_LEN, NODE_CAPACITY, BRANCH_NODE, LEAF_NODE, BRANCH_CHILD, BRANCH_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, OV_SIZE, OV_NEXT}, page::{PAGE_HEADER_SIZE, BPTREE_BRANCH, BPTREE_LEAF, PageSectionType, traits::WritePage}, pager::{traits::{Pager, PageStorage}, BufPager, PageId, OVERFLOW_PAGE}, storage::{FileStorage, PagerStream}, superblock::SUPERBLOCK_PAGE, result::Result}, utils::slice::IntoSection};
+    use super::{Fsck, Problem, PageLinks};
+
+    const LIST: u8 = 0x20;
+
+    /// The page references its child at the beginning of its body.
+    struct ListLinks;
+
+    impl PageLinks for ListLinks {
+        fn links(&self, content: &[u8]) -> Vec<u64> {
+            match u64::from_le_bytes(content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].try_into().unwrap()) {
+                0 => vec![],
+                child => vec![child]
+            }
+        }
+
+        fn relink(&self, _content: &mut [u8], _from: u64, _to: u64) {}
+    }
+
+    fn link(pager: &BufPager<PageId, u8, FileStorage>, parent: PageId, child: PageId) -> Result<()> {
+        pager.borrow_mut_page(&parent)?.into_section(PageSectionType::Body).as_mut()[..8].copy_from_slice(&child.to_le_bytes());
+        pager.borrow_mut_page(&child)?.set_parent(parent);
+        Ok(())
+    }
+
+    fn write_body<Storage: PageStorage>(pager: &BufPager<PageId, u8, Storage>, pid: PageId, body: &[u8]) -> Result<()> where Storage::Error: Into<crate::paging::error::Error> {
+        pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().copy_from_slice(body);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck() -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        (0..8).try_for_each(|_| pager.new_page(LIST).map(|_| ()))?;
+
+        // 1 -> 2 -> 3 -> 2, and 2 is also a named root.
+        pager.set_root("list", 1);
+        pager.set_root("other", 2);
+        link(&pager, 1, 2)?;
+        link(&pager, 2, 3)?;
+        link(&pager, 3, 2)?;
+        pager.borrow_mut_page(&2)?.set_parent(1);
+
+        // The free pages list is 5 -> 4, the page 7 leaked, and the page 6 is unreachable.
+        [4, 5, 7].iter().try_for_each(|pid| pager.drop_page(pid))?;
+        pager.set_freelist_head(Some(5));
+        pager.flush()?;
+        drop(pager);
+
+        // Corrupt the page 8.
+        std::fs::OpenOptions::new().write(true).open(&path)?.write_all_at(&[0xFF], 8 * 4096 + 100)?;
+
+        let pager: BufPager<PageId, u8, _> = BufPager::open(FileStorage::open(&path)?, 10)?;
+        let fsck = Fsck::new().with_links(LIST, ListLinks);
+        let report = fsck.check(&pager)?;
+
+        assert_eq!(report.pages, 8);
+        assert!(matches!(report.problems[0], Problem::ChecksumMismatch { pid: 8, .. }));
+        assert_eq!(report.problems[1..], [
+            Problem::Cycle { pid: 2, referrer: 3 },
+            Problem::DoubleReferenced { pid: 2, first: 1, second: SUPERBLOCK_PAGE },
+            Problem::Orphaned(6),
+            Problem::Leaked(7)
+        ]);
+
+        // The leaked page is back in the free pages list.
+        assert_eq!(fsck.repair(&pager, &report)?, 1);
+        assert_eq!(fsck.check(&pager)?.leaks(), 0);
+        assert_eq!(pager.new_page(LIST)?, 4);
+        assert_eq!(pager.new_page(LIST)?, 5);
+        assert_eq!(pager.new_page(LIST)?, 7);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsck_builtin_links() {
+        // Walked without recursion: a long overflow chain fits in a small stack.
+        std::thread::Builder::new().stack_size(128 * 1024).spawn(|| -> Result<()> {
+            let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(PagerStream::new(InMemory::new()), 4096, 10)?;
+            let (branch, leaf) = (pager.new_page(BPTREE_BRANCH)?, pager.new_page(BPTREE_LEAF)?);
+            let chain = pager.new_pages(OVERFLOW_PAGE, 2000)?;
+            pager.set_root("tree", branch);
+
+            // The branch references the leaf, and the overflow chain, which is not a node.
+            let mut body = [0u8; 4096 - PAGE_HEADER_SIZE];
+            (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]) = (BRANCH_NODE, 2, 4);
+            [(leaf, 1), (chain, 1)].iter().enumerate().for_each(|(index, (child, key))| {
+                let offset = cell_offset(4096, 4, index);
+                write_u64(&mut body, offset + BRANCH_CHILD.start..offset + BRANCH_CHILD.end, *child);
+                write_u64(&mut body, offset + BRANCH_KEY.start..offset + BRANCH_KEY.end, *key);
+            });
+            write_body(&pager, branch, &body)?;
+
+            // The leaf holds an element of 1 MB, which overflows in the chain.
+            let mut body = [0u8; 4096 - PAGE_HEADER_SIZE];
+            (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]) = (LEAF_NODE, 1, 4);
+            let offset = cell_offset(4096, 4, 0);
+            write_u64(&mut body, offset + LEAF_SIZE.start..offset + LEAF_SIZE.end, 1 << 20);
+            write_u64(&mut body, offset + LEAF_IN_PAGE_SIZE.start..offset + LEAF_IN_PAGE_SIZE.end, 16);
+            write_u64(&mut body, offset + LEAF_OVERFLOW.start..offset + LEAF_OVERFLOW.end, chain);
+            write_body(&pager, leaf, &body)?;
+            pager.borrow_mut_page(&leaf)?.set_parent(branch);
+
+            for pid in chain..chain + 2000 {
+                let mut body = [0u8; 4096 - PAGE_HEADER_SIZE];
+                body[OV_SIZE].copy_from_slice(&16u16.to_le_bytes());
+                write_u64(&mut body, OV_NEXT, if pid + 1 < chain + 2000 { pid + 1 } else { 0 });
+                write_body(&pager, pid, &body)?;
+                pager.borrow_mut_page(&pid)?.set_parent(if pid == chain { leaf } else { pid - 1 });
+            }
+
+            let report = Fsck::new().with_builtin_links().check(&pager)?;
+
+            assert_eq!(report.pages, 2002);
+            assert_eq!(report.problems, [
+                Problem::Invalid { pid: branch, reason: "cell 1: key 1 is not above the key 1 of the previous cell".into() },
+                Problem::TypeMismatch { pid: chain, found: OVERFLOW_PAGE },
+                Problem::DoubleReferenced { pid: chain, first: leaf, second: branch }
+            ]);
+
+            Ok(())
+        }).unwrap().join().unwrap().unwrap();
+    }
+}
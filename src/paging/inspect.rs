@@ -0,0 +1,223 @@
+//! Decoding of the raw content of the pages, for debugging the on-disk layouts.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use super::{page::{Page, PAGE_HEADER_SIZE, ROOT, BPTREE_LEAF, BPTREE_BRANCH, traits::ReadPage}, pager::{FREE_PAGE, OVERFLOW_PAGE}, allocator::read_next, layout::{body_size, cell_offset, cell_size, max_in_page_element_size, overflow_capacity, read_u64, OV_SIZE, OV_NEXT, NODE_TYPE, NODE_LEN, NODE_CAPACITY, NODE_HEADER_SIZE, LEAF_NODE, LEAF_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, LEAF_CELL_HEADER_SIZE, BRANCH_CELL_SIZE}};
+
+pub fn type_name(ptype: u8) -> &'static str {
+    match ptype {
+        FREE_PAGE => "free",
+        ROOT => "root",
+        BPTREE_LEAF => "b+tree leaf",
+        BPTREE_BRANCH => "b+tree branch",
+        OVERFLOW_PAGE => "overflow",
+        _ => "unknown"
+    }
+}
+
+/// Header of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub id: u64,
+    pub ptype: u8,
+    pub parent: u64,
+    pub checksum: u32,
+    pub lsn: u64,
+    pub version: u64,
+    /// Offset of the body.
+    pub body: usize,
+    /// The content matches the checksum.
+    pub valid: bool
+}
+
+impl PageHeader {
+    pub fn read(content: &[u8]) -> Self {
+        let page = Page::<u64, u8, _>::from(content);
+
+        Self {
+            id: page.get_id(),
+            ptype: page.get_type(),
+            parent: page.get_parent(),
+            checksum: page.get_checksum(),
+            lsn: page.get_lsn(),
+            version: page.get_version(),
+            body: PAGE_HEADER_SIZE,
+            valid: page.verify_checksum().is_ok()
+        }
+    }
+}
+
+/// Header of a B+tree leaf cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeafCell {
+    pub key: u64,
+    pub size: u64,
+    pub in_page_size: u64,
+    pub overflow: Option<u64>
+}
+
+/// Body of a page of a known type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageBody {
+    Free { next: Option<u64> },
+    Overflow { size: u16, next: Option<u64> },
+    Node { leaf: bool, len: u8, capacity: u8, cells: Vec<LeafCell> },
+    Unknown
+}
+
+fn non_zero(pid: u64) -> Option<u64> {
+    Some(pid).filter(|pid| *pid != 0)
+}
+
+impl PageBody {
+    pub fn decode(content: &[u8]) -> Self {
+        let body = &content[PAGE_HEADER_SIZE..];
+
+        match Page::<u64, u8, _>::from(content).get_type() {
+            FREE_PAGE => Self::Free { next: read_next(content) },
+            OVERFLOW_PAGE => Self::Overflow {
+                size: u16::from_le_bytes(body[OV_SIZE].try_into().unwrap()),
+                next: non_zero(read_u64(body, OV_NEXT))
+            },
+            BPTREE_LEAF | BPTREE_BRANCH => {
+                let (leaf, len, capacity) = (body[NODE_TYPE] == LEAF_NODE, body[NODE_LEN], body[NODE_CAPACITY]);
+                let mut cells = Vec::new();
+
+                if leaf && cell_size(content.len(), capacity) >= LEAF_CELL_HEADER_SIZE {
+                    for cell in (0..len.min(capacity) as usize).map(|index| &body[cell_offset(content.len(), capacity, index)..]) {
+                        cells.push(LeafCell {
+                            key: read_u64(cell, LEAF_KEY),
+                            size: read_u64(cell, LEAF_SIZE),
+                            in_page_size: read_u64(cell, LEAF_IN_PAGE_SIZE),
+                            overflow: non_zero(read_u64(cell, LEAF_OVERFLOW))
+                        });
+                    }
+                }
+
+                Self::Node { leaf, len, capacity, cells }
+            },
+            _ => Self::Unknown
+        }
+    }
+}
+
+/// Bytes of the body left unused by the layout of the page, none for the pages of unknown types.
+///
+/// Free pages only hold the next page of the list, overflow pages the chunk after their header.
+/// Nodes lose the end of the body the cells do not fill, the unused cells, and the room left in the used ones.
+pub fn free_space(content: &[u8]) -> usize {
+    let page_size = content.len();
+
+    match PageBody::decode(content) {
+        PageBody::Free { .. } => body_size(page_size).saturating_sub(8),
+        PageBody::Overflow { size, .. } => overflow_capacity(page_size).saturating_sub(size as usize),
+        PageBody::Node { leaf, len, capacity, cells } => {
+            let (len, cell) = (len.min(capacity) as usize, cell_size(page_size, capacity));
+            let unused = body_size(page_size).saturating_sub(NODE_HEADER_SIZE + capacity as usize * cell) + (capacity as usize - len) * cell;
+
+            if leaf {
+                let max_in_page = max_in_page_element_size(page_size, capacity);
+                unused + cells.iter().map(|cell| max_in_page.saturating_sub(cell.in_page_size as usize)).sum::<usize>()
+            } else {
+                unused + len * cell.saturating_sub(BRANCH_CELL_SIZE)
+            }
+        },
+        PageBody::Unknown => 0
+    }
+}
+
+/// Dump the bytes by lines of 16, starting at offset, repeated lines are collapsed into a star.
+pub fn hex_dump(bytes: &[u8], offset: usize) -> String {
+    let mut dump = String::new();
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !collapsed {
+                dump.push_str("*\n");
+                collapsed = true;
+            }
+
+            continue;
+        }
+
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line.iter().map(|byte| if byte.is_ascii_graphic() { *byte as char } else { '.' }).collect();
+        writeln!(dump, "{:08x}  {:<47}  |{}|", offset + i * 16, hex.join(" "), ascii).unwrap();
+
+        previous = Some(line);
+        collapsed = false;
+    }
+
+    dump
+}
+
+/// Pages and free space, by type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub pages: BTreeMap<u8, u64>,
+    pub free_space: BTreeMap<u8, u64>,
+    /// Pages not matching their checksum.
+    pub corrupted: u64
+}
+
+impl Summary {
+    pub fn record(&mut self, content: &[u8]) {
+        let header = PageHeader::read(content);
+        *self.pages.entry(header.ptype).or_default() += 1;
+        *self.free_space.entry(header.ptype).or_default() += free_space(content) as u64;
+
+        if !header.valid {
+            self.corrupted += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paging::{layout::{body_size, cell_offset, cell_size, max_in_page_element_size, overflow_capacity, write_u64, OV_SIZE, OV_NEXT, OV_RESERVED, NODE_TYPE, NODE_LEN, NODE_CAPACITY, NODE_HEADER_SIZE, LEAF_NODE, LEAF_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, LEAF_CELL_HEADER_SIZE}, page::{Page, PAGE_HEADER_SIZE, BPTREE_LEAF}, pager::OVERFLOW_PAGE};
+    use super::{hex_dump, LeafCell, PageBody, PageHeader, Summary};
+
+    fn page(pid: u64, ptype: u8, body: &[u8]) -> Vec<u8> {
+        let mut content = vec![0u8; 4096];
+        content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + body.len()].copy_from_slice(body);
+        let mut page = Page::<u64, u8, _>::new(pid, ptype, &mut content[..]);
+        page.update_checksum();
+        content
+    }
+
+    #[test]
+    fn test_decode_pages() {
+        let mut body = [0u8; OV_RESERVED];
+        body[OV_SIZE].copy_from_slice(&16u16.to_le_bytes());
+        write_u64(&mut body, OV_NEXT, 7);
+        let overflow = page(3, OVERFLOW_PAGE, &body);
+        assert_eq!(PageBody::decode(&overflow), PageBody::Overflow { size: 16, next: Some(7) });
+
+        // A leaf holding one of its two cells, the element 5 continues in the page 9.
+        let mut node = vec![0u8; NODE_HEADER_SIZE + LEAF_CELL_HEADER_SIZE];
+        (node[NODE_TYPE], node[NODE_LEN], node[NODE_CAPACITY]) = (LEAF_NODE, 1, 2);
+        let cell = &mut node[cell_offset(4096, 2, 0)..];
+        [(LEAF_KEY, 5), (LEAF_SIZE, 100), (LEAF_IN_PAGE_SIZE, 40), (LEAF_OVERFLOW, 9)].into_iter().for_each(|(field, value)| write_u64(cell, field, value));
+        let leaf = page(4, BPTREE_LEAF, &node);
+
+        assert_eq!(PageBody::decode(&leaf), PageBody::Node {
+            leaf: true, len: 1, capacity: 2,
+            cells: vec![LeafCell { key: 5, size: 100, in_page_size: 40, overflow: Some(9) }]
+        });
+
+        let header = PageHeader::read(&leaf);
+        assert_eq!((header.id, header.ptype, header.valid), (4, BPTREE_LEAF, true));
+
+        let mut summary = Summary::default();
+        summary.record(&overflow);
+        summary.record(&leaf);
+        assert_eq!(summary.pages[&OVERFLOW_PAGE], 1);
+        assert_eq!(summary.free_space[&OVERFLOW_PAGE], (overflow_capacity(4096) - 16) as u64);
+        // The unused cell, the room left in the used one, and the end of the body the cells do not fill.
+        assert_eq!(summary.free_space[&BPTREE_LEAF], (cell_size(4096, 2) + max_in_page_element_size(4096, 2) - 40 + (body_size(4096) - NODE_HEADER_SIZE) % 2) as u64);
+
+        assert_eq!(hex_dump(&[0; 48], 0x10), format!("00000010  {}  |{}|\n*\n", ["00"; 16].join(" "), ".".repeat(16)));
+    }
+}
@@ -0,0 +1,105 @@
+//! On-disk layouts of the overflow pages, of the B+tree nodes and of the var values, for a given page size.
+//! Offsets are relative to the body of the page.
+
+use std::ops::Range;
+
+use super::page::PAGE_HEADER_SIZE;
+
+/// Overflow pages: size of the chunk stored in the page, and next page of the chain, followed by the chunk.
+/// The next page is stored in the first 8 bytes of its field, the last 2 are padding kept from the original format.
+pub const OV_SIZE: Range<usize> = 0..2;
+pub const OV_NEXT: Range<usize> = 2..12;
+pub const OV_RESERVED: usize = 12;
+
+/// B+tree nodes: type, number of cells and capacity, followed by the cells.
+pub const NODE_TYPE: usize = 0;
+pub const NODE_LEN: usize = 1;
+pub const NODE_CAPACITY: usize = 2;
+pub const NODE_HEADER_SIZE: usize = 24;
+pub const BRANCH_NODE: u8 = 1;
+pub const LEAF_NODE: u8 = 2;
+
+/// B+tree leaf cells: key, total size of the element, size stored in the cell, and first overflow page, followed by the element.
+pub const LEAF_KEY: Range<usize> = 0..8;
+pub const LEAF_SIZE: Range<usize> = 8..16;
+pub const LEAF_IN_PAGE_SIZE: Range<usize> = 16..24;
+pub const LEAF_OVERFLOW: Range<usize> = 24..32;
+pub const LEAF_CELL_HEADER_SIZE: usize = 32;
+
+/// B+tree branch cells: child holding the keys up to the key of the cell, and the key.
+pub const BRANCH_CHILD: Range<usize> = 0..8;
+pub const BRANCH_KEY: Range<usize> = 8..16;
+pub const BRANCH_CELL_SIZE: usize = 16;
+
+/// Var values: size, overflow page, in page size, pointer and in page capacity, followed by the in page bytes.
+pub const VAR_HEADER_SIZE: usize = 36;
+
+/// Size of the body of a page.
+pub const fn body_size(page_size: usize) -> usize {
+    page_size.saturating_sub(PAGE_HEADER_SIZE)
+}
+
+/// Bytes of a chunk held by an overflow page.
+pub const fn overflow_capacity(page_size: usize) -> usize {
+    body_size(page_size).saturating_sub(OV_RESERVED)
+}
+
+/// Size of the cells of a node, they share the body evenly.
+pub const fn cell_size(page_size: usize, capacity: u8) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+
+    body_size(page_size).saturating_sub(NODE_HEADER_SIZE) / capacity as usize
+}
+
+/// Offset of the cell in the body of a node.
+pub const fn cell_offset(page_size: usize, capacity: u8, index: usize) -> usize {
+    NODE_HEADER_SIZE + index * cell_size(page_size, capacity)
+}
+
+/// Bytes of an element stored in a leaf cell, the rest goes to overflow pages.
+pub const fn max_in_page_element_size(page_size: usize, capacity: u8) -> usize {
+    cell_size(page_size, capacity).saturating_sub(LEAF_CELL_HEADER_SIZE)
+}
+
+/// Bytes of a var value stored in the page, when its header is at base in the body.
+pub const fn var_in_page_capacity(page_size: usize, base: usize) -> usize {
+    body_size(page_size).saturating_sub(base + VAR_HEADER_SIZE)
+}
+
+/// Read the value held in the first 8 bytes of the field.
+pub fn read_u64(bytes: &[u8], range: Range<usize>) -> u64 {
+    u64::from_le_bytes(bytes[range][..8].try_into().unwrap())
+}
+
+/// Write the value in the first 8 bytes of the field.
+pub fn write_u64(bytes: &mut [u8], range: Range<usize>, value: u64) {
+    bytes[range][..8].copy_from_slice(&value.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paging::page::PAGE_HEADER_SIZE;
+    use super::{cell_size, cell_offset, max_in_page_element_size, overflow_capacity, var_in_page_capacity, NODE_HEADER_SIZE, OV_NEXT, OV_RESERVED, LEAF_CELL_HEADER_SIZE, VAR_HEADER_SIZE};
+
+    #[test]
+    fn test_layout_page_size() {
+        for page_size in [4096, 16_384, 65_536] {
+            let body = page_size - PAGE_HEADER_SIZE;
+            assert_eq!(overflow_capacity(page_size), body - OV_RESERVED);
+            assert_eq!(cell_size(page_size, 8), (body - NODE_HEADER_SIZE) / 8);
+            assert_eq!(max_in_page_element_size(page_size, 8), cell_size(page_size, 8) - LEAF_CELL_HEADER_SIZE);
+            assert_eq!(var_in_page_capacity(page_size, 100), body - 100 - VAR_HEADER_SIZE);
+
+            // The cells fit in the body.
+            assert!(cell_offset(page_size, 8, 8) <= body);
+        }
+
+        // The chunk of an overflow page starts after the padding of the next page.
+        assert_eq!((OV_NEXT, OV_RESERVED), (2..12, 12));
+
+        assert_eq!(cell_size(4096, 0), 0);
+        assert_eq!(max_in_page_element_size(4096, 255), 0);
+    }
+}
@@ -0,0 +1,224 @@
+//! Links held by the overflow pages and the B+tree nodes, as laid out in layout.
+
+use std::ops::Range;
+
+use super::{layout::{read_u64, write_u64, cell_offset, cell_size, max_in_page_element_size, overflow_capacity, OV_SIZE, OV_NEXT, NODE_TYPE, NODE_LEN, NODE_CAPACITY, BRANCH_NODE, LEAF_NODE, BRANCH_CHILD, BRANCH_KEY, BRANCH_CELL_SIZE, LEAF_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, LEAF_CELL_HEADER_SIZE}, page::{PAGE_HEADER_SIZE, PAGE_TYPE_OFFSET, BPTREE_BRANCH, BPTREE_LEAF}, pager::OVERFLOW_PAGE, vacuum::traits::PageLinks};
+
+/// Page types whose links are known, with their links.
+pub fn builtin_links() -> Vec<(u8, Box<dyn PageLinks>)> {
+    vec![
+        (OVERFLOW_PAGE, Box::new(OverflowLinks)),
+        (BPTREE_BRANCH, Box::new(BPTreeLinks)),
+        (BPTREE_LEAF, Box::new(BPTreeLinks))
+    ]
+}
+
+/// Overflow pages link the next page of their chain.
+pub struct OverflowLinks;
+
+impl PageLinks for OverflowLinks {
+    fn links(&self, content: &[u8]) -> Vec<u64> {
+        match read_u64(&content[PAGE_HEADER_SIZE..], OV_NEXT) {
+            0 => vec![],
+            next => vec![next]
+        }
+    }
+
+    fn relink(&self, content: &mut [u8], from: u64, to: u64) {
+        let body = &mut content[PAGE_HEADER_SIZE..];
+
+        if read_u64(body, OV_NEXT) == from {
+            write_u64(body, OV_NEXT, to);
+        }
+    }
+
+    fn check(&self, content: &[u8]) -> Result<(), String> {
+        let size = u16::from_le_bytes(content[PAGE_HEADER_SIZE..][OV_SIZE].try_into().unwrap()) as usize;
+
+        if size > overflow_capacity(content.len()) {
+            return Err(format!("chunk of {} bytes, over a capacity of {}", size, overflow_capacity(content.len())));
+        }
+
+        Ok(())
+    }
+
+    fn accepts(&self, _content: &[u8], ptype: u8) -> bool {
+        ptype == OVERFLOW_PAGE
+    }
+}
+
+/// Branch nodes link their children, leaf nodes the first overflow page of their elements.
+pub struct BPTreeLinks;
+
+impl BPTreeLinks {
+    /// Ranges of the field in the cells of the node, relative to the content of the page.
+    fn fields(content: &[u8], field: Range<usize>) -> Vec<Range<usize>> {
+        let body = &content[PAGE_HEADER_SIZE..];
+        let capacity = body[NODE_CAPACITY];
+
+        (0..body[NODE_LEN].min(capacity) as usize)
+        .map(|index| PAGE_HEADER_SIZE + cell_offset(content.len(), capacity, index))
+        .map(|offset| offset + field.start..offset + field.end)
+        .filter(|range| range.end <= content.len())
+        .collect()
+    }
+
+    /// Ranges of the links in the content of the page.
+    fn ranges(content: &[u8]) -> Vec<Range<usize>> {
+        match content[PAGE_HEADER_SIZE + NODE_TYPE] {
+            BRANCH_NODE => Self::fields(content, BRANCH_CHILD),
+            LEAF_NODE => Self::fields(content, LEAF_OVERFLOW),
+            _ => vec![]
+        }
+    }
+
+    /// Sizes of the elements of the leaf cells: total, stored in the cell, and first overflow page.
+    fn check_leaf_cells(content: &[u8]) -> Result<(), String> {
+        let capacity = content[PAGE_HEADER_SIZE + NODE_CAPACITY];
+        let max_in_page = max_in_page_element_size(content.len(), capacity) as u64;
+        let cells = Self::fields(content, LEAF_SIZE).into_iter().zip(Self::fields(content, LEAF_IN_PAGE_SIZE)).zip(Self::fields(content, LEAF_OVERFLOW));
+
+        for (index, ((size, in_page_size), overflow)) in cells.enumerate() {
+            let (size, in_page_size, overflow) = (read_u64(content, size), read_u64(content, in_page_size), read_u64(content, overflow));
+
+            if in_page_size > max_in_page || in_page_size > size {
+                return Err(format!("cell {}: {} bytes stored in the cell, for an element of {} bytes, and a cell capacity of {}", index, in_page_size, size, max_in_page));
+            }
+
+            if (overflow != 0) != (size > in_page_size) {
+                return Err(format!("cell {}: element of {} bytes, {} stored in the cell, with the overflow page {}", index, size, in_page_size, overflow));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PageLinks for BPTreeLinks {
+    fn links(&self, content: &[u8]) -> Vec<u64> {
+        Self::ranges(content)
+        .into_iter()
+        .map(|range| read_u64(content, range))
+        .filter(|pid| *pid != 0)
+        .collect()
+    }
+
+    fn relink(&self, content: &mut [u8], from: u64, to: u64) {
+        for range in Self::ranges(content) {
+            if read_u64(content, range.clone()) == from {
+                write_u64(content, range, to);
+            }
+        }
+    }
+
+    /// The node matches the type of the page, holds at most its capacity, and its keys are in ascending order.
+    fn check(&self, content: &[u8]) -> Result<(), String> {
+        let body = &content[PAGE_HEADER_SIZE..];
+        let (node_type, len, capacity) = (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]);
+
+        let (key, cell_header_size) = match (content[PAGE_TYPE_OFFSET], node_type) {
+            (BPTREE_BRANCH, BRANCH_NODE) => (BRANCH_KEY, BRANCH_CELL_SIZE),
+            (BPTREE_LEAF, LEAF_NODE) => (LEAF_KEY, LEAF_CELL_HEADER_SIZE),
+            (ptype, _) => return Err(format!("node type {} in a page of type {:#04x}", node_type, ptype))
+        };
+
+        if len > capacity {
+            return Err(format!("{} cells, over a capacity of {}", len, capacity));
+        }
+
+        if len > 0 && cell_size(content.len(), capacity) < cell_header_size {
+            return Err(format!("cells of {} bytes, for a capacity of {}", cell_size(content.len(), capacity), capacity));
+        }
+
+        let keys: Vec<u64> = Self::fields(content, key).into_iter().map(|range| read_u64(content, range)).collect();
+
+        if let Some(index) = keys.windows(2).position(|pair| pair[0] >= pair[1]) {
+            return Err(format!("cell {}: key {} is not above the key {} of the previous cell", index + 1, keys[index + 1], keys[index]));
+        }
+
+        if node_type == LEAF_NODE {
+            Self::check_leaf_cells(content)?;
+        }
+
+        Ok(())
+    }
+
+    /// Branch nodes reference nodes, leaf nodes overflow pages.
+    fn accepts(&self, content: &[u8], ptype: u8) -> bool {
+        match content[PAGE_HEADER_SIZE + NODE_TYPE] {
+            BRANCH_NODE => ptype == BPTREE_BRANCH || ptype == BPTREE_LEAF,
+            _ => ptype == OVERFLOW_PAGE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paging::{layout::{cell_offset, write_u64, NODE_TYPE, NODE_LEN, NODE_CAPACITY, BRANCH_NODE, LEAF_NODE, BRANCH_CHILD, BRANCH_KEY, LEAF_SIZE, LEAF_IN_PAGE_SIZE, LEAF_OVERFLOW, OV_SIZE, OV_NEXT}, page::{PAGE_HEADER_SIZE, PAGE_TYPE_OFFSET, BPTREE_BRANCH, BPTREE_LEAF}, pager::OVERFLOW_PAGE, vacuum::traits::PageLinks};
+    use super::{BPTreeLinks, OverflowLinks};
+
+    /// Node of the type, with the cells linking the pages.
+    fn node(node_type: u8, capacity: u8, links: &[u64]) -> Vec<u8> {
+        let mut content = vec![0u8; 4096];
+        let field = if node_type == BRANCH_NODE { BRANCH_CHILD } else { LEAF_OVERFLOW };
+        let body = &mut content[PAGE_HEADER_SIZE..];
+        (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]) = (node_type, links.len() as u8, capacity);
+
+        for (index, pid) in links.iter().enumerate() {
+            let offset = cell_offset(4096, capacity, index);
+            write_u64(body, offset + field.start..offset + field.end, *pid);
+        }
+
+        content
+    }
+
+    #[test]
+    fn test_builtin_links() {
+        let mut branch = node(BRANCH_NODE, 8, &[4, 7, 9]);
+        assert_eq!(BPTreeLinks.links(&branch), vec![4, 7, 9]);
+        BPTreeLinks.relink(&mut branch, 7, 2);
+        assert_eq!(BPTreeLinks.links(&branch), vec![4, 2, 9]);
+
+        // Elements stored in the cell have no overflow page.
+        let leaf = node(LEAF_NODE, 4, &[0, 12]);
+        assert_eq!(BPTreeLinks.links(&leaf), vec![12]);
+        assert!(BPTreeLinks.links(&vec![0u8; 4096]).is_empty());
+
+        let mut overflow = vec![0u8; 4096];
+        assert!(OverflowLinks.links(&overflow).is_empty());
+        write_u64(&mut overflow[PAGE_HEADER_SIZE..], OV_NEXT, 5);
+        OverflowLinks.relink(&mut overflow, 5, 3);
+        assert_eq!(OverflowLinks.links(&overflow), vec![3]);
+        assert!(OverflowLinks.check(&overflow).is_ok());
+        overflow[PAGE_HEADER_SIZE..][OV_SIZE].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(OverflowLinks.check(&overflow).is_err());
+    }
+
+    #[test]
+    fn test_builtin_checks() {
+        let mut branch = node(BRANCH_NODE, 8, &[4, 7]);
+        branch[PAGE_TYPE_OFFSET] = BPTREE_BRANCH;
+        assert!(BPTreeLinks.accepts(&branch, BPTREE_LEAF) && !BPTreeLinks.accepts(&branch, OVERFLOW_PAGE));
+
+        // Keys in ascending order.
+        let key = PAGE_HEADER_SIZE + cell_offset(4096, 8, 1) + BRANCH_KEY.start;
+        write_u64(&mut branch, key..key + 8, 10);
+        assert!(BPTreeLinks.check(&branch).is_ok());
+        branch[PAGE_HEADER_SIZE + NODE_LEN] = 9;
+        assert!(BPTreeLinks.check(&branch).is_err());
+        branch[PAGE_HEADER_SIZE + NODE_LEN] = 2;
+        branch[PAGE_TYPE_OFFSET] = BPTREE_LEAF;
+        assert!(BPTreeLinks.check(&branch).is_err());
+
+        // The element overflows, but the cell links no overflow page.
+        let mut leaf = node(LEAF_NODE, 4, &[0]);
+        leaf[PAGE_TYPE_OFFSET] = BPTREE_LEAF;
+        assert!(BPTreeLinks.accepts(&leaf, OVERFLOW_PAGE) && !BPTreeLinks.accepts(&leaf, BPTREE_LEAF));
+        let size = PAGE_HEADER_SIZE + cell_offset(4096, 4, 0) + LEAF_SIZE.start;
+        write_u64(&mut leaf, size..size + 8, 100);
+        assert!(BPTreeLinks.check(&leaf).is_err());
+        let in_page_size = PAGE_HEADER_SIZE + cell_offset(4096, 4, 0) + LEAF_IN_PAGE_SIZE.start;
+        write_u64(&mut leaf, in_page_size..in_page_size + 8, 100);
+        assert!(BPTreeLinks.check(&leaf).is_ok());
+    }
+}
@@ -0,0 +1,366 @@
+use std::{cell::RefCell, collections::{BTreeMap, BTreeSet}, fs::File, os::unix::io::AsRawFd, path::Path};
+
+use crate::buffer::RefBufArray;
+
+use super::{page::{Page, RefMutPage}, pager::{BufPager, traits::{PageStorage, Pager}}, storage::{FileStorage, LockMode, LockWait}, vacuum::{Vacuum, VacuumReport}, error::Error, result::Result};
+
+/// Read-only mapping of the database file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    /// Number of borrows of pages mapped by it.
+    borrows: usize
+}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self { ptr: ptr as *mut u8, len, borrows: 0 })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Mappings of the file by generation, the last one is current.
+/// The file is mapped again when it grows, the superseded mappings are unmapped once their pages are no longer borrowed.
+#[derive(Default)]
+struct Mappings {
+    mapped: BTreeMap<u64, Mapping>,
+    /// Number of borrows of mapped pages, by page.
+    readers: BTreeMap<u64, usize>
+}
+
+impl Mappings {
+    fn current(&self) -> Option<(u64, &Mapping)> {
+        self.mapped.last_key_value().map(|(generation, mapping)| (*generation, mapping))
+    }
+
+    fn push(&mut self, mapping: Mapping) {
+        let generation = self.current().map(|(generation, _)| generation + 1).unwrap_or(0);
+        self.mapped.insert(generation, mapping);
+        self.release_superseded();
+    }
+
+    fn release_superseded(&mut self) {
+        let current = self.current().map(|(generation, _)| generation);
+        self.mapped.retain(|generation, mapping| Some(*generation) == current || mapping.borrows > 0);
+    }
+}
+
+/// A page borrowed from the mapping.
+pub struct MappedRef<'a> {
+    pid: u64,
+    generation: u64,
+    content: &'a [u8],
+    mappings: &'a RefCell<Mappings>
+}
+
+impl<'a> MappedRef<'a> {
+    fn new(pid: u64, generation: u64, content: &'a [u8], mappings: &'a RefCell<Mappings>) -> Self {
+        let mut borrowed = mappings.borrow_mut();
+        *borrowed.readers.entry(pid).or_default() += 1;
+
+        if let Some(mapping) = borrowed.mapped.get_mut(&generation) {
+            mapping.borrows += 1;
+        }
+
+        std::mem::drop(borrowed);
+        Self { pid, generation, content, mappings }
+    }
+}
+
+impl<'a> Drop for MappedRef<'a> {
+    fn drop(&mut self) {
+        let mut mappings = self.mappings.borrow_mut();
+
+        if let Some(count) = mappings.readers.get_mut(&self.pid) {
+            *count -= 1;
+
+            if *count == 0 {
+                mappings.readers.remove(&self.pid);
+            }
+        }
+
+        if let Some(mapping) = mappings.mapped.get_mut(&self.generation) {
+            mapping.borrows -= 1;
+        }
+
+        mappings.release_superseded();
+    }
+}
+
+/// Content of a page, either mapped from the file, or held in the buffer.
+pub enum PageRef<'a> {
+    Mapped(MappedRef<'a>),
+    Buffered(RefBufArray<'a, u8>)
+}
+
+impl<'a> PageRef<'a> {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Self::Mapped(_))
+    }
+}
+
+impl<'a> AsRef<[u8]> for PageRef<'a> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(mapped) => mapped.content,
+            Self::Buffered(array) => array.as_ref()
+        }
+    }
+}
+
+pub type MmapPage<'a, Id, Type> = Page<'a, Id, Type, PageRef<'a>>;
+
+/// Pager reading the pages straight from a mapping of the database file.
+///
+/// Writes go through a buffered pager, pages modified since the last flush, or held in its buffer, are read from it.
+pub struct MmapPager<'buffer, Id, Type, Storage> where Storage: PageStorage {
+    file: File,
+    writer: BufPager<'buffer, Id, Type, Storage>,
+    mappings: RefCell<Mappings>,
+    /// Pages modified since the last flush.
+    modified: RefCell<BTreeSet<u64>>
+}
+
+impl<'buffer, Id, Type> MmapPager<'buffer, Id, Type, FileStorage>
+where Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    /// Open an existing database file, locked exclusively.
+    /// buffer_size: number of pages that can be stored in memory, for writes
+    pub fn open<P: AsRef<Path>>(path: P, buffer_size: usize) -> Result<Self> {
+        Self::open_locked(path, LockMode::Exclusive, LockWait::FailFast, buffer_size)
+    }
+
+    /// Open an existing database file, the mapping shares the lock of the storage.
+    /// buffer_size: number of pages that can be stored in memory, for writes
+    pub fn open_locked<P: AsRef<Path>>(path: P, mode: LockMode, wait: LockWait, buffer_size: usize) -> Result<Self> {
+        let store = FileStorage::open_locked(path, mode, wait)?;
+        Self::with_storage(store.file().try_clone()?, store, buffer_size)
+    }
+}
+
+impl<'buffer, Id, Type, Storage> MmapPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    /// Open the database stored in the file, writes go through the storage (see WalStorage).
+    /// buffer_size: number of pages that can be stored in memory, for writes
+    pub fn with_storage(file: File, store: Storage, buffer_size: usize) -> Result<Self> {
+        Ok(Self {
+            file,
+            writer: BufPager::open(store, buffer_size)?,
+            mappings: Default::default(),
+            modified: Default::default()
+        })
+    }
+
+    /// The pager handling the writes.
+    /// The file must not be truncated through it, see truncate and vacuum, as the mapped pages beyond the end would fault on access.
+    pub fn writer(&self) -> &BufPager<'buffer, Id, Type, Storage> {
+        &self.writer
+    }
+
+    /// Release the pager, and returns its storage.
+    pub fn into_storage(self) -> Storage where Id: Default {
+        self.writer.into_storage()
+    }
+
+    /// Discard the pages beyond last, and release their room in the file, see BufPager::truncate.
+    /// Refused while mapped pages are borrowed, the file is mapped again on the next access.
+    pub fn truncate(&self, last: Id) -> Result<()> where Type: From<u8> + Into<u8> {
+        self.unmap()?;
+        self.writer.truncate(last)
+    }
+
+    /// Compact the file, see Vacuum::run.
+    /// Refused while mapped pages are borrowed, the file is mapped again on the next access.
+    pub fn vacuum(&self, vacuum: &Vacuum) -> Result<VacuumReport> where Type: From<u8> + Into<u8> {
+        self.unmap()?;
+        vacuum.run(&self.writer)
+    }
+
+    /// Drop the mappings, before the file shrinks.
+    fn unmap(&self) -> Result<()> {
+        let mut mappings = self.mappings.borrow_mut();
+
+        if !mappings.readers.is_empty() {
+            return Err(Error::BufferError(crate::buffer::Error::ImmutablyBorrowed));
+        }
+
+        mappings.mapped.clear();
+        Ok(())
+    }
+
+    /// Map the content of the page, if it is stored in the file, returns it with the generation of its mapping.
+    fn map_page(&self, pid: u64) -> Result<Option<(u64, &[u8])>> where Type: From<u8> + Into<u8> {
+        let page_size = self.writer.get_page_size() as u64;
+        let end = (pid + 1) * page_size;
+        let mut mappings = self.mappings.borrow_mut();
+
+        // The file grew since it was last mapped.
+        if mappings.current().map(|(_, mapping)| (mapping.len as u64) < end).unwrap_or(true) {
+            let len = self.file.metadata()?.len();
+
+            if len < end {
+                return Ok(None);
+            }
+
+            mappings.push(Mapping::new(&self.file, len as usize)?);
+        }
+
+        let (generation, mapping) = mappings.current().unwrap();
+
+        // The mapping is kept as long as the page is borrowed, see MappedRef.
+        unsafe {
+            Ok(Some((generation, std::slice::from_raw_parts(mapping.ptr.add((end - page_size) as usize), page_size as usize))))
+        }
+    }
+
+    /// Number of mappings of the file, the current one and the superseded ones still borrowed.
+    pub fn count_mappings(&self) -> usize {
+        self.mappings.borrow().mapped.len()
+    }
+
+    /// Pages borrowed from the mapping cannot be modified.
+    fn check_unmapped(&self, pid: u64) -> Result<()> {
+        if self.mappings.borrow().readers.contains_key(&pid) {
+            return Err(Error::BufferError(crate::buffer::Error::ImmutablyBorrowed));
+        }
+
+        self.modified.borrow_mut().insert(pid);
+        Ok(())
+    }
+}
+
+impl<'a, 'buffer, Id, Type, Storage> Pager<'a> for MmapPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: 'a + std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: 'a + From<u8> + Into<u8>
+{
+    type Error = Error;
+    type RefPage = MmapPage<'a, Id, Type>;
+    type RefMutPage = RefMutPage<'a, Id, Type>;
+
+    fn new_page(&'a self, ptype: Type) -> Result<Id> {
+        let pid = self.writer.new_page(ptype)?;
+        self.modified.borrow_mut().insert(pid.into());
+        Ok(pid)
+    }
+
+    fn borrow_page(&'a self, pid: &Id) -> Result<Self::RefPage> {
+        let key: u64 = (*pid).into();
+
+        if !self.modified.borrow().contains(&key) && !self.writer.is_buffered(pid) {
+            if let Some((generation, content)) = self.map_page(key)? {
+                let page = MmapPage::from(PageRef::Mapped(MappedRef::new(key, generation, content, &self.mappings)));
+
+                page
+                .verify_checksum()
+                .map_err(|(expected, actual)| Error::ChecksumMismatch { pid: key, expected, actual })?;
+
+                return Ok(page);
+            }
+        }
+
+        Ok(MmapPage::from(PageRef::Buffered(self.writer.borrow_page(pid)?.into_data())))
+    }
+
+    fn borrow_mut_page(&'a self, pid: &Id) -> Result<Self::RefMutPage> {
+        self.check_unmapped((*pid).into())?;
+        self.writer.borrow_mut_page(pid)
+    }
+
+    fn drop_page(&self, pid: &Id) -> Result<()> {
+        self.check_unmapped((*pid).into())?;
+        self.writer.drop_page(pid)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.flush()?;
+        self.modified.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn get_page_size(&self) -> usize {
+        self.writer.get_page_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fixtures, paging::{page::PageSectionType, pager::{traits::Pager, BufPager, PageId}, storage::FileStorage, vacuum::Vacuum, error::Error, result::Result}, utils::slice::{BorrowSection, IntoSection}};
+    use super::{MmapPage, MmapPager};
+
+    /// First byte of the body, and whether the page was mapped.
+    fn read(page: MmapPage<'_, PageId, u8>) -> (u8, bool) {
+        let value = page.borrow_section(PageSectionType::Body).as_ref()[0];
+        (value, page.into_data().is_mapped())
+    }
+
+    #[test]
+    fn test_mmap_pager() -> Result<()> {
+        let path = fixtures::temp_path();
+
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        let pid = pager.new_page(0x10)?;
+        pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().fill(1);
+        pager.flush()?;
+        drop(pager);
+
+        // Room for a single page, to force the pages written through the pager out of the buffer.
+        let pager: MmapPager<PageId, u8, _> = MmapPager::open(&path, 1)?;
+        assert_eq!(read(pager.borrow_page(&pid)?), (1, true));
+        assert!(matches!(MmapPager::<PageId, u8, _>::open(&path, 1), Err(Error::Locked { .. })));
+
+        // Mapped pages cannot be modified while borrowed.
+        let page = pager.borrow_page(&pid)?;
+        assert!(matches!(pager.borrow_mut_page(&pid), Err(Error::BufferError(_))));
+        drop(page);
+
+        pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().fill(2);
+        assert_eq!(read(pager.borrow_page(&pid)?), (2, false));
+
+        // The new pages are stored beyond the first mapping.
+        let other = pager.new_page(0x10)?;
+        pager.borrow_mut_page(&other)?.into_section(PageSectionType::Body).as_mut().fill(3);
+        pager.flush()?;
+        pager.new_page(0x10)?;
+        pager.flush()?;
+
+        assert_eq!(read(pager.borrow_page(&pid)?), (2, true));
+        assert_eq!(read(pager.borrow_page(&other)?), (3, true));
+
+        // The superseded mapping is unmapped once its pages are no longer borrowed.
+        let page = pager.borrow_page(&pid)?;
+        let grown = [pager.new_page(0x10)?, pager.new_page(0x10)?];
+        pager.flush()?;
+        assert!(read(pager.borrow_page(&grown[0])?).1);
+        assert_eq!(pager.count_mappings(), 2);
+        drop(page);
+        assert_eq!(pager.count_mappings(), 1);
+
+        // The file does not shrink under borrowed pages.
+        grown.iter().try_for_each(|pid| pager.drop_page(pid))?;
+        pager.flush()?;
+        let page = pager.borrow_page(&pid)?;
+        assert!(matches!(pager.vacuum(&Vacuum::new()), Err(Error::BufferError(_))));
+        drop(page);
+
+        assert_eq!(pager.vacuum(&Vacuum::new())?.truncated, 2);
+        assert_eq!(read(pager.borrow_page(&other)?), (3, true));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
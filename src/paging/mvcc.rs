@@ -0,0 +1,341 @@
+use std::{collections::{BTreeMap, BTreeSet}, marker::PhantomData, sync::{Arc, Mutex}};
+
+use super::{error::Error, page::Page, pager::traits::PageStorage, result::Result};
+
+use self::traits::Versioned;
+
+pub mod traits {
+    use std::collections::BTreeMap;
+
+    use super::CommittedPages;
+
+    /// A pager keeping the committed versions of its pages while snapshots reference them.
+    pub trait Versioned {
+        type Id;
+        type Type;
+        type Storage;
+
+        /// Pin the last committed version, and return it with its named roots.
+        fn pin_snapshot(&self) -> (u64, BTreeMap<String, Self::Id>);
+        /// The committed contents of the pages, read by the snapshots.
+        fn committed_pages(&self) -> CommittedPages<'_, Self::Storage>;
+    }
+}
+
+/// Superseded contents of a page, with the last version they were valid for.
+type VersionChain = Vec<(u64, Arc<[u8]>)>;
+
+/// Version chains of the pages superseded while snapshots were pinned.
+#[derive(Default)]
+pub struct VersionStore {
+    chains: BTreeMap<u64, VersionChain>,
+    /// Pinned versions, and the number of snapshots pinning them.
+    snapshots: BTreeMap<u64, usize>,
+    /// Committed pages written over since the last commit, while no snapshot was pinned: their committed content is lost.
+    overwritten: BTreeSet<u64>
+}
+
+impl VersionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// At least one snapshot is pinned.
+    pub fn is_pinned(&self) -> bool {
+        !self.snapshots.is_empty()
+    }
+
+    pub fn pin(&mut self, version: u64) {
+        *self.snapshots.entry(version).or_default() += 1;
+    }
+
+    pub fn release(&mut self, version: u64) {
+        if let Some(count) = self.snapshots.get_mut(&version) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.snapshots.remove(&version);
+                self.gc();
+            }
+        }
+    }
+
+    /// Keep the content of the page, valid up to the version included.
+    /// The content already kept for the version is not replaced, as the page may have been stored since.
+    pub fn push(&mut self, pid: u64, valid_until: u64, content: Arc<[u8]>) {
+        if !self.contains(pid, valid_until) {
+            self.chains.entry(pid).or_default().push((valid_until, content));
+        }
+    }
+
+    /// The content of the page valid up to the version is kept.
+    pub fn contains(&self, pid: u64, valid_until: u64) -> bool {
+        self.chains.get(&pid).is_some_and(|chain| chain.iter().any(|(version, _)| *version == valid_until))
+    }
+
+    /// Content of the page as of the version, if it was superseded since.
+    pub fn get(&self, pid: u64, version: u64) -> Option<Arc<[u8]>> {
+        self.chains
+        .get(&pid)?
+        .iter()
+        .find(|(valid_until, _)| *valid_until >= version)
+        .map(|(_, content)| content.clone())
+    }
+
+    /// The committed content of the page was written over, without being kept.
+    pub fn overwrite(&mut self, pid: u64) {
+        self.overwritten.insert(pid);
+    }
+
+    pub fn is_overwritten(&self, pid: u64) -> bool {
+        self.overwritten.contains(&pid)
+    }
+
+    /// The stored pages are committed.
+    pub fn commit(&mut self) {
+        self.overwritten.clear();
+        self.gc();
+    }
+
+    /// Number of page versions kept.
+    pub fn len(&self) -> usize {
+        self.chains.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+
+    /// Drop the page versions which are no longer seen by any snapshot.
+    pub fn gc(&mut self) {
+        let snapshots = &self.snapshots;
+
+        self.chains.retain(|_, chain| {
+            let mut previous: Option<u64> = None;
+
+            chain.retain(|(valid_until, _)| {
+                // The version is seen by the snapshots pinned after the previous one was superseded.
+                let from = previous.map(|version| version + 1).unwrap_or(0);
+                previous = Some(*valid_until);
+                snapshots.range(from..=*valid_until).next().is_some()
+            });
+
+            !chain.is_empty()
+        });
+    }
+}
+
+pub type SnapshotPage<Id, Type> = Page<'static, Id, Type, Arc<[u8]>>;
+
+/// Committed contents of the pages: the superseded ones are kept in the version store, the others are in the storage.
+///
+/// The pager keeps the committed content of a page under the lock of the version store before overwriting it in the storage,
+/// and the storage is read under that lock as well: the snapshots may read it from other threads, if the storage is Sync.
+pub struct CommittedPages<'pager, Storage> {
+    store: &'pager Storage,
+    versions: &'pager Mutex<VersionStore>,
+    page_size: usize
+}
+
+impl<'pager, Storage> CommittedPages<'pager, Storage> {
+    pub fn new(store: &'pager Storage, versions: &'pager Mutex<VersionStore>, page_size: usize) -> Self {
+        Self { store, versions, page_size }
+    }
+
+    /// Unpin the version, its page versions can be garbage collected.
+    pub fn release(&self, version: u64) {
+        self.versions.lock().unwrap().release(version)
+    }
+}
+
+impl<'pager, Storage> CommittedPages<'pager, Storage> where Storage: PageStorage, Storage::Error: Into<Error> {
+    /// Read the content of the page, as of the committed version.
+    pub fn read_page_as_of(&self, pid: u64, version: u64) -> Result<Arc<[u8]>> {
+        let versions = self.versions.lock().unwrap();
+
+        if let Some(content) = versions.get(pid, version) {
+            return Ok(content);
+        }
+
+        // The page was written back while no snapshot was pinned, the storage holds its uncommitted content.
+        if versions.is_overwritten(pid) {
+            return Err(Error::SnapshotUnavailable { pid });
+        }
+
+        // The page was not superseded since the version, and cannot be while the lock is held.
+        let mut content = vec![0u8; self.page_size];
+        self.store.fetch(pid, &mut content).map_err(Into::<Error>::into)?;
+
+        Page::<u64, u8, _>::from(&content[..])
+        .verify_checksum()
+        .map_err(|(expected, actual)| Error::ChecksumMismatch { pid, expected, actual })?;
+
+        Ok(content.into())
+    }
+}
+
+/// Read-only view of the pages, as of a committed version.
+///
+/// The version is pinned as long as the snapshot lives. The snapshot does not borrow the pager,
+/// it can be sent to other threads while the pager keeps writing, if the storage is Sync.
+pub struct Snapshot<'pager, Pager> where Pager: Versioned {
+    pages: CommittedPages<'pager, Pager::Storage>,
+    version: u64,
+    roots: BTreeMap<String, Pager::Id>,
+    pht: PhantomData<fn() -> Pager::Type>
+}
+
+impl<'pager, Pager> Snapshot<'pager, Pager> where Pager: Versioned {
+    pub fn open(pager: &'pager Pager) -> Self {
+        let (version, roots) = pager.pin_snapshot();
+        Self { pages: pager.committed_pages(), version, roots, pht: PhantomData }
+    }
+
+    /// The committed version seen by the snapshot.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the page of a named root, as of the snapshot.
+    pub fn get_root(&self, name: &str) -> Option<Pager::Id> where Pager::Id: Copy {
+        self.roots.get(name).copied()
+    }
+
+    /// Returns the page, as of the snapshot.
+    pub fn borrow_page(&self, pid: &Pager::Id) -> Result<SnapshotPage<Pager::Id, Pager::Type>>
+    where Pager::Id: Copy + Into<u64>, Pager::Storage: PageStorage, <Pager::Storage as PageStorage>::Error: Into<Error> {
+        Ok(Page::from(self.pages.read_page_as_of((*pid).into(), self.version)?))
+    }
+}
+
+impl<'pager, Pager> Drop for Snapshot<'pager, Pager> where Pager: Versioned {
+    fn drop(&mut self) {
+        self.pages.release(self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::{fixtures, io::InMemory, paging::{page::PageSectionType, pager::{traits::{Pager, PageStorage}, BufPager, PageId}, storage::{FileStorage, PagerStream}, result::Result, error::Error}, utils::slice::IntoSection};
+
+    fn write_body<S>(pager: &BufPager<PageId, u8, S>, pid: PageId, value: u8) -> Result<()> where S: PageStorage, S::Error: Into<Error> {
+        pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().fill(value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 10);
+        let pid = pager.new_page(0x10)?;
+        write_body(&pager, pid, 1)?;
+        pager.set_root("main", pid);
+        pager.flush()?;
+
+        let first = pager.snapshot();
+        let read = |snapshot: &super::Snapshot<'_, _>| -> Result<u8> {
+            Ok(snapshot.borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0])
+        };
+
+        // Uncommitted modifications are not seen.
+        write_body(&pager, pid, 2)?;
+        pager.remove_root("main");
+        assert_eq!(read(&first)?, 1);
+        
+        pager.flush()?;
+        assert_eq!(read(&first)?, 1);
+        assert_eq!(first.get_root("main"), Some(pid));
+
+        let second = pager.snapshot();
+        write_body(&pager, pid, 3)?;
+        pager.flush()?;
+
+        assert_eq!(read(&first)?, 1);
+        assert_eq!(read(&second)?, 2);
+        assert_eq!(second.get_root("main"), None);
+        assert_eq!(pager.count_page_versions(), 2);
+
+        drop(first);
+        assert_eq!(pager.count_page_versions(), 1);
+        assert_eq!(read(&second)?, 2);
+
+        drop(second);
+        assert_eq!(pager.count_page_versions(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_write_back() -> Result<()> {
+        // Room for a single page, the modified page is written back before the flush.
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 1);
+        let pid = pager.new_page(0x10)?;
+        let other = pager.new_page(0x10)?;
+        write_body(&pager, pid, 1)?;
+        pager.flush()?;
+
+        let snapshot = pager.snapshot();
+        write_body(&pager, pid, 2)?;
+        write_body(&pager, other, 2)?;
+        write_body(&pager, pid, 3)?;
+        pager.flush()?;
+
+        // The committed content is kept once, not the written back one.
+        assert_eq!(pager.count_page_versions(), 2);
+        assert_eq!(snapshot.borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0], 1);
+        drop(snapshot);
+
+        // Without pinned snapshots, the committed content is not kept, the snapshots opened before the next commit cannot read the page.
+        write_body(&pager, pid, 4)?;
+        write_body(&pager, other, 4)?;
+        assert_eq!(pager.count_page_versions(), 0);
+        assert!(matches!(pager.snapshot().borrow_page(&pid), Err(Error::SnapshotUnavailable { .. })));
+
+        pager.flush()?;
+        assert_eq!(pager.snapshot().borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0], 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_across_threads() -> Result<()> {
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = BufPager::new(FileStorage::create(&path)?, 2);
+        let pid = pager.new_page(0x10)?;
+        write_body(&pager, pid, 1)?;
+        pager.flush()?;
+
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| -> Result<()> {
+            let mut readers = Vec::new();
+
+            for value in 1..20 {
+                if value > 1 {
+                    write_body(&pager, pid, value)?;
+                    pager.flush()?;
+                }
+
+                let (snapshot, done) = (pager.snapshot(), &done);
+
+                // Keep reading the version while the pager writes over the page, from this thread.
+                readers.push(scope.spawn(move || -> Result<()> {
+                    while !done.load(Ordering::Acquire) {
+                        assert_eq!(snapshot.borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0], value);
+                    }
+
+                    Ok(())
+                }));
+            }
+
+            done.store(true, Ordering::Release);
+            readers.into_iter().try_for_each(|reader| reader.join().unwrap())
+        })?;
+
+        assert_eq!(pager.count_page_versions(), 0);
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+}
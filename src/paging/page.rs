@@ -1,6 +1,7 @@
 use std::ops::Range;
 
-use crate::buffer::{BufArray, RefBufArray, RefMutBufArray};
+use crate::buffer::{BufArray, PinGuard, RefBufArray, RefMutBufArray};
+use crate::hash::{Crc32Hasher, traits::Hasher};
 use crate::utils::cell::TryCell;
 use crate::utils::slice::{Section, BorrowSection, CloneSection, BorrowMutSection, IntoSection};
 use crate::utils::borrow::TryBorrowMut;
@@ -15,8 +16,16 @@ pub const BPTREE_BRANCH: u8 = 0x3;
 /// Page sections
 const ID_RANGE: Range<usize> = 0..8;
 const TYPE_RANGE: Range<usize> = 8..9;
-const PARENT_RANGE: Range<usize> = 9..18;
-const RESERVED: usize = 18;
+const PARENT_RANGE: Range<usize> = 9..17;
+const CHECKSUM_RANGE: Range<usize> = 17..21;
+const LSN_RANGE: Range<usize> = 21..29;
+const VERSION_RANGE: Range<usize> = 29..37;
+const RESERVED: usize = 37;
+
+/// Size of the page header
+pub const PAGE_HEADER_SIZE: usize = RESERVED;
+/// Offset of the type in the page header
+pub const PAGE_TYPE_OFFSET: usize = TYPE_RANGE.start;
 
 pub mod traits 
 {  
@@ -61,6 +70,31 @@ fn get_type(content: &[u8]) -> u8 {
 fn get_parent(content: &[u8]) -> u64 {
     u64::from_le_bytes(content[PARENT_RANGE].try_into().unwrap())
 }
+fn get_checksum(content: &[u8]) -> u32 {
+    u32::from_le_bytes(content[CHECKSUM_RANGE].try_into().unwrap())
+}
+fn set_checksum(content: &mut [u8], checksum: u32) {
+    content[CHECKSUM_RANGE].copy_from_slice(&checksum.to_le_bytes())
+}
+fn get_lsn(content: &[u8]) -> u64 {
+    u64::from_le_bytes(content[LSN_RANGE].try_into().unwrap())
+}
+fn set_lsn(content: &mut [u8], lsn: u64) {
+    content[LSN_RANGE].copy_from_slice(&lsn.to_le_bytes())
+}
+fn get_version(content: &[u8]) -> u64 {
+    u64::from_le_bytes(content[VERSION_RANGE].try_into().unwrap())
+}
+fn set_version(content: &mut [u8], version: u64) {
+    content[VERSION_RANGE].copy_from_slice(&version.to_le_bytes())
+}
+/// Checksum of the whole page, the checksum field excluded.
+fn compute_checksum(content: &[u8]) -> u32 {
+    let mut hasher = Crc32Hasher::new();
+    hasher.update(&content[..CHECKSUM_RANGE.start]);
+    hasher.update(&content[CHECKSUM_RANGE.end..]);
+    hasher.finalize().into()
+}
 
 #[derive(Clone)]
 pub struct Page<'a, Id, Type, Data>(Data, std::marker::PhantomData<&'a (Id, Type)>);
@@ -83,8 +117,8 @@ impl<'a, Id, Type, DataCell> TryCell for Page<'a, Id, Type, DataCell> where Data
         Ok(Page::from(self.0.try_borrow_mut()?))
     }
 }
-impl<'a, Id, Type, DataCell> Page<'a, Id, Type, DataCell> where DataCell: TryBorrowMut<'a, [u8]> {
-    pub fn try_new(pid: u64, ptype: u8, data: DataCell) -> std::result::Result<Self, DataCell::Error> {
+impl<'a, Id, Type, DataCell> Page<'a, Id, Type, DataCell> where DataCell: TryBorrowMut<'a, [u8]>, Id: Into<u64>, Type: Into<u8> {
+    pub fn try_new(pid: Id, ptype: Type, data: DataCell) -> std::result::Result<Self, DataCell::Error> {
         let mut pg = Self(data, Default::default());
         
         {
@@ -103,17 +137,17 @@ impl<'a, Id, Type, Data> TraitPage for Page<'a, Id, Type, Data>{
     type Type = Type;
 }
 
-impl<'a, Id, Type, Data> ReadPage for Page<'a, Id, Type, Data> where Data: AsRef<[u8]> {
+impl<'a, Id, Type, Data> ReadPage for Page<'a, Id, Type, Data> where Data: AsRef<[u8]>, Id: From<u64>, Type: From<u8> {
     fn get_id(&self) -> Id {
-        get_id(self.0.as_ref())
+        get_id(self.0.as_ref()).into()
     }
 
     fn get_type(&self) -> Type {
-        get_type(self.0.as_ref())
+        get_type(self.0.as_ref()).into()
     }
 
     fn get_parent(&self) -> Id {
-        get_parent(self.0.as_ref())
+        get_parent(self.0.as_ref()).into()
     }
 
     fn get_size(&self) -> usize {
@@ -121,25 +155,85 @@ impl<'a, Id, Type, Data> ReadPage for Page<'a, Id, Type, Data> where Data: AsRef
     }
 }
 
+impl<'a, Id, Type, Data> Page<'a, Id, Type, Data> {
+    /// Release the page, and returns its content.
+    pub fn into_data(self) -> Data {
+        self.0
+    }
+}
+
+impl<'a, Id, Type, Data> Page<'a, Id, Type, Data> where Data: AsRef<[u8]> {
+    /// Check the stored checksum against the content of the page.
+    /// Returns the (expected, actual) checksums on mismatch.
+    pub fn verify_checksum(&self) -> std::result::Result<(), (u32, u32)> {
+        let expected = get_checksum(self.0.as_ref());
+        let actual = compute_checksum(self.0.as_ref());
+
+        if expected == actual {
+            Ok(())
+        } else {
+            Err((expected, actual))
+        }
+    }
+
+    /// Checksum stored in the header.
+    pub fn get_checksum(&self) -> u32 {
+        get_checksum(self.0.as_ref())
+    }
+
+    /// Log sequence number of the last logged write of the page.
+    pub fn get_lsn(&self) -> u64 {
+        get_lsn(self.0.as_ref())
+    }
+
+    /// Number of times the page was stored, to detect the writes of others.
+    pub fn get_version(&self) -> u64 {
+        get_version(self.0.as_ref())
+    }
+}
+
 impl<'a, Id, Type, Data> Page<'a, Id, Type, Data> where Data: AsMut<[u8]> + AsRef<[u8]> {
-    fn new(pid: Id, ptype: Type, data: Data) -> Self {
+    /// Compute the checksum of the page, and store it in the header.
+    pub fn update_checksum(&mut self) {
+        let checksum = compute_checksum(self.0.as_ref());
+        set_checksum(self.0.as_mut(), checksum);
+    }
+
+    pub fn set_lsn(&mut self, lsn: u64) {
+        set_lsn(self.0.as_mut(), lsn)
+    }
+
+    pub fn set_version(&mut self, version: u64) {
+        set_version(self.0.as_mut(), version)
+    }
+}
+
+impl<'a, Id, Type, Data> Page<'a, Id, Type, Data> where Data: AsMut<[u8]> + AsRef<[u8]>, Id: Into<u64>, Type: Into<u8> {
+    pub fn new(pid: Id, ptype: Type, data: Data) -> Self {
         let mut page = Self(data, Default::default());
         page.set_id(pid);
         page.set_type(ptype);
         page
     }
 }
-impl<'a, Id, Type, Data> WritePage for Page<'a, Id, Type, Data> where Data: AsMut<[u8]> + AsRef<[u8]> {
+impl<'a, Id, Type, Data> WritePage for Page<'a, Id, Type, Data> where Data: AsMut<[u8]> + AsRef<[u8]>, Id: Into<u64>, Type: Into<u8> {
     fn set_id(&mut self, pid: Id) {
-        set_id(&mut self.0.as_mut(), pid)
+        set_id(self.0.as_mut(), pid.into())
     }
 
     fn set_type(&mut self, ptype: Type) {
-        set_type(&mut self.0.as_mut(), ptype)
+        set_type(self.0.as_mut(), ptype.into())
     }
 
     fn set_parent(&mut self, parent: Id) {
-        set_parent(&mut self.0.as_mut(), parent)
+        set_parent(self.0.as_mut(), parent.into())
+    }
+
+    /// Only the id and the version are kept, the rest of the page is zeroed (type 0x00 is a free page).
+    fn drop(&mut self) {
+        let content = self.0.as_mut();
+        content[TYPE_RANGE.start..VERSION_RANGE.start].fill(0);
+        content[VERSION_RANGE.end..].fill(0);
     }
 }
 
@@ -205,6 +299,20 @@ pub type RefBufPage<'buffer, Id, Type> = Page<'buffer, Id, Type, RefBufArray<'bu
 pub type RefMutPage<'buffer, Id, Type> = Page<'buffer, Id, Type, RefMutBufArray<'buffer, u8>>;
 
 impl<'buffer, Id, Type> BufPage<'buffer, Id, Type> {
+    /// Read the id of the page, regardless of its borrow state.
+    pub fn peek_id(&self) -> Id where Id: From<u64> {
+        unsafe {
+            get_id(self.0.as_slice_unchecked()).into()
+        }
+    }
+
+    /// Read the type of the page, regardless of its borrow state.
+    pub fn peek_type(&self) -> u8 {
+        unsafe {
+            get_type(self.0.as_slice_unchecked())
+        }
+    }
+
     pub fn is_upserted(&self) -> bool {
         self.0.is_upserted()
     }
@@ -212,6 +320,11 @@ impl<'buffer, Id, Type> BufPage<'buffer, Id, Type> {
     pub fn ack_upsertion(&mut self) {
         self.0.ack_upsertion()
     }
+
+    /// Keep the page from being evicted, as long as the guard is held.
+    pub fn pin(&self) -> PinGuard<'buffer> {
+        self.0.pin()
+    }
 }
 
 pub enum PageSectionType {
@@ -224,7 +337,7 @@ mod tests {
     use std::io::{Write, Read};
 
     use crate::{io::Data, fixtures, paging::page::PageSectionType, utils::slice::BorrowMutSection};
-    use super::Page;
+    use super::{Page, traits::WritePage};
 
     #[test]
     pub fn test_page() -> std::io::Result<()> {
@@ -249,4 +362,17 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_page_checksum() {
+        let mut area: [u8; 1000] = [0; 1000];
+        fixtures::randomise(&mut area);
+
+        let mut page = Page::new(1u64, 1u8, &mut area[..]);
+        page.update_checksum();
+        assert_eq!(page.verify_checksum(), Ok(()));
+
+        page.set_parent(2);
+        assert!(page.verify_checksum().is_err());
+    }
 }
\ No newline at end of file
@@ -1,10 +1,10 @@
-use std::{io::{Read, Write, Seek, SeekFrom}, ops::DerefMut};
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, sync::{Arc, Mutex}, time::Instant};
 
-use crate::{buffer::{Buffer, BufCellIterator}, utils::{Counter, cell::TryCell, slice::{IntoSection, CloneSection}, borrow::TryBorrowMut}};
+use crate::{buffer::{Buffer, BufCellIterator, PinGuard, WriteBack, budget::MemoryBudget, eviction::EvictionPolicy}, utils::{Counter, cell::TryCell, slice::IntoSection, borrow::TryBorrowMut}};
 
 use self::traits::PageStorage;
 
-use super::{page::{BufPage, PageSectionType, traits::Page, RefBufPage, RefMutPage}, error::Error, result::Result};
+use super::{page::{BufPage, PageSectionType, traits::{Page, ReadPage, WritePage}, RefBufPage, RefMutPage}, error::Error, result::Result, superblock::{Superblock, SUPERBLOCK_PAGE}, transaction::{Transaction, UndoLog, traits::Transactional}, mvcc::{CommittedPages, Snapshot, VersionStore, traits::Versioned}, stats::{PagerStats, traits::Observer}, allocator::{FreeList, traits::{PageAccess, PageAllocator}}};
 
 pub type PageId = u64;
 
@@ -15,8 +15,35 @@ pub mod traits {
     pub trait PageStorage {
         type Error;
 
-        fn store<Id: AsRef<str>, Data: AsRef<u8>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error>;
-        fn fetch<Id: AsRef<str>, DataReceiver: AsMut<u8>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error>;
+        /// Store the whole content of the page.
+        fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error>;
+        /// Fetch the whole content of the page into the receiver.
+        fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error>;
+        /// Fetch the contiguous pages starting at first, split by page_size in the receiver, in a single read if the storage allows it.
+        fn fetch_many(&self, first: u64, page_size: usize, data: &mut [u8]) -> std::result::Result<(), Self::Error> {
+            for (i, mut page) in data.chunks_mut(page_size).enumerate() {
+                self.fetch(first + i as u64, &mut page)?;
+            }
+
+            Ok(())
+        }
+        /// Ensure that all stored pages reached the underlying device.
+        fn sync(&self) -> std::result::Result<(), Self::Error>;
+        /// Release the storage beyond len bytes, storages unable to shrink keep it.
+        fn truncate(&self, _len: u64) -> std::result::Result<(), Self::Error> {
+            Ok(())
+        }
+        /// The stored pages are logged, and reach the storage only once synced, so a crash before the sync discards them.
+        fn is_logged(&self) -> bool {
+            false
+        }
+        /// Exclude the other writers of the storage until unlock_commit, storages with a single writer need no lock.
+        fn lock_commit(&self) -> std::result::Result<(), Self::Error> {
+            Ok(())
+        }
+        fn unlock_commit(&self) -> std::result::Result<(), Self::Error> {
+            Ok(())
+        }
     }
 
     pub trait Pager<'a> {
@@ -39,14 +66,28 @@ pub mod traits {
 
         /// Flush upserted pages into the stream
         fn flush(&self) -> Result<(), Self::Error>;
+
+        /// Size of the pages, in bytes
+        fn get_page_size(&self) -> usize;
     }
 }
 
-pub const PAGE_SIZE: usize = 16_000;
+pub const DEFAULT_PAGE_SIZE: usize = 16_384;
+pub const MIN_PAGE_SIZE: usize = 4_096;
+pub const MAX_PAGE_SIZE: usize = 65_536;
 pub const RESERVED: usize = 10;
 pub const FREE_PAGE: u8 = 0x00;
 pub const OVERFLOW_PAGE: u8 = 0xFF;
 
+/// Page sizes must be a power of two, between MIN_PAGE_SIZE and MAX_PAGE_SIZE.
+fn check_page_size(page_size: u64) -> Result<()> {
+    if page_size.is_power_of_two() && (MIN_PAGE_SIZE as u64..=MAX_PAGE_SIZE as u64).contains(&page_size) {
+        Ok(())
+    } else {
+        Err(Error::InvalidPageSize(page_size))
+    }
+}
+
 pub struct BufPageIterator<'buffer, Page> {
     cells: BufCellIterator<'buffer>,
     pht: std::marker::PhantomData<Page>
@@ -74,104 +115,1098 @@ impl<'buffer, Id, Type> Iterator for BufPageIterator<'buffer, BufPage<'buffer, I
 }
 
 pub struct Pager<'buffer, Page, Storage>
-where Storage: PageStorage, Page: crate::paging::page::traits::Page, Page::Id: std::ops::AddAssign<u8>
+where Storage: PageStorage, Page: crate::paging::page::traits::Page
 {
     pool: Buffer, 
     store: Storage, 
+    page_size: usize,
     counter: Counter<Page::Id>, 
+    freelist: Cell<Option<Page::Id>>,
+    roots: RefCell<BTreeMap<String, Page::Id>>,
+    undo: RefCell<Option<UndoLog<Page::Id>>>,
+    /// Last committed version, and the state of the pager at that point.
+    version: Cell<u64>,
+    committed_last_page_id: Cell<Page::Id>,
+    committed_roots: RefCell<BTreeMap<String, Page::Id>>,
+    /// Superblock last read or written, another writer modified the database if the stored one differs.
+    committed_superblock: RefCell<Option<Superblock>>,
+    /// Check the versions of the stored pages before overwriting them, under the commit lock of the storage.
+    detect_conflicts: bool,
+    /// Versions of the stored pages whose creation was rolled back, to create them again.
+    released: RefCell<BTreeMap<u64, u64>>,
+    /// Shared with the snapshots, which may read it from other threads.
+    versions: Mutex<VersionStore>,
+    stats: RefCell<PagerStats>,
+    observer: Option<Box<dyn Observer>>,
+    allocator: Box<dyn PageAllocator>,
     pht: std::marker::PhantomData<&'buffer ()>
 }
 
 pub type BufPager<'buffer, Id, Type, Storage> =  Pager<'buffer, BufPage<'buffer, Id, Type>, Storage>;
 
-impl<'buffer, Id, Type, Storage> self::traits::Pager<'buffer> for BufPager<'buffer, Id, Type, Storage> where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign<u8>
+impl<'a, 'buffer, Id, Type, Storage> self::traits::Pager<'a> for BufPager<'buffer, Id, Type, Storage> 
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: 'a + std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: 'a + From<u8> + Into<u8>
 {
     type Error = Error;
-    type RefPage = RefBufPage<'buffer, Id, Type>;
-    type RefMutPage = RefMutPage<'buffer, Id, Type>;
+    type RefPage = RefBufPage<'a, Id, Type>;
+    type RefMutPage = RefMutPage<'a, Id, Type>;
 
-    fn new_page(&'buffer self, ptype: <Self::RefPage as Page>::Type) -> std::result::Result<<Self::RefPage as Page>::Id, Self::Error> {
-        let data = self.pool.alloc_array_uninit::<u8>(PAGE_SIZE)?;
-        let pid = self.counter.inc();
-        let page = BufPage::try_new(pid, ptype, data)?;
-        Ok(pid)
+    fn new_page(&'a self, ptype: <Self::RefPage as Page>::Type) -> std::result::Result<<Self::RefPage as Page>::Id, Self::Error> {
+        match self.allocator.alloc(self)? {
+            Some(pid) => self.reuse_page(pid, ptype),
+            None => self.create_page(ptype)
+        }
     }
 
-    fn borrow_page(&'buffer self, pid: &<Self::RefPage as Page>::Id) -> std::result::Result<Self::RefPage, Self::Error> {
-        todo!()
+    fn borrow_page(&'a self, pid: &<Self::RefPage as Page>::Id) -> std::result::Result<Self::RefPage, Self::Error> {
+        Ok(self.get_page(pid)?.try_borrow()?)
     }
 
-    fn borrow_mut_page(&'buffer self, pid: &<Self::RefMutPage as Page>::Id) -> std::result::Result<Self::RefMutPage, Self::Error> {
-        todo!()
+    fn borrow_mut_page(&'a self, pid: &<Self::RefMutPage as Page>::Id) -> std::result::Result<Self::RefMutPage, Self::Error> {
+        let mut page = self.get_page(pid)?;
+        self.record_pre_image(&page)?;
+        Ok(page.try_borrow_mut()?)
     }
 
+    /// The superblock, free pages and pages beyond the last one cannot be dropped, as they would corrupt the free pages list.
     fn drop_page(&self, pid: &<Self::RefPage as Page>::Id) -> std::result::Result<(), Self::Error> {
-        todo!()
+        let key: u64 = (*pid).into();
+
+        if key == SUPERBLOCK_PAGE || key > self.counter.get().into() {
+            return Err(Error::NotDroppable { pid: key });
+        }
+
+        let mut page = self.get_page(pid)?;
+
+        if Into::<u8>::into(page.peek_type()) == FREE_PAGE {
+            return Err(Error::NotDroppable { pid: key });
+        }
+
+        self.record_pre_image(&page)?;
+        WritePage::drop(&mut page.try_borrow_mut()?);
+        self.allocator.free(self, (*pid).into())
     }
 
     fn flush(&self) -> std::result::Result<(), Self::Error> {
-        todo!()
+        self.flush_pages(&|_| true)
+    }
+
+    fn get_page_size(&self) -> usize {
+        self.page_size
     }
 }
 
 impl<'buffer, Page, Storage> Pager<'buffer, Page, Storage>
-where Storage: PageStorage, Page: crate::paging::page::traits::Page
+where Storage: PageStorage, Page: crate::paging::page::traits::Page, Page::Id: Default
 {
-    /// Create a pager
-    /// io: The stream to read and write into
+    /// Create a pager over a new database, with the default page size.
+    /// store: The storage to fetch and store pages from/into
     /// buffer_size: number of pages that can be stored in memory
     pub fn new(store: Storage, buffer_size: usize) -> Self {
-        Self {
+        Self::with_page_size(store, DEFAULT_PAGE_SIZE, buffer_size).unwrap()
+    }
+
+    /// Create a pager over a new database.
+    /// store: The storage to fetch and store pages from/into
+    /// page_size: size of a page, a power of two between MIN_PAGE_SIZE and MAX_PAGE_SIZE
+    /// buffer_size: number of pages that can be stored in memory
+    pub fn with_page_size(store: Storage, page_size: usize, buffer_size: usize) -> Result<Self> {
+        check_page_size(page_size as u64)?;
+
+        Ok(Self {
             store,
-            pool: Buffer::new_by_array::<u8>(PAGE_SIZE, buffer_size),
+            page_size,
+            pool: Buffer::new_by_array::<u8>(page_size, buffer_size),
             counter: Default::default(),
+            freelist: Default::default(),
+            roots: Default::default(),
+            undo: Default::default(),
+            version: Default::default(),
+            committed_last_page_id: Default::default(),
+            committed_roots: Default::default(),
+            committed_superblock: Default::default(),
+            detect_conflicts: false,
+            released: Default::default(),
+            versions: Default::default(),
+            stats: Default::default(),
+            observer: None,
+            allocator: Box::new(FreeList::new()),
+            pht: Default::default()
+        })
+    }
+
+    /// Select the policy evicting the pages from the buffer, pages are evicted by a CLOCK by default.
+    pub fn with_eviction_policy(self, policy: impl EvictionPolicy + 'static) -> Self {
+        self.pool.set_eviction_policy(Box::new(policy));
+        self
+    }
+
+    /// Register the buffer to the memory budget, shared with the buffers of other pagers.
+    pub fn with_budget(self, budget: &Arc<MemoryBudget>) -> Result<Self> {
+        self.pool.join_budget(budget)?;
+        Ok(self)
+    }
+
+    /// Select the allocator reusing the dropped pages, they are stacked in a free pages list by default.
+    pub fn with_allocator(mut self, allocator: impl PageAllocator + 'static) -> Self {
+        self.allocator = Box::new(allocator);
+        self
+    }
+
+    /// Reject the flushes and write-backs overwriting pages, or a superblock, stored by another writer since they were read.
+    /// Each flushed page is read back from the storage to compare its version, under the commit lock of the storage.
+    pub fn with_conflict_detection(mut self) -> Self {
+        self.detect_conflicts = true;
+        self
+    }
+
+    /// Notify the observer of the activity of the pager.
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Snapshot of the activity of the pager, and of its buffer.
+    pub fn stats(&self) -> PagerStats {
+        let mut stats = self.stats.borrow().clone();
+        stats.buffer = self.pool.stats();
+        stats
+    }
+
+    /// Release the pager, and returns its storage.
+    pub fn into_storage(self) -> Storage {
+        self.store
+    }
+}
+
+impl<'buffer, Id, Type, Storage> BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    /// Open a pager over an existing database, and restore its state from the superblock.
+    /// store: The storage to fetch and store pages from/into, it holds the lock of the database (see FileStorage::open_locked)
+    /// buffer_size: number of pages that can be stored in memory
+    pub fn open(store: Storage, buffer_size: usize) -> Result<Self> {
+        // The page size is not known yet, but is stored at the beginning of the superblock.
+        let mut content = vec![0u8; MIN_PAGE_SIZE];
+        store.fetch(SUPERBLOCK_PAGE, &mut content).map_err(Into::<Error>::into)?;
+        let page_size = Superblock::read_page_size(&content)?;
+        check_page_size(page_size)?;
+
+        let page_size = page_size as usize;
+
+        let pager = Self {
+            store,
+            page_size,
+            pool: Buffer::new_by_array::<u8>(page_size, buffer_size),
+            counter: Counter::new(Id::from(0u8)),
+            freelist: Default::default(),
+            roots: Default::default(),
+            undo: Default::default(),
+            version: Default::default(),
+            committed_last_page_id: Cell::new(Id::from(0u8)),
+            committed_roots: Default::default(),
+            committed_superblock: Default::default(),
+            detect_conflicts: false,
+            released: Default::default(),
+            versions: Default::default(),
+            stats: Default::default(),
+            observer: None,
+            allocator: Box::new(FreeList::new()),
             pht: Default::default()
+        };
+
+        pager.load_superblock()?;
+        Ok(pager)
+    }
+
+    /// Restore the state of the pager from the stored superblock.
+    fn load_superblock(&self) -> Result<()> {
+        let mut content = vec![0u8; self.page_size];
+        self.store.fetch(SUPERBLOCK_PAGE, &mut content).map_err(Into::<Error>::into)?;
+        let superblock = Superblock::read(&content)?;
+
+        let last_page_id = Id::from(superblock.next_page_id.checked_sub(1).ok_or(Error::CorruptedSuperblock)?);
+        let roots: BTreeMap<String, Id> = superblock.roots.iter().map(|(name, pid)| (name.clone(), Id::from(*pid))).collect();
+
+        self.counter.set(last_page_id);
+        self.freelist.set(superblock.freelist_head.map(Id::from));
+        self.roots.replace(roots.clone());
+        self.committed_last_page_id.set(last_page_id);
+        self.committed_roots.replace(roots);
+        self.committed_superblock.replace(Some(superblock));
+        self.allocator.invalidate();
+        Ok(())
+    }
+
+    /// Discard the buffered pages, modified or not, and reload the state of the database from the superblock,
+    /// to resume after a conflict with another writer. The modifications not flushed yet are lost.
+    pub fn refresh(&self) -> Result<()> {
+        if self.undo.borrow().is_some() {
+            return Err(Error::TransactionInProgress);
+        }
+
+        // The snapshots may still read the pages.
+        if self.versions.lock().unwrap().is_pinned() {
+            return Err(Error::SnapshotPinned);
+        }
+
+        let pids: Vec<u64> = self.iter().map(|page| page.peek_id().into()).collect();
+
+        for pid in pids {
+            if let Some(mut page) = self.lookup_page(&Id::from(pid)) {
+                page.try_borrow_mut()?.into_section(PageSectionType::All).as_mut().fill(0);
+                self.pool.remove(pid);
+                page.ack_upsertion();
+            }
+        }
+
+        self.released.borrow_mut().clear();
+        self.versions.lock().unwrap().commit();
+        self.load_superblock()
+    }
+
+    /// Return the state of the pager to persist.
+    pub fn superblock(&self) -> Superblock {
+        let mut superblock = Superblock::new(self.page_size as u64);
+        superblock.next_page_id = self.counter.get().into() + 1;
+        superblock.freelist_head = self.freelist.get().map(Into::into);
+        superblock.roots = self.roots.borrow().iter().map(|(name, pid)| (name.clone(), (*pid).into())).collect();
+        superblock
+    }
+
+    /// Head of the free pages list.
+    pub fn get_freelist_head(&self) -> Option<Id> {
+        self.freelist.get()
+    }
+
+    pub fn set_freelist_head(&self, head: Option<Id>) {
+        self.freelist.set(head)
+    }
+
+    /// Get the page of a named root.
+    pub fn get_root(&self, name: &str) -> Option<Id> {
+        self.roots.borrow().get(name).copied()
+    }
+
+    /// Register a named root.
+    pub fn set_root(&self, name: impl Into<String>, pid: Id) {
+        self.roots.borrow_mut().insert(name.into(), pid);
+    }
+
+    /// Named roots, by name.
+    pub fn get_roots(&self) -> BTreeMap<String, Id> {
+        self.roots.borrow().clone()
+    }
+
+    /// Unregister a named root.
+    pub fn remove_root(&self, name: &str) -> Option<Id> {
+        self.roots.borrow_mut().remove(name)
+    }
+
+    /// The page is held in the buffer.
+    pub fn is_buffered(&self, pid: &Id) -> bool {
+        self.lookup_page(pid).is_some()
+    }
+
+    /// Resize the buffer to hold buffer_size pages, dirty pages are written back to be evicted.
+    pub fn resize_buffer(&self, buffer_size: usize) -> Result<()> {
+        Ok(self.pool.resize_with_write_back(buffer_size, self)?)
+    }
+
+    /// Give back the memory beyond the target set by the budget, dirty pages are written back to be evicted.
+    pub fn trim_buffer(&self) -> Result<()> {
+        Ok(self.pool.trim_with_write_back(self)?)
+    }
+
+    /// Pages known to the allocator as free.
+    pub fn free_pages(&self) -> Result<Vec<u64>> {
+        self.allocator.free_pages(self)
+    }
+
+    /// Replace the free pages known to the allocator.
+    pub fn rebuild_free_pages(&self, free: &[u64]) -> Result<()> {
+        self.allocator.rebuild(self, free)
+    }
+
+    /// Discard the pages beyond last, flush, and release their room in the storage.
+    /// The pages must be free, and no longer referenced, their ids will be reused.
+    pub fn truncate(&self, last: Id) -> Result<()> where Type: From<u8> + Into<u8> {
+        if self.undo.borrow().is_some() {
+            return Err(Error::TransactionInProgress);
+        }
+
+        // The snapshots may still read the pages.
+        if self.versions.lock().unwrap().is_pinned() {
+            return Err(Error::SnapshotPinned);
+        }
+
+        for pid in last.into() + 1..=self.counter.get().into() {
+            if let Some(mut page) = self.lookup_page(&Id::from(pid)) {
+                self.pool.remove(pid);
+                page.try_borrow_mut()?.into_section(PageSectionType::All).as_mut().fill(0);
+                page.ack_upsertion();
+            }
+        }
+
+        self.counter.set(last);
+        self.released.borrow_mut().retain(|pid, _| *pid <= last.into());
+        self.allocator.invalidate();
+        self::traits::Pager::flush(self)?;
+
+        self.store.truncate((last.into() + 1) * self.page_size as u64).map_err(Into::<Error>::into)?;
+        self.store.sync().map_err(Into::<Error>::into)
+    }
+
+    /// Hint that the pages are about to be read, the ones not buffered yet are fetched by runs of contiguous ids, each in a single read.
+    /// Only the free room of the buffer is filled, a hint does not evict the buffered pages, and the pages failing their checksum are left to be reported on access.
+    /// The fetched pages are accounted as faults. Returns the number of fetched pages.
+    pub fn prefetch(&self, pids: &[Id]) -> Result<usize> {
+        let last = self.counter.get().into();
+        let mut pids: Vec<u64> = pids.iter().map(|pid| (*pid).into()).filter(|pid| (1..=last).contains(pid)).collect();
+        pids.sort_unstable();
+        pids.dedup();
+        pids.retain(|pid| !self.is_buffered(&Id::from(*pid)));
+        pids.truncate(self.pool.free_capacity());
+
+        let mut fetched = 0;
+        let mut runs = pids.as_slice();
+
+        while let Some(&first) = runs.first() {
+            let len = runs.iter().enumerate().take_while(|(i, pid)| **pid == first + *i as u64).count();
+            fetched += self.prefetch_run(first, len)?;
+            runs = &runs[len..];
+        }
+
+        self.stats.borrow_mut().prefetched += fetched as u64;
+        Ok(fetched)
+    }
+
+    /// Hint that the count pages from the page are about to be read in sequence, as by a range scan or the read of a large blob.
+    pub fn prefetch_from(&self, from: &Id, count: usize) -> Result<usize> {
+        let from = (*from).into();
+        self.prefetch(&(from..from + count as u64).map(Id::from).collect::<Vec<_>>())
+    }
+
+    /// Fetch the len pages from the first one in a single read, and store them in the buffer.
+    fn prefetch_run(&self, first: u64, len: usize) -> Result<usize> {
+        let mut content = vec![0u8; len * self.page_size];
+        self.store.fetch_many(first, self.page_size, &mut content).map_err(Into::<Error>::into)?;
+        let mut fetched = 0;
+
+        for (pid, content) in (first..).zip(content.chunks(self.page_size)) {
+            if super::page::Page::<Id, Type, _>::from(content).verify_checksum().is_err() {
+                continue;
+            }
+
+            let mut data = self.pool.alloc_array_uninit_with_write_back::<u8>(self.page_size, self)?;
+            data.try_borrow_mut()?.copy_from_slice(content);
+            data.ack_upsertion();
+
+            // The pages were filtered on the buffer, and the pager is not shared between threads: none was faulted in since.
+            self.pool.insert(pid, data.raw());
+            fetched += 1;
+
+            let ptype = super::page::Page::<u64, u8, _>::from(content).get_type();
+            self.stats.borrow_mut().record_access(ptype, false);
+
+            if let Some(observer) = &self.observer {
+                observer.on_access(ptype, false);
+            }
+        }
+
+        Ok(fetched)
+    }
+
+    /// Keep the page in the buffer, as long as the guard is held.
+    pub fn pin_page(&self, pid: &Id) -> Result<PinGuard<'_>> {
+        Ok(self.get_page(pid)?.pin())
+    }
+
+    /// Create count pages with contiguous ids, and returns the first one.
+    /// Free pages are reused if the allocator finds a run of them, otherwise new pages are appended.
+    pub fn new_pages(&self, ptype: Type, count: usize) -> Result<Id> where Type: From<u8> + Into<u8> {
+        if count == 0 {
+            return Err(Error::EmptyRun);
+        }
+
+        let ptype: u8 = ptype.into();
+
+        match self.allocator.alloc_run(self, count)? {
+            Some(first) => {
+                for pid in first..first + count as u64 {
+                    self.reuse_page(pid, Type::from(ptype))?;
+                }
+
+                Ok(Id::from(first))
+            },
+            None => {
+                let first = self.create_page(Type::from(ptype))?;
+                
+                for _ in 1..count {
+                    self.create_page(Type::from(ptype))?;
+                }
+
+                Ok(first)
+            }
+        }
+    }
+
+    /// Append a new page.
+    fn create_page(&self, ptype: Type) -> Result<Id> where Type: Into<u8> {
+        let mut data = self.pool.alloc_array_uninit_with_write_back::<u8>(self.page_size, self)?;
+        // The block may have been reclaimed from another page.
+        data.try_borrow_mut()?.fill(0);
+
+        let pid = self.counter.inc();
+        self.pool.insert(pid.into(), data.raw());
+        let version = self.released.borrow_mut().remove(&pid.into()).unwrap_or(0);
+        BufPage::try_new(pid, ptype, data)?.try_borrow_mut()?.set_version(version);
+
+        if let Some(undo) = self.undo.borrow_mut().as_mut() {
+            undo.record_creation(pid);
         }
+
+        Ok(pid)
+    }
+
+    /// Reuse a free page handed out by the allocator.
+    fn reuse_page(&self, pid: u64, ptype: Type) -> Result<Id> where Type: Into<u8> {
+        let pid = Id::from(pid);
+        let mut page = self.get_page(&pid)?;
+        self.record_pre_image(&page)?;
+        
+        let mut content = page.try_borrow_mut()?;
+        WritePage::drop(&mut content);
+        content.set_type(ptype);
+        
+        Ok(pid)
+    }
+
+    /// Begin a transaction, the modifications made through it are discarded unless it is committed.
+    pub fn begin(&self) -> Result<Transaction<'_, Self>> where Type: From<u8> + Into<u8> {
+        Transaction::begin(self)
+    }
+
+    /// Open a read-only snapshot of the last committed version.
+    pub fn snapshot(&self) -> Snapshot<'_, Self> where Type: From<u8> + Into<u8> {
+        Snapshot::open(self)
+    }
+
+    /// Number of page versions kept for the pinned snapshots.
+    pub fn count_page_versions(&self) -> usize {
+        self.versions.lock().unwrap().len()
     }
 
     /// Return an iterator over upserted pages.
-    pub fn iter_upserted_pages(&self) -> impl Iterator<Item=Page> {
+    pub fn iter_upserted_pages(&self) -> impl Iterator<Item=BufPage<'_, Id, Type>> {
         self.iter().filter(|page| page.is_upserted())
     }
 
     /// Iterate over in memory pages
-    pub fn iter(&self) -> impl Iterator<Item=BufPage> {
+    pub fn iter(&self) -> BufPageIterator<'_, BufPage<'_, Id, Type>> {
         BufPageIterator::new(self.pool.iter())
     }
 
+    /// Get the page from the buffer, or fetch it from the storage.
+    fn get_page(&self, pid: &Id) -> Result<BufPage<'_, Id, Type>> {
+        let (page, hit) = match self.lookup_page(pid) {
+            Some(page) => (page, true),
+            None => (self.fetch_page(pid)?, false)
+        };
+
+        self.stats.borrow_mut().record_access(page.peek_type(), hit);
+        
+        if let Some(observer) = &self.observer {
+            observer.on_access(page.peek_type(), hit);
+        }
+
+        Ok(page)
+    }
+
+    /// Get the page from the buffer.
+    fn lookup_page(&self, pid: &Id) -> Option<BufPage<'_, Id, Type>> {
+        self.pool
+        .get((*pid).into())
+        .and_then(|cell| cell.try_into_array::<u8>())
+        .map(BufPage::from)
+    }
+
+    /// Keep the content of the page before its first modification within the pending transaction.
+    fn record_pre_image(&self, page: &BufPage<'_, Id, Type>) -> Result<()> {
+        if let Some(undo) = self.undo.borrow_mut().as_mut() {
+            let pid = page.peek_id();
+
+            if !undo.contains(&pid) {
+                let content = page.try_borrow()?.into_section(PageSectionType::All).as_ref().to_vec();
+                undo.record(pid, content);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the committed content of the page for the snapshots, before it is overwritten in the storage.
+    fn preserve_committed(&self, pid: &Id) -> Result<()> {
+        // New pages have no committed content, and the committed content is kept once per version.
+        let (key, version) = ((*pid).into(), self.version.get());
+
+        // The lock is held until the content is kept, the snapshots read the storage meanwhile.
+        let mut versions = self.versions.lock().unwrap();
+
+        if key <= self.committed_last_page_id.get().into() && !versions.contains(key, version) {
+            let mut committed = vec![0u8; self.page_size];
+            self.fetch_into(pid, &mut committed)?;
+            versions.push(key, version, committed.into());
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the page from the storage, and store it in the buffer.
+    fn fetch_page(&self, pid: &Id) -> Result<BufPage<'_, Id, Type>> {
+        let mut data = self.pool.alloc_array_uninit_with_write_back::<u8>(self.page_size, self)?;
+        let fetched = data.try_borrow_mut().map_err(Error::from).and_then(|mut content| self.fetch_into(pid, &mut content));
+        
+        if fetched.is_err() {
+            // Do not leave a partially fetched, or corrupted, page behind.
+            data.try_borrow_mut()?.fill(0);
+        }
+
+        data.ack_upsertion();
+        fetched?;
+
+        // The pager is not shared between threads, and the write-backs of the allocation only read the storage: the page was not faulted in since the lookup.
+        self.pool.insert((*pid).into(), data.raw());
+        Ok(BufPage::from(data))
+    }
+
+    /// Fetch the content of the page from the storage, and check its integrity.
+    fn fetch_into<Data: AsMut<[u8]> + AsRef<[u8]>>(&self, pid: &Id, data: &mut Data) -> Result<()> {
+        self.store.fetch(*pid, data).map_err(Into::<Error>::into)?;
+        
+        super::page::Page::<Id, Type, _>::from(data.as_ref())
+        .verify_checksum()
+        .map_err(|(expected, actual)| Error::ChecksumMismatch { pid: (*pid).into(), expected, actual })
+    }
+
+    /// Version of the page in the storage, zero if it was never stored.
+    fn stored_version(&self, pid: &Id) -> Result<u64> {
+        let mut content = vec![0u8; self.page_size];
+
+        match self.store.fetch(*pid, &mut content) {
+            // Room left in the storage, but never written.
+            Ok(()) if content.iter().all(|byte| *byte == 0) => Ok(0),
+            Ok(()) => {
+                let page = super::page::Page::<Id, Type, _>::from(&content[..]);
+                page.verify_checksum().map_err(|(expected, actual)| Error::ChecksumMismatch { pid: (*pid).into(), expected, actual })?;
+                Ok(page.get_version())
+            },
+            // Pages created since the last flush may not be stored yet.
+            Err(_) if (*pid).into() > self.committed_last_page_id.get().into() => Ok(0),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    /// Check that the page was not stored by another writer since it was read at the version.
+    fn check_version(&self, pid: &Id, version: u64) -> Result<()> {
+        let stored = self.stored_version(pid)?;
+
+        if stored != version {
+            return Err(Error::Conflict { pid: (*pid).into(), expected: version, found: stored });
+        }
+
+        Ok(())
+    }
+
+    /// Check that the superblock was not stored by another writer since it was read.
+    fn check_superblock(&self) -> Result<()> {
+        let mut content = vec![0u8; self.page_size];
+
+        let stored = match self.store.fetch(SUPERBLOCK_PAGE, &mut content) {
+            Ok(()) => Superblock::read(&content).ok(),
+            Err(_) => None
+        };
+
+        if stored != *self.committed_superblock.borrow() {
+            return Err(Error::SuperblockConflict);
+        }
+
+        Ok(())
+    }
+
+    /// Run the stores under the commit lock of the storage, if the conflicts are detected,
+    /// so that no other writer stores pages between their check and their store.
+    fn with_commit_lock<T>(&self, stores: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.detect_conflicts {
+            return stores();
+        }
+
+        self.store.lock_commit().map_err(Into::<Error>::into)?;
+        let result = stores();
+        self.store.unlock_commit().map_err(Into::<Error>::into)?;
+        result
+    }
+
+    /// Store the selected upserted pages, and the superblock.
+    /// Nothing is written if another writer modified the database since it was read, when the conflicts are detected.
+    fn flush_pages(&self, selected: &dyn Fn(u64) -> bool) -> Result<()> {
+        self.with_commit_lock(|| {
+            if self.detect_conflicts {
+                for page in self.iter_upserted_pages().filter(|page| selected(page.peek_id().into())) {
+                    self.check_version(&page.peek_id(), page.try_borrow()?.get_version())?;
+                }
+
+                self.check_superblock()?;
+            }
+
+            self.store_pages(selected)
+        })
+    }
+
+    fn store_pages(&self, selected: &dyn Fn(u64) -> bool) -> Result<()> {
+        let start = Instant::now();
+        let version = self.version.get();
+        let preserve = self.versions.lock().unwrap().is_pinned();
+        let mut flushed = 0;
+
+        for mut page in self.iter_upserted_pages().filter(|page| selected(page.peek_id().into())) {
+            let pid = page.peek_id();
+            self.stats.borrow_mut().record_flushed(page.peek_type());
+            flushed += 1;
+
+            if preserve {
+                self.preserve_committed(&pid)?;
+            }
+            
+            let mut content = page.try_borrow_mut()?;
+            let version = content.get_version();
+            content.set_version(version + 1);
+            content.update_checksum();
+
+            self.store
+            .store(pid, content.into_section(PageSectionType::All))
+            .map_err(Into::<Error>::into)?;
+            
+            page.ack_upsertion();
+        }
+
+        self.flush_superblock()?;
+        self.store.sync().map_err(Into::<Error>::into)?;
+
+        self.version.set(version + 1);
+        self.committed_last_page_id.set(self.counter.get());
+        self.committed_roots.replace(self.roots.borrow().clone());
+        self.versions.lock().unwrap().commit();
+
+        let elapsed = start.elapsed();
+        self.stats.borrow_mut().record_flush(elapsed);
+        
+        if let Some(observer) = &self.observer {
+            observer.on_flush(flushed, elapsed);
+        }
+
+        Ok(())
+    }
+
+    /// Store the superblock in the first page.
+    fn flush_superblock(&self) -> Result<()> {
+        let mut content = vec![0u8; self.page_size];
+        let superblock = self.superblock();
+        superblock.write(&mut content)?;
+        self.store.store(SUPERBLOCK_PAGE, &content).map_err(Into::<Error>::into)?;
+        self.committed_superblock.replace(Some(superblock));
+        Ok(())
+    }
+}
+
+impl<'buffer, Id, Type, Storage> Transactional for BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: From<u8> + Into<u8>
+{
+    type Error = Error;
+
+    fn begin_transaction(&self) -> Result<()> {
+        let mut undo = self.undo.borrow_mut();
+
+        if undo.is_some() {
+            return Err(Error::TransactionInProgress);
+        }
+
+        *undo = Some(UndoLog::new(self.counter.get(), self.freelist.get(), self.roots.borrow().clone()));
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        if self.undo.borrow().is_none() {
+            return Err(Error::NoTransaction);
+        }
+
+        // Pages modified before the transaction began are left to the next flush.
+        // The storage is responsible for applying the flushed pages atomically (see WalStorage).
+        let touched = self.undo.borrow().as_ref().map(UndoLog::pids).unwrap_or_default();
+        self.flush_pages(&|pid| touched.contains(&pid))?;
+        self.undo.take();
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        let undo = self.undo.take().ok_or(Error::NoTransaction)?;
+
+        // The restored pages stay upserted, as they may have been flushed during the transaction.
+        // The pages keep their version, as they may have been stored since.
+        for mut image in undo.pages {
+            let mut page = self.get_page(&image.pid)?;
+            let version = page.try_borrow()?.get_version();
+            super::page::Page::<Id, Type, _>::from(&mut image.content[..]).set_version(version);
+            page.try_borrow_mut()?.into_section(PageSectionType::All).as_mut().copy_from_slice(&image.content);
+        }
+
+        // Release the pages created during the transaction, their ids will be reused.
+        for pid in undo.created {
+            let version = match self.lookup_page(&pid) {
+                Some(mut page) => {
+                    self.pool.remove(pid.into());
+                    let version = page.try_borrow()?.get_version();
+                    page.try_borrow_mut()?.into_section(PageSectionType::All).as_mut().fill(0);
+                    page.ack_upsertion();
+                    version
+                },
+                None => self.stored_version(&pid)?
+            };
+
+            if version > 0 {
+                self.released.borrow_mut().insert(pid.into(), version);
+            }
+        }
+
+        self.counter.set(undo.last_page_id);
+        self.freelist.set(undo.freelist);
+        self.roots.replace(undo.roots);
+        self.allocator.invalidate();
+
+        Ok(())
+    }
+}
+
+impl<'buffer, Id, Type, Storage> WriteBack for BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    /// Store a dirty page evicted from the buffer.
+    /// It is not committed until the next flush, its committed content is kept for the pinned snapshots.
+    /// Refused for the pages modified within a transaction, unless the storage logs them.
+    fn write_back(&self, key: u64, content: &mut [u8]) -> std::io::Result<()> {
+        let pid = Id::from(key);
+
+        // The modifications of a pending transaction must not reach the storage before its commit, unless they are logged until then.
+        if !self.store.is_logged() && self.undo.borrow().as_ref().is_some_and(|undo| undo.contains(&pid)) {
+            return Err(Error::UncommittedWriteBack { pid: key }.into());
+        }
+
+        self.with_commit_lock(|| self.store_written_back(pid, content)).map_err(Into::into)
+    }
+}
+
+impl<'buffer, Id, Type, Storage> BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    fn store_written_back(&self, pid: Id, content: &mut [u8]) -> Result<()> {
+        let key: u64 = pid.into();
+        let version = super::page::Page::<Id, Type, _>::from(&*content).get_version();
+
+        if self.detect_conflicts {
+            self.check_version(&pid, version)?;
+        }
+
+        if self.versions.lock().unwrap().is_pinned() {
+            self.preserve_committed(&pid)?;
+        } else if key <= self.committed_last_page_id.get().into() {
+            self.versions.lock().unwrap().overwrite(key);
+        }
+
+        let mut page = super::page::Page::<Id, Type, _>::from(&mut *content);
+        page.set_version(version + 1);
+        page.update_checksum();
+        let ptype = super::page::Page::<u64, u8, _>::from(&*content).get_type();
+        self.stats.borrow_mut().record_write_back(ptype);
+
+        if let Some(observer) = &self.observer {
+            observer.on_write_back(ptype);
+        }
+
+        self.store.store(pid, &*content).map_err(Into::<Error>::into)
+    }
+}
+
+impl<'buffer, Id, Type, Storage> PageAccess for BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+{
+    fn read_page(&self, pid: u64, read: &mut dyn FnMut(&[u8])) -> Result<()> {
+        let page = self.get_page(&Id::from(pid))?;
+        read(page.try_borrow()?.into_section(PageSectionType::All).as_ref());
+        Ok(())
+    }
+
+    fn write_page(&self, pid: u64, write: &mut dyn FnMut(&mut [u8])) -> Result<()> {
+        let mut page = self.get_page(&Id::from(pid))?;
+        self.record_pre_image(&page)?;
+        write(page.try_borrow_mut()?.into_section(PageSectionType::All).as_mut());
+        Ok(())
+    }
+
+    fn read_freelist_head(&self) -> Option<u64> {
+        self.freelist.get().map(Into::into)
+    }
+
+    fn write_freelist_head(&self, head: Option<u64>) {
+        self.freelist.set(head.map(Id::from))
+    }
+
+    fn last_page_id(&self) -> u64 {
+        self.counter.get().into()
+    }
+}
+
+impl<'buffer, Id, Type, Storage> Versioned for BufPager<'buffer, Id, Type, Storage>
+where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: From<u8> + Into<u8>
+{
+    type Id = Id;
+    type Type = Type;
+    type Storage = Storage;
+
+    fn pin_snapshot(&self) -> (u64, BTreeMap<String, Id>) {
+        let version = self.version.get();
+        self.versions.lock().unwrap().pin(version);
+        (version, self.committed_roots.borrow().clone())
+    }
+
+    fn committed_pages(&self) -> CommittedPages<'_, Storage> {
+        CommittedPages::new(&self.store, &self.versions, self.page_size)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{io::{Write, Read}, ops::DerefMut};
-    use crate::{io::{InMemory, Data}, fixtures, paging::page::{PageSectionType}, utils::{cell::TryCell, slice::CloneSection, borrow::TryBorrowMut}};
-    use super::{traits::Pager, PageId};
+    use std::io::Write;
+    use crate::{buffer::eviction::Lru, io::InMemory, fixtures, paging::{page::{PageSectionType, traits::ReadPage}, stats::PageTypeStats, storage::PagerStream}, utils::slice::IntoSection};
+    use super::{traits::Pager, BufPager, PageId, Error};
 
     #[test]
     fn test_pager() -> super::Result<()> {
-        let pager = super::Pager::new(InMemory::new(), 10);
+        // Room for a single page, to force the pager to fetch it back from the storage.
+        let pager: BufPager<PageId, u8, _> = super::Pager::new(PagerStream::new(InMemory::new()), 1).with_eviction_policy(Lru::new());
         
         let data_size: usize = 1000;
         let random = fixtures::random_data(data_size);
         
-        let pid: PageId;
-        {
-            let page = pager.new_page(0x10)?;
-            pid = page.try_borrow()?.get_id();
-            
-            page
-            .clone_section(PageSectionType::Body)
-            .try_borrow_mut()?
-            .deref_mut()
-            .write_all(&random)?;
+        let pid = pager.new_page(0x10)?;
+        
+        pager
+        .borrow_mut_page(&pid)?
+        .into_section(PageSectionType::Body)
+        .as_mut()
+        .write_all(&random)?;
+        
+        pager.flush()?;
+
+        let other = pager.new_page(0x10)?;
+        pager.flush()?;
+
+        let page = pager.borrow_page(&pid)?;
+        assert_eq!(page.get_id(), pid);
+        assert_eq!(page.get_type(), 0x10);
+        assert_eq!(page.into_section(PageSectionType::Body).as_ref()[..data_size], *random);
+        
+        assert_ne!(pid, other);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pager_write_back() -> super::Result<()> {
+        // Twice as many dirty pages as the buffer can hold.
+        let pager: BufPager<PageId, u8, _> = super::Pager::with_page_size(PagerStream::new(InMemory::new()), 4096, 4)?;
+
+        let pids = (0..8u8).map(|value| {
+            let pid = pager.new_page(0x10)?;
+            pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().fill(value);
+            Ok(pid)
+        }).collect::<super::Result<Vec<_>>>()?;
+        
+        // The dirty pages left in the buffer are written back to shrink it.
+        pager.resize_buffer(2)?;
+        pager.flush()?;
+
+        let pager: BufPager<PageId, u8, _> = super::Pager::open(pager.into_storage(), 4)?;
+        
+        for (value, pid) in pids.iter().enumerate() {
+            assert_eq!(pager.borrow_page(pid)?.into_section(PageSectionType::Body).as_ref()[0], value as u8);
         }
 
-        let mut stored = Data::with_size(data_size);
-        let page = pager.get_page(pid)?.try_borrow()?;
-        page.clone_section(PageSectionType::Body).as_ref().read(&mut stored)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pager_reopen() -> super::Result<()> {
+        let pager: BufPager<PageId, u8, _> = super::Pager::new(PagerStream::new(InMemory::new()), 10);
+        let root = pager.new_page(0x10)?;
+        let free = pager.new_page(0x10)?;
+        pager.set_root("main", root);
+        pager.drop_page(&free)?;
+        pager.flush()?;
+        
+        let pager: BufPager<PageId, u8, _> = super::Pager::open(pager.into_storage(), 10)?;
+        assert_eq!(pager.get_root("main"), Some(root));
+        assert_eq!(pager.get_freelist_head(), Some(free));
         
-        assert_eq!(random, stored);
+        // The head of the free pages list is reused first.
+        assert_eq!(pager.new_page(0x10)?, free);
+        assert_eq!(pager.new_page(0x10)?, free + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pager_page_size() -> super::Result<()> {
+        assert!(matches!(
+            BufPager::<PageId, u8, _>::with_page_size(PagerStream::new(InMemory::new()), 5000, 10), 
+            Err(Error::InvalidPageSize(5000))
+        ));
+
+        let pager: BufPager<PageId, u8, _> = super::Pager::with_page_size(PagerStream::new(InMemory::new()), 4096, 10)?;
+        pager.new_page(0x10)?;
+        pager.flush()?;
 
+        let pager: BufPager<PageId, u8, _> = super::Pager::open(pager.into_storage(), 10)?;
+        assert_eq!(pager.get_page_size(), 4096);
+        assert_eq!(pager.borrow_page(&1)?.get_size(), 4096);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pager_corrupted_page() -> super::Result<()> {
+        let pager: BufPager<PageId, u8, _> = super::Pager::with_page_size(PagerStream::new(InMemory::new()), 4096, 10)?;
+        let pid = pager.new_page(0x10)?;
+        pager.flush()?;
+
+        let mut stream = pager.into_storage().into_inner();
+        stream[4096 + 100] ^= 0xFF;
+
+        let pager: BufPager<PageId, u8, _> = super::Pager::open(PagerStream::new(stream), 10)?;
+        assert!(matches!(pager.borrow_page(&pid), Err(Error::ChecksumMismatch { pid: 1, .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pager_conflict() -> super::Result<()> {
+        use std::os::unix::fs::FileExt;
+        use crate::paging::{allocator::traits::PageAccess, storage::{FileStorage, LockMode, LockWait}};
+
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = super::Pager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        let pids = (0..2).map(|_| pager.new_page(0x10)).collect::<super::Result<Vec<_>>>()?;
+        pager.flush()?;
+        drop(pager);
+
+        // Two writers sharing the file, both read the pages.
+        let unlocked = || FileStorage::open_locked(&path, LockMode::Unlocked, LockWait::FailFast);
+        let first: BufPager<PageId, u8, _> = super::Pager::open(unlocked()?, 10)?.with_conflict_detection();
+        let second: BufPager<PageId, u8, _> = super::Pager::open(unlocked()?, 10)?.with_conflict_detection();
+        pids.iter().try_for_each(|pid| first.borrow_page(pid).and(second.borrow_page(pid)).map(|_| ()))?;
+
+        first.borrow_mut_page(&pids[0])?.into_section(PageSectionType::Body).as_mut().fill(1);
+        first.flush()?;
+
+        // The page was modified since it was read, nothing is written.
+        second.borrow_mut_page(&pids[0])?.into_section(PageSectionType::Body).as_mut().fill(2);
+        second.borrow_mut_page(&pids[1])?.into_section(PageSectionType::Body).as_mut().fill(2);
+        assert!(matches!(second.flush(), Err(Error::Conflict { pid: 1, expected: 1, found: 2 })));
+
+        // The stale pages are discarded, the pages are read again.
+        second.refresh()?;
+        assert_eq!(second.borrow_page(&pids[0])?.into_section(PageSectionType::Body).as_ref()[0], 1);
+        assert_eq!(second.borrow_page(&pids[1])?.into_section(PageSectionType::Body).as_ref()[0], 0);
+
+        // Writes to distinct pages do not conflict, unless the superblock changed.
+        second.borrow_mut_page(&pids[1])?.into_section(PageSectionType::Body).as_mut().fill(2);
+        second.flush()?;
+        first.new_page(0x10)?;
+        first.flush()?;
+        second.borrow_mut_page(&pids[1])?.into_section(PageSectionType::Body).as_mut().fill(3);
+        assert!(matches!(second.flush(), Err(Error::SuperblockConflict)));
+
+        second.refresh()?;
+        assert_eq!(second.last_page_id(), 3);
+        second.borrow_mut_page(&pids[1])?.into_section(PageSectionType::Body).as_mut().fill(3);
+        second.flush()?;
+
+        // A corrupted page is reported, rather than taken for a page never stored.
+        std::fs::OpenOptions::new().write(true).open(&path)?.write_all_at(&[0xFF], 4096 + 100)?;
+        first.borrow_mut_page(&pids[0])?.into_section(PageSectionType::Body).as_mut().fill(4);
+        assert!(matches!(first.flush(), Err(Error::ChecksumMismatch { pid: 1, .. })));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Counts the reads reaching the storage.
+    struct CountingStorage<S>(S, std::cell::Cell<usize>);
+
+    impl<S: super::PageStorage> super::PageStorage for CountingStorage<S> {
+        type Error = S::Error;
+
+        fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> Result<(), Self::Error> {
+            self.0.store(id, page)
+        }
+
+        fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> Result<(), Self::Error> {
+            self.1.set(self.1.get() + 1);
+            self.0.fetch(id, data)
+        }
+
+        fn fetch_many(&self, first: u64, page_size: usize, data: &mut [u8]) -> Result<(), Self::Error> {
+            self.1.set(self.1.get() + 1);
+            self.0.fetch_many(first, page_size, data)
+        }
+
+        fn sync(&self) -> Result<(), Self::Error> {
+            self.0.sync()
+        }
+    }
+
+    #[test]
+    fn test_pager_prefetch() -> super::Result<()> {
+        use crate::paging::storage::FileStorage;
+
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = super::Pager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        (0..8).try_for_each(|_| pager.new_page(0x10).map(|_| ()))?;
+        pager.flush()?;
+        drop(pager);
+
+        let pager: BufPager<PageId, u8, _> = super::Pager::open(CountingStorage(FileStorage::open(&path)?, Default::default()), 4)?;
+        let reads = pager.store.1.get();
+
+        // A scan from the page 3, beyond the buffer and the last page, fetches what the buffer holds in a single read.
+        assert_eq!(pager.prefetch_from(&3, 10)?, 4);
+        assert_eq!(pager.store.1.get(), reads + 1);
+
+        // The prefetched pages were faulted in by the hint, their accesses are hits.
+        (3..=6).try_for_each(|pid| pager.borrow_page(&pid).map(|_| ()))?;
+        assert_eq!(pager.store.1.get(), reads + 1);
+        assert_eq!(pager.stats().page_type(0x10), PageTypeStats { hits: 4, misses: 4, ..Default::default() });
+
+        // The buffer is full, the hint does not evict its pages.
+        assert_eq!(pager.prefetch(&[8, 1, 2])?, 0);
+        assert!((3..=6).all(|pid| pager.is_buffered(&pid)));
+
+        // The buffered page is skipped, the others are read by run.
+        pager.resize_buffer(8)?;
+        assert_eq!(pager.prefetch(&[8, 3, 1, 2])?, 3);
+        assert_eq!(pager.store.1.get(), reads + 3);
+        assert_eq!(pager.stats().prefetched, 7);
+
+        std::fs::remove_file(path)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
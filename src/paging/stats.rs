@@ -0,0 +1,117 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::buffer::BufferStats;
+
+pub mod traits {
+    use std::time::Duration;
+
+    /// Notified of the activity of the pager, to export it to a monitoring system.
+    pub trait Observer {
+        /// The page was found in the buffer (hit), or fetched from the storage.
+        fn on_access(&self, _ptype: u8, _hit: bool) {}
+        /// The dirty page was written back, to make room in the buffer.
+        fn on_write_back(&self, _ptype: u8) {}
+        /// The upserted pages were flushed.
+        fn on_flush(&self, _pages: usize, _elapsed: Duration) {}
+    }
+}
+
+/// Activity on the pages of a type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageTypeStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub write_backs: u64,
+    pub flushed: u64
+}
+
+/// Snapshot of the activity of the pager, and of its buffer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PagerStats {
+    pub buffer: BufferStats,
+    pub flushes: u64,
+    /// Time spent flushing, in total, and by the slowest flush.
+    pub flush_time: Duration,
+    pub max_flush_time: Duration,
+    /// Pages fetched ahead of their access, on hints.
+    pub prefetched: u64,
+    pub by_type: BTreeMap<u8, PageTypeStats>
+}
+
+impl PagerStats {
+    /// Activity on the pages of the type.
+    pub fn page_type(&self, ptype: u8) -> PageTypeStats {
+        self.by_type.get(&ptype).copied().unwrap_or_default()
+    }
+
+    pub fn record_access(&mut self, ptype: u8, hit: bool) {
+        let stats = self.by_type.entry(ptype).or_default();
+
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+
+    pub fn record_write_back(&mut self, ptype: u8) {
+        self.by_type.entry(ptype).or_default().write_backs += 1;
+    }
+
+    pub fn record_flushed(&mut self, ptype: u8) {
+        self.by_type.entry(ptype).or_default().flushed += 1;
+    }
+
+    pub fn record_flush(&mut self, elapsed: Duration) {
+        self.flushes += 1;
+        self.flush_time += elapsed;
+        self.max_flush_time = self.max_flush_time.max(elapsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc, time::Duration};
+
+    use crate::{io::InMemory, paging::{page::{BPTREE_LEAF, BPTREE_BRANCH}, pager::{traits::Pager, BufPager, PageId}, storage::PagerStream, result::Result}};
+    use super::traits::Observer;
+
+    #[derive(Default)]
+    struct Accesses(Cell<u64>, Cell<usize>);
+
+    impl Observer for Rc<Accesses> {
+        fn on_access(&self, _ptype: u8, _hit: bool) {
+            self.0.set(self.0.get() + 1);
+        }
+
+        fn on_flush(&self, pages: usize, _elapsed: Duration) {
+            self.1.set(self.1.get() + pages);
+        }
+    }
+
+    #[test]
+    fn test_pager_stats() -> Result<()> {
+        let accesses = Rc::new(Accesses::default());
+
+        // Room for a single page, the leaf is written back when the branch is created.
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 1).with_observer(accesses.clone());
+        let leaf = pager.new_page(BPTREE_LEAF)?;
+        let branch = pager.new_page(BPTREE_BRANCH)?;
+        pager.borrow_page(&branch)?;
+        pager.borrow_page(&leaf)?;
+        pager.flush()?;
+
+        let stats = pager.stats();
+        assert_eq!(stats.page_type(BPTREE_LEAF).misses, 1);
+        assert_eq!(stats.page_type(BPTREE_LEAF).write_backs, 1);
+        assert_eq!(stats.page_type(BPTREE_BRANCH).hits, 1);
+        assert_eq!(stats.page_type(BPTREE_BRANCH).write_backs, 1);
+        assert_eq!(stats.page_type(BPTREE_LEAF).flushed, 0);
+        assert_eq!((stats.flushes, stats.buffer.evictions, stats.buffer.write_backs), (1, 2, 2));
+
+        assert_eq!(accesses.0.get(), 2);
+        assert_eq!(accesses.1.get(), 0);
+
+        Ok(())
+    }
+}
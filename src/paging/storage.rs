@@ -0,0 +1,285 @@
+use std::{cell::RefCell, io::{Read, Write, Seek, SeekFrom}, fs::{File, OpenOptions}, path::Path, os::unix::{fs::{FileExt, MetadataExt}, io::AsRawFd}, time::{Duration, Instant}};
+
+use super::{pager::traits::PageStorage, error::Error, result::Result};
+
+/// Interval between two attempts to lock a locked database file.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Advisory lock taken on the database file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// A single writer, and no reader.
+    Exclusive,
+    /// Any number of readers, and no writer. The file is opened read-only.
+    Shared,
+    /// No lock, the writers sharing the file must detect their conflicts (see Pager::with_conflict_detection).
+    Unlocked
+}
+
+/// What to do when the database file is locked by another handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockWait {
+    FailFast,
+    /// Retry until the lock is released, or the timeout elapsed.
+    Timeout(Duration)
+}
+
+/// Page storage backed by a seekable stream.
+/// The page N is located at N * page_size.
+pub struct PagerStream<S>(RefCell<S>);
+
+impl<S> PagerStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self(RefCell::new(stream))
+    }
+
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+}
+
+impl<S> PageStorage for PagerStream<S> where S: Read + Write + Seek {
+    type Error = std::io::Error;
+
+    fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error> {
+        let page = page.as_ref();
+        let mut stream = self.0.borrow_mut();
+        stream.seek(SeekFrom::Start(id.into() * page.len() as u64))?;
+        stream.write_all(page)
+    }
+
+    fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error> {
+        let data = data.as_mut();
+        let mut stream = self.0.borrow_mut();
+        stream.seek(SeekFrom::Start(id.into() * data.len() as u64))?;
+        stream.read_exact(data)
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Page storage backed by a single file.
+/// The page N is located at N * page_size, and is accessed with positional reads and writes.
+pub struct FileStorage(File);
+
+impl FileStorage {
+    /// Create a new database file, locked exclusively, fails if it already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_locked(path, LockMode::Exclusive, LockWait::FailFast)
+    }
+
+    /// Create a new database file, fails if it already exists.
+    pub fn create_locked<P: AsRef<Path>>(path: P, mode: LockMode, wait: LockWait) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        
+        Self(file).lock(mode, wait)
+    }
+
+    /// Open an existing database file, locked exclusively.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_locked(path, LockMode::Exclusive, LockWait::FailFast)
+    }
+
+    /// Open an existing database file, read-only if the lock is shared.
+    pub fn open_locked<P: AsRef<Path>>(path: P, mode: LockMode, wait: LockWait) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode != LockMode::Shared)
+            .open(path)?;
+        
+        Self(file).lock(mode, wait)
+    }
+
+    /// The database file, its locks are held by the storage.
+    pub(crate) fn file(&self) -> &File {
+        &self.0
+    }
+
+    /// Size of the file, in bytes.
+    pub fn len(&self) -> std::io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Lock the database file (flock), the lock is released once the storage is dropped.
+    /// The lock is advisory, it only excludes the handles locking the file as well.
+    pub fn lock(self, mode: LockMode, wait: LockWait) -> Result<Self> {
+        let operation = match mode {
+            LockMode::Exclusive => libc::LOCK_EX,
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Unlocked => libc::LOCK_UN
+        };
+
+        let deadline = match wait {
+            LockWait::FailFast => None,
+            LockWait::Timeout(timeout) => Some(Instant::now() + timeout)
+        };
+
+        loop {
+            if unsafe { libc::flock(self.0.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+                return Ok(self);
+            }
+
+            let err = std::io::Error::last_os_error();
+
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => std::thread::sleep(LOCK_RETRY_INTERVAL),
+                _ => return Err(Error::Locked { pid: self.lock_holder() })
+            }
+        }
+    }
+
+    fn set_commit_lock(&self, l_type: libc::c_short) -> std::io::Result<()> {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = l_type;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        lock.l_len = 1;
+
+        loop {
+            if unsafe { libc::fcntl(self.0.as_raw_fd(), libc::F_OFD_SETLKW, &lock) } == 0 {
+                return Ok(());
+            }
+
+            let err = std::io::Error::last_os_error();
+
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Process holding a lock on the file, as listed in /proc/locks.
+    fn lock_holder(&self) -> Option<u32> {
+        let metadata = self.0.metadata().ok()?;
+        let dev = metadata.dev() as libc::dev_t;
+        let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+        let file = format!("{:02x}:{:02x}:{}", major, minor, metadata.ino());
+
+        // 1: FLOCK  ADVISORY  WRITE <pid> <major>:<minor>:<inode> 0 EOF
+        std::fs::read_to_string("/proc/locks")
+        .ok()?
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&"FLOCK") && fields.get(5) == Some(&file.as_str()))
+        .and_then(|fields| fields.get(4)?.parse().ok())
+    }
+}
+
+impl PageStorage for FileStorage {
+    type Error = std::io::Error;
+
+    fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error> {
+        let page = page.as_ref();
+        self.0.write_all_at(page, id.into() * page.len() as u64)
+    }
+
+    fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error> {
+        let data = data.as_mut();
+        self.0.read_exact_at(data, id.into() * data.len() as u64)
+    }
+
+    fn fetch_many(&self, first: u64, page_size: usize, data: &mut [u8]) -> std::result::Result<(), Self::Error> {
+        self.0.read_exact_at(data, first * page_size as u64)
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.0.sync_data()
+    }
+
+    fn truncate(&self, len: u64) -> std::result::Result<(), Self::Error> {
+        self.0.set_len(len)
+    }
+
+    /// Write lock on the first byte of the file (open file description lock, independent from the flock of the file),
+    /// waiting for the other handles to release it.
+    fn lock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.set_commit_lock(libc::F_WRLCK as libc::c_short)
+    }
+
+    fn unlock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.set_commit_lock(libc::F_UNLCK as libc::c_short)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use std::time::Duration;
+
+    use crate::{fixtures, paging::{pager::{BufPager, Pager, PageId, traits::{Pager as TraitPager, PageStorage}}, page::PageSectionType, error::Error}, utils::slice::IntoSection};
+    use super::{FileStorage, LockMode, LockWait};
+
+    #[test]
+    fn test_file_storage() -> crate::paging::result::Result<()> {
+        let path = fixtures::temp_path();
+        let data_size: usize = 1000;
+        let random = fixtures::random_data(data_size);
+        
+        let pid: PageId = {
+            let pager: BufPager<PageId, u8, _> = Pager::new(FileStorage::create(&path)?, 10);
+            let pid = pager.new_page(0x10)?;
+            
+            pager
+            .borrow_mut_page(&pid)?
+            .into_section(PageSectionType::Body)
+            .as_mut()
+            .write_all(&random)?;
+
+            pager.flush()?;
+            pid
+        };
+
+        let pager: BufPager<PageId, u8, _> = Pager::open(FileStorage::open(&path)?, 10)?;
+        let stored = pager.borrow_page(&pid)?.into_section(PageSectionType::Body);
+        assert_eq!(stored.as_ref()[..data_size], *random);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_lock() -> crate::paging::result::Result<()> {
+        let path = fixtures::temp_path();
+        // Writers lock the file exclusively by default.
+        let writer = FileStorage::create(&path)?;
+        assert!(matches!(FileStorage::open(&path), Err(Error::Locked { .. })));
+
+        // The holder is reported, it is this process here.
+        let locked = FileStorage::open_locked(&path, LockMode::Shared, LockWait::Timeout(Duration::from_millis(50)));
+        assert!(matches!(locked, Err(Error::Locked { pid: Some(pid) }) if pid == std::process::id()));
+
+        // Readers share the file once the writer is gone, and cannot write to it.
+        drop(writer);
+        let reader = FileStorage::open_locked(&path, LockMode::Shared, LockWait::FailFast)?;
+        let other = FileStorage::open_locked(&path, LockMode::Shared, LockWait::FailFast)?;
+        assert!(matches!(FileStorage::open(&path), Err(Error::Locked { .. })));
+        assert!(reader.store(1u64, [0u8; 4096]).is_err());
+
+        // The writer waits for the readers.
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(reader);
+            drop(other);
+        });
+
+        FileStorage::open_locked(&path, LockMode::Exclusive, LockWait::Timeout(Duration::from_secs(5)))?;
+        handle.join().unwrap();
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::utils::slice::IntoSection;
+
+use super::{page::{Page, PageSectionType, ROOT}, error::Error, result::Result};
+
+/// Magic number written at the beginning of the superblock.
+pub const MAGIC: [u8; 8] = *b"BROUAS\0\0";
+/// Version of the on-disk format.
+pub const FORMAT_VERSION: u16 = 1;
+/// The superblock is always stored in the first page.
+pub const SUPERBLOCK_PAGE: u64 = 0;
+
+/// Superblock sections (relative to the page body)
+const MAGIC_RANGE: Range<usize> = 0..8;
+const VERSION_RANGE: Range<usize> = 8..10;
+const PAGE_SIZE_RANGE: Range<usize> = 10..18;
+const NEXT_PAGE_RANGE: Range<usize> = 18..26;
+const FREE_HEAD_RANGE: Range<usize> = 26..34;
+const ROOTS_LEN_RANGE: Range<usize> = 34..36;
+const ROOTS: usize = 36;
+
+fn verify_checksum(content: &[u8]) -> Result<()> {
+    Page::<u64, u8, _>::from(content)
+    .verify_checksum()
+    .map_err(|(expected, actual)| Error::ChecksumMismatch { pid: SUPERBLOCK_PAGE, expected, actual })
+}
+
+/// State of the pager persisted in the first page of the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    pub version: u16,
+    pub page_size: u64,
+    /// Id of the next page to be created.
+    pub next_page_id: u64,
+    /// Head of the free pages list.
+    pub freelist_head: Option<u64>,
+    /// Named roots (trees, collections, ...) of the database.
+    pub roots: BTreeMap<String, u64>
+}
+
+impl Superblock {
+    pub fn new(page_size: u64) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            page_size,
+            next_page_id: SUPERBLOCK_PAGE + 1,
+            freelist_head: None,
+            roots: Default::default()
+        }
+    }
+
+    /// Read the page size of the database, only requires the beginning of the superblock.
+    pub fn read_page_size(content: &[u8]) -> Result<u64> {
+        let page = Page::<u64, u8, _>::from(content);
+        let body = page.into_section(PageSectionType::Body);
+        Self::check(body.as_ref())?;
+        
+        Ok(u64::from_le_bytes(body.as_ref()[PAGE_SIZE_RANGE].try_into().unwrap()))
+    }
+
+    /// Read the superblock from the raw content of the page.
+    pub fn read(content: &[u8]) -> Result<Self> {
+        let page = Page::<u64, u8, _>::from(content);
+        let body = page.into_section(PageSectionType::Body);
+        let body = body.as_ref();
+
+        let version = Self::check(body)?;
+
+        verify_checksum(content)?;
+
+        let freelist_head = match u64::from_le_bytes(body[FREE_HEAD_RANGE].try_into().unwrap()) {
+            0 => None,
+            pid => Some(pid)
+        };
+
+        let mut roots = BTreeMap::default();
+        let mut cursor = ROOTS;
+        
+        for _ in 0..u16::from_le_bytes(body[ROOTS_LEN_RANGE].try_into().unwrap()) {
+            let name_len = *body.get(cursor).ok_or(Error::CorruptedSuperblock)? as usize;
+            cursor += 1;
+
+            let name = body.get(cursor..cursor + name_len).ok_or(Error::CorruptedSuperblock)?;
+            let name = String::from_utf8(name.to_vec()).map_err(|_| Error::CorruptedSuperblock)?;
+            cursor += name_len;
+
+            let pid = body.get(cursor..cursor + 8).ok_or(Error::CorruptedSuperblock)?;
+            roots.insert(name, u64::from_le_bytes(pid.try_into().unwrap()));
+            cursor += 8;
+        }
+
+        Ok(Self {
+            version,
+            page_size: u64::from_le_bytes(body[PAGE_SIZE_RANGE].try_into().unwrap()),
+            next_page_id: u64::from_le_bytes(body[NEXT_PAGE_RANGE].try_into().unwrap()),
+            freelist_head,
+            roots
+        })
+    }
+
+    /// Check the magic number and the format version, returns the version.
+    fn check(body: &[u8]) -> Result<u16> {
+        if body.len() < ROOTS {
+            return Err(Error::CorruptedSuperblock);
+        }
+
+        if body[MAGIC_RANGE] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let version = u16::from_le_bytes(body[VERSION_RANGE].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::IncompatibleVersion { expected: FORMAT_VERSION, got: version });
+        }
+
+        Ok(version)
+    }
+
+    /// Write the superblock into the raw content of the page.
+    pub fn write(&self, content: &mut [u8]) -> Result<()> {
+        content.fill(0);
+
+        let page = Page::<u64, u8, _>::new(SUPERBLOCK_PAGE, ROOT, &mut *content);
+        let mut body = page.into_section(PageSectionType::Body);
+        let body = body.as_mut();
+
+        body[MAGIC_RANGE].copy_from_slice(&MAGIC);
+        body[VERSION_RANGE].copy_from_slice(&self.version.to_le_bytes());
+        body[PAGE_SIZE_RANGE].copy_from_slice(&self.page_size.to_le_bytes());
+        body[NEXT_PAGE_RANGE].copy_from_slice(&self.next_page_id.to_le_bytes());
+        body[FREE_HEAD_RANGE].copy_from_slice(&self.freelist_head.unwrap_or(0).to_le_bytes());
+        body[ROOTS_LEN_RANGE].copy_from_slice(&(self.roots.len() as u16).to_le_bytes());
+
+        let mut cursor = ROOTS;
+
+        for (name, pid) in self.roots.iter() {
+            let name = name.as_bytes();
+            let name_len: u8 = name.len().try_into().map_err(|_| Error::SuperblockFull)?;
+            let entry = body.get_mut(cursor..cursor + 1 + name.len() + 8).ok_or(Error::SuperblockFull)?;
+            
+            entry[0] = name_len;
+            entry[1..1 + name.len()].copy_from_slice(name);
+            entry[1 + name.len()..].copy_from_slice(&pid.to_le_bytes());
+            cursor += entry.len();
+        }
+
+        Page::<u64, u8, _>::from(content).update_checksum();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paging::error::Error;
+    use super::Superblock;
+
+    #[test]
+    fn test_superblock() -> crate::paging::result::Result<()> {
+        let mut content = vec![0u8; 4096];
+        
+        let mut superblock = Superblock::new(4096);
+        superblock.next_page_id = 12;
+        superblock.freelist_head = Some(7);
+        superblock.roots.insert("users".into(), 3);
+        superblock.roots.insert("orders".into(), 5);
+        superblock.write(&mut content)?;
+
+        assert_eq!(Superblock::read(&content)?, superblock);
+
+        superblock.version += 1;
+        superblock.write(&mut content)?;
+        assert!(matches!(Superblock::read(&content), Err(Error::IncompatibleVersion { .. })));
+
+        assert!(matches!(Superblock::read(&[0u8; 4096]), Err(Error::InvalidMagic)));
+
+        Ok(())
+    }
+}
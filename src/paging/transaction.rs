@@ -0,0 +1,171 @@
+use std::{collections::{BTreeMap, BTreeSet}, ops::Deref};
+
+use self::traits::Transactional;
+
+pub mod traits {
+    /// A pager able to group page modifications, and to apply them all-or-nothing.
+    pub trait Transactional {
+        type Error;
+
+        /// Start recording the pre-images of the modified pages.
+        fn begin_transaction(&self) -> Result<(), Self::Error>;
+        /// Make the modifications durable, and forget the pre-images.
+        fn commit_transaction(&self) -> Result<(), Self::Error>;
+        /// Restore the pre-images, and forget the modifications.
+        fn rollback_transaction(&self) -> Result<(), Self::Error>;
+    }
+}
+
+/// Content of a page before its first modification within the transaction.
+pub struct PreImage<Id> {
+    pub pid: Id,
+    pub content: Vec<u8>
+}
+
+/// State of the pager when the transaction began, and pre-images of the pages modified since then.
+pub struct UndoLog<Id> {
+    pub pages: Vec<PreImage<Id>>,
+    pub created: Vec<Id>,
+    pub last_page_id: Id,
+    pub freelist: Option<Id>,
+    pub roots: BTreeMap<String, Id>
+}
+
+impl<Id> UndoLog<Id> where Id: PartialEq {
+    pub fn new(last_page_id: Id, freelist: Option<Id>, roots: BTreeMap<String, Id>) -> Self {
+        Self {
+            pages: Default::default(),
+            created: Default::default(),
+            last_page_id,
+            freelist,
+            roots
+        }
+    }
+
+    /// The page was created, or its pre-image already recorded.
+    pub fn contains(&self, pid: &Id) -> bool {
+        self.created.contains(pid) || self.pages.iter().any(|image| image.pid == *pid)
+    }
+
+    pub fn record(&mut self, pid: Id, content: Vec<u8>) {
+        self.pages.push(PreImage { pid, content })
+    }
+
+    pub fn record_creation(&mut self, pid: Id) {
+        self.created.push(pid)
+    }
+}
+
+impl<Id> UndoLog<Id> where Id: Copy + Into<u64> {
+    /// Pages modified, or created, within the transaction.
+    pub fn pids(&self) -> BTreeSet<u64> {
+        self.pages.iter().map(|image| image.pid.into()).chain(self.created.iter().map(|pid| (*pid).into())).collect()
+    }
+}
+
+/// Handle over a pending transaction.
+///
+/// The modifications are made through the handle, which dereferences to the pager.
+/// They are discarded if the transaction is dropped without being committed.
+pub struct Transaction<'pager, Pager> where Pager: Transactional {
+    pager: &'pager Pager,
+    done: bool
+}
+
+impl<'pager, Pager> Transaction<'pager, Pager> where Pager: Transactional {
+    pub fn begin(pager: &'pager Pager) -> Result<Self, Pager::Error> {
+        pager.begin_transaction()?;
+        Ok(Self { pager, done: false })
+    }
+
+    /// Make the modifications durable, all at once.
+    /// If the commit fails, the modifications are rolled back, and the commit error is returned.
+    pub fn commit(mut self) -> Result<(), Pager::Error> {
+        self.done = true;
+
+        self.pager.commit_transaction().inspect_err(|_| {
+            let _ = self.pager.rollback_transaction();
+        })
+    }
+
+    /// Discard the modifications.
+    pub fn rollback(mut self) -> Result<(), Pager::Error> {
+        self.done = true;
+        self.pager.rollback_transaction()
+    }
+}
+
+impl<'pager, Pager> Deref for Transaction<'pager, Pager> where Pager: Transactional {
+    type Target = Pager;
+
+    fn deref(&self) -> &Self::Target {
+        self.pager
+    }
+}
+
+impl<'pager, Pager> Drop for Transaction<'pager, Pager> where Pager: Transactional {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.pager.rollback_transaction();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{io::InMemory, paging::{page::PageSectionType, pager::{traits::Pager, BufPager, PageId}, storage::PagerStream, result::Result}, utils::slice::IntoSection};
+
+    fn write_body(pager: &BufPager<PageId, u8, PagerStream<InMemory>>, pid: PageId, value: u8) -> Result<()> {
+        pager.borrow_mut_page(&pid)?.into_section(PageSectionType::Body).as_mut().fill(value);
+        Ok(())
+    }
+
+    fn read_body(pager: &BufPager<PageId, u8, PagerStream<InMemory>>, pid: PageId) -> Result<u8> {
+        Ok(pager.borrow_page(&pid)?.into_section(PageSectionType::Body).as_ref()[0])
+    }
+
+    #[test]
+    fn test_transaction() -> Result<()> {
+        let pager: BufPager<PageId, u8, _> = BufPager::new(PagerStream::new(InMemory::new()), 10);
+        let pid = pager.new_page(0x10)?;
+        write_body(&pager, pid, 1)?;
+        pager.flush()?;
+
+        {
+            let tx = pager.begin()?;
+            write_body(&tx, pid, 2)?;
+            let created = tx.new_page(0x10)?;
+            tx.set_root("main", created);
+            // Dropped without commit.
+        }
+
+        assert_eq!(read_body(&pager, pid)?, 1);
+        assert_eq!(pager.get_root("main"), None);
+        assert_eq!(pager.new_page(0x10)?, pid + 1);
+
+        // The page modified before the transaction is not committed with it.
+        let other = pager.new_page(0x10)?;
+        pager.flush()?;
+        write_body(&pager, other, 4)?;
+
+        let tx = pager.begin()?;
+        assert!(matches!(pager.begin(), Err(crate::paging::error::Error::TransactionInProgress)));
+        write_body(&tx, pid, 3)?;
+        tx.commit()?;
+        assert_eq!(pager.iter_upserted_pages().map(|page| page.peek_id()).collect::<Vec<_>>(), vec![other]);
+
+        let pager: BufPager<PageId, u8, _> = BufPager::open(pager.into_storage(), 1)?;
+        assert_eq!(read_body(&pager, pid)?, 3);
+
+        // Room for a single page: the page modified within the transaction is not written back to make room, as the storage has no log.
+        {
+            let tx = pager.begin()?;
+            write_body(&tx, pid, 5)?;
+            assert!(matches!(write_body(&tx, other, 5), Err(crate::paging::error::Error::IoError(err)) if err.kind() == std::io::ErrorKind::OutOfMemory));
+        }
+
+        assert_eq!(read_body(&pager, pid)?, 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{page::{Page, PAGE_TYPE_OFFSET, traits::{ReadPage, WritePage}}, pager::{BufPager, FREE_PAGE, traits::PageStorage}, allocator::traits::PageAccess, links::builtin_links, error::Error, result::Result};
+
+use self::traits::PageLinks;
+
+/// Moves committed at once by a run, the pages they modify must fit in the buffer.
+pub const MOVES_PER_STEP: usize = 16;
+
+pub mod traits {
+    /// References held by the pages of a type to other pages (B+tree children, overflow next pages...).
+    pub trait PageLinks {
+        /// Pages referenced by the content of the page.
+        fn links(&self, content: &[u8]) -> Vec<u64>;
+        /// Replace the references to the page from, by references to the page to.
+        fn relink(&self, content: &mut [u8], from: u64, to: u64);
+        /// Check the invariants of the content of the page (ordering of the keys...), returns the violated one.
+        fn check(&self, _content: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+        /// Whether the page may reference pages of the type.
+        fn accepts(&self, _content: &[u8], _ptype: u8) -> bool {
+            true
+        }
+    }
+}
+
+/// Outcome of a vacuum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VacuumReport {
+    /// Pages moved into free slots.
+    pub moved: usize,
+    /// Pages released at the end of the storage.
+    pub truncated: u64
+}
+
+/// Moves the live pages from the end of the storage into free slots, and truncates the storage.
+///
+/// A page is moved if the links of its type are registered, and if it is a named root without parent, or has a parent of a registered type,
+/// as the references to it are then known: the link of its parent, the parent ids of its children, and the named roots.
+pub struct Vacuum {
+    links: BTreeMap<u8, Box<dyn PageLinks>>
+}
+
+impl Vacuum {
+    pub fn new() -> Self {
+        Self { links: Default::default() }
+    }
+
+    /// Register the links held by the pages of the type.
+    pub fn with_links(mut self, ptype: u8, links: impl PageLinks + 'static) -> Self {
+        self.links.insert(ptype, Box::new(links));
+        self
+    }
+
+    /// Register the links held by the overflow pages and the B+tree nodes.
+    pub fn with_builtin_links(mut self) -> Self {
+        self.links.extend(builtin_links());
+        self
+    }
+
+    /// Move at most max_moves pages, and truncate the storage, while the pager stays online.
+    /// The moves are committed at once, with the free pages list holding the pages they left, before the storage is truncated.
+    pub fn step<'buffer, Id, Type, Storage>(&self, pager: &BufPager<'buffer, Id, Type, Storage>, max_moves: usize) -> Result<VacuumReport>
+    where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: From<u8> + Into<u8>
+    {
+        let last = pager.last_page_id();
+        let mut free: BTreeSet<u64> = pager.free_pages()?.into_iter().collect();
+        let mut released = Vec::new();
+        let mut report = VacuumReport::default();
+        let mut end = last;
+
+        let tx = pager.begin()?;
+
+        loop {
+            // Free pages at the end are released.
+            while end > 0 && free.remove(&end) {
+                released.push(end);
+                end -= 1;
+            }
+
+            if report.moved == max_moves {
+                break;
+            }
+
+            let dst = match free.iter().next() {
+                Some(&dst) if dst < end => dst,
+                _ => break
+            };
+
+            let roots: BTreeSet<u64> = tx.get_roots().into_values().map(Into::into).collect();
+
+            if !self.is_movable(&*tx, &roots, end)? {
+                break;
+            }
+
+            self.relocate(&tx, end, dst)?;
+            free.remove(&dst);
+            free.insert(end);
+            report.moved += 1;
+        }
+
+        // The moved pages overwrote free pages, the list is rebuilt with the pages they left.
+        if report.moved > 0 {
+            tx.rebuild_free_pages(&free.iter().chain(released.iter()).copied().collect::<Vec<_>>())?;
+        }
+
+        tx.commit()?;
+
+        if !released.is_empty() {
+            report.truncated = last - end;
+            pager.rebuild_free_pages(&free.into_iter().collect::<Vec<_>>())?;
+            pager.truncate(Id::from(end))?;
+        }
+
+        Ok(report)
+    }
+
+    /// Compact the storage by steps of MOVES_PER_STEP moves, the pager should not be used by anyone else in the meantime.
+    pub fn run<'buffer, Id, Type, Storage>(&self, pager: &BufPager<'buffer, Id, Type, Storage>) -> Result<VacuumReport>
+    where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>, Type: From<u8> + Into<u8>
+    {
+        let mut report = VacuumReport::default();
+
+        loop {
+            let step = self.step(pager, MOVES_PER_STEP)?;
+            report.moved += step.moved;
+            report.truncated += step.truncated;
+
+            if step.moved < MOVES_PER_STEP {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// All the references to the page are known.
+    fn is_movable(&self, pages: &dyn PageAccess, roots: &BTreeSet<u64>, pid: u64) -> Result<bool> {
+        if !self.links.contains_key(&read_type(pages, pid)?) {
+            return Ok(false);
+        }
+
+        // A page without parent may be referenced from anywhere, unless it is a named root.
+        match read_parent(pages, pid)? {
+            0 => Ok(roots.contains(&pid)),
+            parent => Ok(self.links.contains_key(&read_type(pages, parent)?))
+        }
+    }
+
+    /// Move the page from src to dst, and rewrite the references to it.
+    fn relocate<'buffer, Id, Type, Storage>(&self, pager: &BufPager<'buffer, Id, Type, Storage>, src: u64, dst: u64) -> Result<()>
+    where Storage: PageStorage, Storage::Error: Into<Error>, Id: std::ops::AddAssign + From<u8> + Copy + PartialEq + From<u64> + Into<u64>
+    {
+        let mut content = Vec::new();
+        pager.read_page(src, &mut |page| content = page.to_vec())?;
+        Page::<u64, u8, _>::from(&mut content[..]).set_id(dst);
+        // The free page keeps its version, as stored.
+        pager.write_page(dst, &mut |page| {
+            let version = Page::<u64, u8, _>::from(&*page).get_version();
+            page.copy_from_slice(&content);
+            Page::<u64, u8, _>::from(page).set_version(version);
+        })?;
+
+        let ptype = content[PAGE_TYPE_OFFSET];
+        let parent = Page::<u64, u8, _>::from(&content[..]).get_parent();
+
+        if parent != 0 {
+            let links = &self.links[&read_type(pager, parent)?];
+            pager.write_page(parent, &mut |page| links.relink(page, src, dst))?;
+        }
+
+        for child in self.links[&ptype].links(&content) {
+            pager.write_page(child, &mut |page| {
+                let mut page = Page::<u64, u8, _>::from(page);
+
+                if page.get_parent() == src {
+                    page.set_parent(dst);
+                }
+            })?;
+        }
+
+        for (name, pid) in pager.get_roots() {
+            if pid.into() == src {
+                pager.set_root(name, Id::from(dst));
+            }
+        }
+
+        pager.write_page(src, &mut |page| Page::<u64, u8, _>::from(page).drop())
+    }
+}
+
+fn read_type(pages: &dyn PageAccess, pid: u64) -> Result<u8> {
+    let mut ptype = FREE_PAGE;
+    pages.read_page(pid, &mut |content| ptype = content[PAGE_TYPE_OFFSET])?;
+    Ok(ptype)
+}
+
+fn read_parent(pages: &dyn PageAccess, pid: u64) -> Result<u64> {
+    let mut parent = 0;
+    pages.read_page(pid, &mut |content| parent = Page::<u64, u8, _>::from(content).get_parent())?;
+    Ok(parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fixtures, paging::{layout::{cell_offset, write_u64, NODE_TYPE, NODE_LEN, NODE_CAPACITY, BRANCH_NODE, LEAF_NODE, BRANCH_CHILD}, links::BPTreeLinks, page::{PAGE_HEADER_SIZE, BPTREE_BRANCH, BPTREE_LEAF, PageSectionType, traits::{ReadPage, WritePage}}, pager::{traits::Pager, BufPager, PageId}, storage::FileStorage, error::Error, result::Result}, utils::slice::{BorrowMutSection, IntoSection}};
+    use super::{traits::PageLinks, Vacuum, VacuumReport};
+
+    const LIST: u8 = 0x20;
+
+    /// The page references its child at the beginning of its body.
+    struct ListLinks;
+
+    impl PageLinks for ListLinks {
+        fn links(&self, content: &[u8]) -> Vec<u64> {
+            match read_child(content) {
+                0 => vec![],
+                child => vec![child]
+            }
+        }
+
+        fn relink(&self, content: &mut [u8], from: u64, to: u64) {
+            if read_child(content) == from {
+                content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].copy_from_slice(&to.to_le_bytes());
+            }
+        }
+    }
+
+    fn read_child(content: &[u8]) -> u64 {
+        u64::from_le_bytes(content[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].try_into().unwrap())
+    }
+
+    fn link(pager: &BufPager<PageId, u8, FileStorage>, parent: PageId, child: PageId) -> Result<()> {
+        pager.borrow_mut_page(&parent)?.into_section(PageSectionType::Body).as_mut()[..8].copy_from_slice(&child.to_le_bytes());
+        pager.borrow_mut_page(&child)?.set_parent(parent);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        let pids = (0..6).map(|_| pager.new_page(LIST)).collect::<Result<Vec<_>>>()?;
+
+        // A list rooted at the last page: 6 -> 5 -> 3.
+        pager.set_root("list", pids[5]);
+        link(&pager, pids[5], pids[4])?;
+        link(&pager, pids[4], pids[2])?;
+        [0, 1, 3].iter().try_for_each(|&index| pager.drop_page(&pids[index]))?;
+        pager.flush()?;
+
+        let vacuum = Vacuum::new().with_links(LIST, ListLinks);
+
+        // Online, the root is moved into the first free page.
+        assert_eq!(vacuum.step(&pager, 1)?, VacuumReport { moved: 1, truncated: 1 });
+        assert_eq!(pager.get_root("list"), Some(1));
+        assert_eq!(pager.borrow_page(&5)?.get_parent(), 1);
+        assert_eq!(pager.free_pages()?, vec![2, 4]);
+
+        // The moves are committed by the step, along with the free pages list.
+        {
+            let _tx = pager.begin()?;
+            assert!(matches!(vacuum.step(&pager, 1), Err(Error::TransactionInProgress)));
+        }
+
+        assert_eq!(vacuum.run(&pager)?, VacuumReport { moved: 1, truncated: 2 });
+        assert_eq!(pager.into_storage().len()?, 4 * 4096);
+
+        // The list is reachable from its root once reopened: 1 -> 2 -> 3.
+        let pager: BufPager<PageId, u8, _> = BufPager::open(FileStorage::open(&path)?, 10)?;
+        let root = pager.get_root("list").unwrap();
+        let child = read_child(pager.borrow_page(&root)?.into_section(PageSectionType::All).as_ref());
+        let leaf = read_child(pager.borrow_page(&child)?.into_section(PageSectionType::All).as_ref());
+        assert_eq!((root, child, leaf), (1, 2, 3));
+        assert_eq!(pager.borrow_page(&child)?.get_parent(), root);
+        assert_eq!(pager.borrow_page(&leaf)?.get_parent(), child);
+        assert_eq!(pager.new_page(LIST)?, 4);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_builtin_links() -> Result<()> {
+        let path = fixtures::temp_path();
+        let pager: BufPager<PageId, u8, _> = BufPager::with_page_size(FileStorage::create(&path)?, 4096, 10)?;
+        let pids = [pager.new_page(BPTREE_LEAF)?, pager.new_page(BPTREE_LEAF)?, pager.new_page(BPTREE_LEAF)?, pager.new_page(BPTREE_BRANCH)?];
+        let (leaf, branch) = (pids[2], pids[3]);
+
+        // A B+tree rooted at the last page, its branch links the leaf in its first cell.
+        {
+            let mut page = pager.borrow_mut_page(&branch)?;
+            let mut body = page.borrow_mut_section(PageSectionType::Body);
+            let body = body.as_mut();
+            (body[NODE_TYPE], body[NODE_LEN], body[NODE_CAPACITY]) = (BRANCH_NODE, 1, 8);
+            let offset = cell_offset(4096, 8, 0);
+            write_u64(body, offset + BRANCH_CHILD.start..offset + BRANCH_CHILD.end, leaf);
+        }
+
+        pager.borrow_mut_page(&leaf)?.into_section(PageSectionType::Body).as_mut()[NODE_TYPE] = LEAF_NODE;
+        pager.borrow_mut_page(&leaf)?.set_parent(branch);
+        pager.set_root("tree", branch);
+        pager.drop_page(&pids[0])?;
+        pager.drop_page(&pids[1])?;
+        pager.flush()?;
+
+        assert_eq!(Vacuum::new().with_builtin_links().run(&pager)?, VacuumReport { moved: 2, truncated: 2 });
+        assert_eq!(pager.get_root("tree"), Some(1));
+        assert_eq!(BPTreeLinks.links(pager.borrow_page(&1)?.into_section(PageSectionType::All).as_ref()), vec![2]);
+        assert_eq!(pager.borrow_page(&2)?.get_parent(), 1);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
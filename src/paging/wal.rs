@@ -0,0 +1,499 @@
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, fs::{File, OpenOptions}, io::{Read, Write, Seek, SeekFrom}, ops::Range, path::Path};
+
+use crate::hash::{Crc32Hasher, traits::Hasher};
+
+use super::{page::{Page, PAGE_HEADER_SIZE}, pager::traits::PageStorage, error::Error, result::Result, superblock::SUPERBLOCK_PAGE};
+
+/// Record sections
+const RECORD_KIND: usize = 0;
+const RECORD_LSN: Range<usize> = 1..9;
+const RECORD_PID: Range<usize> = 9..17;
+const RECORD_LEN: Range<usize> = 17..21;
+const RECORD_CHECKSUM: Range<usize> = 21..25;
+const RECORD_HEADER: usize = 25;
+
+/// Record kinds
+const PAGE_RECORD: u8 = 0x1;
+const COMMIT_RECORD: u8 = 0x2;
+
+/// Size of the log above which a checkpoint is performed on sync.
+pub const DEFAULT_CHECKPOINT_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+pub mod traits {
+    /// Append-only storage of the write-ahead log.
+    pub trait LogStorage {
+        /// Append a record at the end of the log.
+        fn append(&self, record: &[u8]) -> std::io::Result<()>;
+        /// Read the whole log.
+        fn read_all(&self) -> std::io::Result<Vec<u8>>;
+        /// Discard the whole log.
+        fn truncate(&self) -> std::io::Result<()>;
+        /// Ensure that all appended records reached the underlying device.
+        fn sync(&self) -> std::io::Result<()>;
+        /// Size of the log, in bytes.
+        fn size(&self) -> std::io::Result<u64>;
+    }
+}
+
+use self::traits::LogStorage;
+
+/// Log kept in memory.
+#[derive(Default)]
+pub struct MemoryLog(RefCell<Vec<u8>>);
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LogStorage for MemoryLog {
+    fn append(&self, record: &[u8]) -> std::io::Result<()> {
+        self.0.borrow_mut().extend_from_slice(record);
+        Ok(())
+    }
+
+    fn read_all(&self) -> std::io::Result<Vec<u8>> {
+        Ok(self.0.borrow().clone())
+    }
+
+    fn truncate(&self) -> std::io::Result<()> {
+        self.0.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.0.borrow().len() as u64)
+    }
+}
+
+/// Log stored in its own file, next to the database.
+pub struct FileLog(File);
+
+impl FileLog {
+    /// Open the log file, create it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(Self(file))
+    }
+}
+
+impl LogStorage for FileLog {
+    fn append(&self, record: &[u8]) -> std::io::Result<()> {
+        (&self.0).write_all(record)
+    }
+
+    fn read_all(&self) -> std::io::Result<Vec<u8>> {
+        let mut log = Vec::default();
+        (&self.0).seek(SeekFrom::Start(0))?;
+        (&self.0).read_to_end(&mut log)?;
+        Ok(log)
+    }
+
+    fn truncate(&self) -> std::io::Result<()> {
+        self.0.set_len(0)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+}
+
+struct Record<'a> {
+    kind: u8,
+    lsn: u64,
+    pid: u64,
+    data: &'a [u8]
+}
+
+impl<'a> Record<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = vec![0u8; RECORD_HEADER + self.data.len()];
+        raw[RECORD_KIND] = self.kind;
+        raw[RECORD_LSN].copy_from_slice(&self.lsn.to_le_bytes());
+        raw[RECORD_PID].copy_from_slice(&self.pid.to_le_bytes());
+        raw[RECORD_LEN].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        raw[RECORD_HEADER..].copy_from_slice(self.data);
+
+        let checksum = record_checksum(&raw);
+        raw[RECORD_CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+        raw
+    }
+
+    /// Decode the record at the head of the log, returns None if it is torn or corrupted.
+    fn decode(log: &'a [u8]) -> Option<(Self, usize)> {
+        let header = log.get(..RECORD_HEADER)?;
+        let len = u32::from_le_bytes(header[RECORD_LEN].try_into().unwrap()) as usize;
+        let raw = log.get(..RECORD_HEADER + len)?;
+
+        if u32::from_le_bytes(raw[RECORD_CHECKSUM].try_into().unwrap()) != record_checksum(raw) {
+            return None;
+        }
+
+        let record = Self {
+            kind: raw[RECORD_KIND],
+            lsn: u64::from_le_bytes(raw[RECORD_LSN].try_into().unwrap()),
+            pid: u64::from_le_bytes(raw[RECORD_PID].try_into().unwrap()),
+            data: &raw[RECORD_HEADER..]
+        };
+
+        Some((record, raw.len()))
+    }
+}
+
+/// Checksum of the whole record, the checksum field excluded.
+fn record_checksum(raw: &[u8]) -> u32 {
+    let mut hasher = Crc32Hasher::new();
+    hasher.update(&raw[..RECORD_CHECKSUM.start]);
+    hasher.update(&raw[RECORD_CHECKSUM.end..]);
+    hasher.finalize().into()
+}
+
+/// Page storage protected by a write-ahead log.
+///
+/// Stored pages are appended to the log and kept aside until sync, which commits them:
+/// the commit record is appended, the log is synced, and only then the pages reach the inner storage.
+/// Committed pages are replayed on open, the LSN stored in each page header makes the replay idempotent.
+pub struct WalStorage<Storage, Log> {
+    inner: Storage,
+    log: Log,
+    lsn: Cell<u64>,
+    pending: RefCell<BTreeMap<u64, Vec<u8>>>,
+    checkpoint_threshold: u64
+}
+
+impl<Storage, Log> WalStorage<Storage, Log>
+where Storage: PageStorage, Storage::Error: Into<Error>, Log: LogStorage
+{
+    /// Open the storage, and recover the committed pages from the log.
+    pub fn open(inner: Storage, log: Log) -> Result<Self> {
+        let wal = Self {
+            inner,
+            log,
+            lsn: Cell::new(0),
+            pending: Default::default(),
+            checkpoint_threshold: DEFAULT_CHECKPOINT_THRESHOLD
+        };
+
+        wal.recover()?;
+        Ok(wal)
+    }
+
+    /// Set the size of the log above which a checkpoint is performed on sync.
+    pub fn set_checkpoint_threshold(&mut self, threshold: u64) {
+        self.checkpoint_threshold = threshold;
+    }
+
+    pub fn into_inner(self) -> (Storage, Log) {
+        (self.inner, self.log)
+    }
+
+    /// Commit the pending pages, write them into the inner storage, and truncate the log.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.commit()?;
+        self.inner.sync().map_err(Into::<Error>::into)?;
+        self.log.truncate()?;
+        self.log.sync()?;
+        Ok(())
+    }
+
+    fn next_lsn(&self) -> u64 {
+        self.lsn.set(self.lsn.get() + 1);
+        self.lsn.get()
+    }
+
+    /// Make the pending pages durable, then write them into the inner storage.
+    fn commit(&self) -> Result<()> {
+        if self.pending.borrow().is_empty() {
+            return Ok(());
+        }
+
+        let commit = Record { kind: COMMIT_RECORD, lsn: self.next_lsn(), pid: 0, data: &[] };
+        self.log.append(&commit.encode())?;
+        self.log.sync()?;
+
+        for (pid, image) in self.pending.borrow().iter() {
+            self.inner.store(*pid, image).map_err(Into::<Error>::into)?;
+        }
+
+        self.pending.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Replay the committed transactions of the log, and checkpoint.
+    fn recover(&self) -> Result<()> {
+        let log = self.log.read_all()?;
+        let mut cursor = &log[..];
+        let mut transaction = Vec::default();
+        let mut last_lsn = 0;
+
+        // A torn or corrupted record ends the log.
+        while let Some((record, len)) = Record::decode(cursor) {
+            cursor = &cursor[len..];
+            last_lsn = last_lsn.max(record.lsn);
+
+            match record.kind {
+                PAGE_RECORD => transaction.push(record),
+                COMMIT_RECORD => {
+                    for record in transaction.drain(..) {
+                        self.replay(&record)?;
+                    }
+                },
+                _ => break
+            }
+        }
+
+        self.inner.sync().map_err(Into::<Error>::into)?;
+        self.log.truncate()?;
+        self.log.sync()?;
+
+        // The superblock is written on each flush, it bears the last known LSN.
+        let mut header = [0u8; PAGE_HEADER_SIZE];
+        if self.inner.fetch(SUPERBLOCK_PAGE, &mut header).is_ok() {
+            last_lsn = last_lsn.max(Page::<u64, u8, _>::from(&header[..]).get_lsn());
+        }
+
+        self.lsn.set(last_lsn);
+        Ok(())
+    }
+
+    /// Write the page image, unless the stored page is already up to date.
+    fn replay(&self, record: &Record) -> Result<()> {
+        let mut stored = vec![0u8; record.data.len()];
+
+        if self.inner.fetch(record.pid, &mut stored).is_ok() {
+            let page = Page::<u64, u8, _>::from(&stored[..]);
+
+            if page.verify_checksum().is_ok() && page.get_lsn() >= record.lsn {
+                return Ok(());
+            }
+        }
+
+        self.inner.store(record.pid, record.data).map_err(Into::<Error>::into)
+    }
+}
+
+impl<Storage, Log> PageStorage for WalStorage<Storage, Log>
+where Storage: PageStorage, Storage::Error: Into<Error>, Log: LogStorage
+{
+    type Error = Error;
+
+    fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::result::Result<(), Self::Error> {
+        let pid = id.into();
+        let lsn = self.next_lsn();
+        let mut image = page.as_ref().to_vec();
+
+        {
+            let mut page = Page::<u64, u8, _>::from(&mut image[..]);
+            page.set_lsn(lsn);
+            page.update_checksum();
+        }
+
+        self.log.append(&Record { kind: PAGE_RECORD, lsn, pid, data: &image }.encode())?;
+        self.pending.borrow_mut().insert(pid, image);
+        Ok(())
+    }
+
+    fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::result::Result<(), Self::Error> {
+        let pid = id.into();
+        let data = data.as_mut();
+
+        match self.pending.borrow().get(&pid).and_then(|image| image.get(..data.len())) {
+            Some(image) => data.copy_from_slice(image),
+            None => self.inner.fetch(pid, &mut &mut *data).map_err(Into::<Error>::into)?
+        }
+
+        Ok(())
+    }
+
+    /// The pages are read from the inner storage at once, up to the last one not logged since the last checkpoint,
+    /// as the logged ones may lie beyond its end, then the logged pages are laid over them.
+    fn fetch_many(&self, first: u64, page_size: usize, data: &mut [u8]) -> std::result::Result<(), Self::Error> {
+        let pending = self.pending.borrow();
+        let pages = data.len().div_ceil(page_size);
+        let stored = (0..pages).rev().find(|i| !pending.contains_key(&(first + *i as u64))).map_or(0, |i| i + 1);
+
+        if stored > 0 {
+            let len = (stored * page_size).min(data.len());
+            self.inner.fetch_many(first, page_size, &mut data[..len]).map_err(Into::<Error>::into)?;
+        }
+
+        for (pid, page) in (first..).zip(data.chunks_mut(page_size)) {
+            if let Some(image) = pending.get(&pid).and_then(|image| image.get(..page.len())) {
+                page.copy_from_slice(image);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.commit()?;
+
+        if self.log.size()? >= self.checkpoint_threshold {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    fn is_logged(&self) -> bool {
+        true
+    }
+
+    fn lock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.lock_commit().map_err(Into::<Error>::into)
+    }
+
+    fn unlock_commit(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.unlock_commit().map_err(Into::<Error>::into)
+    }
+
+    /// The logged pages are written first, as they may lie beyond len.
+    fn truncate(&self, len: u64) -> std::result::Result<(), Self::Error> {
+        self.checkpoint()?;
+        self.inner.truncate(len).map_err(Into::<Error>::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{io::InMemory, paging::{pager::{BufPager, Pager, PageId, traits::{Pager as TraitPager, PageStorage}}, page::PageSectionType, storage::PagerStream}, utils::slice::IntoSection};
+    use super::{WalStorage, MemoryLog, traits::LogStorage};
+
+    /// Fails every write once the budget is exhausted, as if the process was killed.
+    struct Crash<S>(S, Rc<Cell<Option<usize>>>);
+
+    impl<S> Crash<S> {
+        fn tick(&self) -> std::io::Result<()> {
+            match self.1.get() {
+                Some(0) => Err(std::io::Error::other("crash")),
+                Some(budget) => { self.1.set(Some(budget - 1)); Ok(()) },
+                None => Ok(())
+            }
+        }
+    }
+
+    impl PageStorage for Crash<PagerStream<InMemory>> {
+        type Error = std::io::Error;
+
+        fn store<Id: Into<u64>, Data: AsRef<[u8]>>(&self, id: Id, page: Data) -> std::io::Result<()> {
+            self.tick()?;
+            self.0.store(id, page)
+        }
+
+        fn fetch<Id: Into<u64>, DataReceiver: AsMut<[u8]>>(&self, id: Id, data: &mut DataReceiver) -> std::io::Result<()> {
+            self.0.fetch(id, data)
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            self.tick()?;
+            self.0.sync()
+        }
+    }
+
+    impl LogStorage for Crash<MemoryLog> {
+        fn append(&self, record: &[u8]) -> std::io::Result<()> {
+            self.tick()?;
+            self.0.append(record)
+        }
+
+        fn read_all(&self) -> std::io::Result<Vec<u8>> {
+            self.0.read_all()
+        }
+
+        fn truncate(&self) -> std::io::Result<()> {
+            self.tick()?;
+            self.0.truncate()
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            self.tick()?;
+            self.0.sync()
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.0.size()
+        }
+    }
+
+    fn fill_pages<S>(pager: &BufPager<PageId, u8, S>, pids: &[PageId], value: u8) -> crate::paging::result::Result<()>
+    where S: PageStorage, S::Error: Into<crate::paging::error::Error>
+    {
+        for pid in pids {
+            pager.borrow_mut_page(pid)?.into_section(PageSectionType::Body).as_mut().fill(value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_crash_recovery() -> crate::paging::result::Result<()> {
+        let mut crash_point = 0;
+
+        loop {
+            let budget = Rc::new(Cell::new(None));
+            let data = Crash(PagerStream::new(InMemory::new()), budget.clone());
+            let log = Crash(MemoryLog::new(), budget.clone());
+
+            let pager: BufPager<PageId, u8, _> = Pager::with_page_size(WalStorage::open(data, log)?, 4096, 10)?;
+            let pids = (0..3).map(|_| pager.new_page(0x10)).collect::<Result<Vec<_>, _>>()?;
+            fill_pages(&pager, &pids, 1)?;
+            pager.flush()?;
+
+            // Kill the flush at the n-th write.
+            budget.set(Some(crash_point));
+            fill_pages(&pager, &pids, 2)?;
+            let completed = pager.flush().is_ok();
+
+            budget.set(None);
+            let (data, log) = pager.into_storage().into_inner();
+            let pager: BufPager<PageId, u8, _> = Pager::open(WalStorage::open(data, log)?, 10)?;
+
+            let values = pids
+                .iter()
+                .map(|pid| Ok(pager.borrow_page(pid)?.into_section(PageSectionType::Body).as_ref()[0]))
+                .collect::<crate::paging::result::Result<Vec<_>>>()?;
+
+            // All or nothing.
+            assert!(values == [1, 1, 1] || values == [2, 2, 2], "crash point {}: {:?}", crash_point, values);
+
+            if completed {
+                assert_eq!(values, [2, 2, 2]);
+                return Ok(());
+            }
+
+            crash_point += 1;
+        }
+    }
+
+    #[test]
+    fn test_wal_fetch_many() -> crate::paging::result::Result<()> {
+        let inner = PagerStream::new(InMemory::new());
+        (0..4u8).try_for_each(|pid| inner.store(pid as u64, [pid; 4096]))?;
+
+        // The logged pages are read over the stored ones, even beyond the end of the inner storage.
+        let wal = WalStorage::open(inner, MemoryLog::new())?;
+        [(2, 20), (4, 40)].iter().try_for_each(|(pid, value)| wal.store(*pid as u64, [*value; 4096]))?;
+
+        let mut data = vec![0u8; 4 * 4096];
+        wal.fetch_many(1, 4096, &mut data)?;
+        assert_eq!(data.chunks(4096).map(|page| page[100]).collect::<Vec<_>>(), [1, 20, 3, 40]);
+
+        Ok(())
+    }
+}
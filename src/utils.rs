@@ -3,9 +3,22 @@ use std::ops::DerefMut;
 #[derive(Default)]
 pub struct Counter<Id>(std::cell::RefCell<Id>);
 
-impl<Id: std::ops::AddAssign<u8>> Counter<Id> {
+impl<Id: std::ops::AddAssign + From<u8> + Copy> Counter<Id> {
+    pub fn new(value: Id) -> Self {
+        Self(std::cell::RefCell::new(value))
+    }
+
+    /// Current value of the counter.
+    pub fn get(&self) -> Id {
+        *self.0.borrow()
+    }
+
+    pub fn set(&self, value: Id) {
+        *self.0.borrow_mut() = value;
+    }
+
     pub fn inc(&self) -> Id {
-        *self.0.borrow_mut().deref_mut() += 1;
+        *self.0.borrow_mut().deref_mut() += Id::from(1);
         *self.0.borrow_mut()
     }
 }